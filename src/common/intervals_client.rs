@@ -1,41 +1,155 @@
 use crate::common::metrics;
-use anyhow::Result;
+use crate::common::rate_limiter::{AdaptiveRateLimiter, RateLimitConfig, parse_retry_after};
+use chrono::NaiveDate;
 use reqwest::StatusCode;
-use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
-use reqwest_retry::{RetryTransientMiddleware, policies::ExponentialBackoff};
+use reqwest::header::RETRY_AFTER;
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, RequestBuilder};
+use reqwest_retry::{
+    DefaultRetryableStrategy, RetryTransientMiddleware, Retryable, RetryableStrategy,
+    policies::ExponentialBackoff,
+};
 use serde::{Deserialize, Serialize};
 use std::hash::{Hash, Hasher};
+use std::time::Duration;
+use tracing::warn;
 
 const ENDPOINT: &str = "https://intervals.icu";
 
 #[derive(Debug)]
-pub enum DownloadError {
-    Http(StatusCode),
+pub enum IntervalsError {
+    /// Access token missing, expired or revoked (HTTP 401/403)
+    Auth(String),
+    /// Still throttled (HTTP 429) after the rate limiter's retries
+    RateLimited { retry_after: Option<Duration> },
+    /// The athlete or activity does not exist (HTTP 404)
+    NotFound,
+    /// The activity has no GPS data to download (HTTP 422)
+    NoGps,
+    /// Any other unsuccessful response from intervals.icu
+    Upstream(StatusCode),
+    /// The response body could not be parsed, e.g. after a CSV schema change
+    Decode(String),
+    /// The request never completed
     Network(reqwest_middleware::Error),
 }
 
-impl std::fmt::Display for DownloadError {
+impl IntervalsError {
+    fn from_response(response: &reqwest::Response) -> Self {
+        let status = response.status();
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                IntervalsError::Auth(format!("HTTP {status}"))
+            }
+            StatusCode::NOT_FOUND => IntervalsError::NotFound,
+            StatusCode::UNPROCESSABLE_ENTITY => IntervalsError::NoGps,
+            StatusCode::TOO_MANY_REQUESTS => IntervalsError::RateLimited {
+                retry_after: response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(parse_retry_after),
+            },
+            _ => IntervalsError::Upstream(status),
+        }
+    }
+
+    /// Whether the same request might succeed if tried again later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            IntervalsError::RateLimited { .. } | IntervalsError::Network(_) => true,
+            IntervalsError::Upstream(status) => status.is_server_error(),
+            IntervalsError::Auth(_)
+            | IntervalsError::NotFound
+            | IntervalsError::NoGps
+            | IntervalsError::Decode(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for IntervalsError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            DownloadError::Http(status) => write!(f, "HTTP {status}"),
-            DownloadError::Network(e) => write!(f, "Network error: {e}"),
+            IntervalsError::Auth(msg) => write!(f, "Authentication error: {msg}"),
+            IntervalsError::RateLimited {
+                retry_after: Some(delay),
+            } => write!(f, "Rate limited, retry after {delay:?}"),
+            IntervalsError::RateLimited { retry_after: None } => write!(f, "Rate limited"),
+            IntervalsError::NotFound => write!(f, "Not found"),
+            IntervalsError::NoGps => write!(f, "No GPS data available"),
+            IntervalsError::Upstream(status) => write!(f, "HTTP {status}"),
+            IntervalsError::Decode(msg) => write!(f, "Decode error: {msg}"),
+            IntervalsError::Network(e) => write!(f, "Network error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for IntervalsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IntervalsError::Network(e) => Some(e),
+            _ => None,
         }
     }
 }
 
-impl std::error::Error for DownloadError {}
+impl From<reqwest_middleware::Error> for IntervalsError {
+    fn from(err: reqwest_middleware::Error) -> Self {
+        IntervalsError::Network(err)
+    }
+}
+
+impl From<reqwest::Error> for IntervalsError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_decode() {
+            IntervalsError::Decode(err.to_string())
+        } else {
+            IntervalsError::Network(reqwest_middleware::Error::Reqwest(err))
+        }
+    }
+}
 
+/// A row of the intervals.icu activities CSV. Columns not listed here are
+/// ignored; optional columns that are missing, empty or unparseable are `None`.
 #[derive(Debug, Deserialize, Clone)]
 pub struct Activity {
     pub id: String,
     pub name: String,
     pub start_date_local: String,
+    #[serde(default, deserialize_with = "csv::invalid_option")]
     pub distance: Option<f64>,
     #[serde(rename = "type")]
     pub activity_type: String,
     pub elapsed_time: i64,
+    #[serde(default, deserialize_with = "csv::invalid_option")]
+    pub moving_time: Option<i64>,
+    #[serde(default, deserialize_with = "csv::invalid_option")]
+    pub total_elevation_gain: Option<f64>,
+    #[serde(default, deserialize_with = "csv::invalid_option")]
+    pub average_speed: Option<f64>,
+    #[serde(default, deserialize_with = "csv::invalid_option")]
+    pub max_speed: Option<f64>,
+    #[serde(default, deserialize_with = "csv::invalid_option")]
+    pub average_heartrate: Option<f64>,
+    #[serde(
+        default,
+        alias = "icu_average_watts",
+        deserialize_with = "csv::invalid_option"
+    )]
+    pub average_watts: Option<f64>,
+    #[serde(default, deserialize_with = "csv::invalid_option")]
+    pub calories: Option<f64>,
+    #[serde(default, deserialize_with = "csv::invalid_option")]
+    pub device_name: Option<String>,
+    #[serde(default, alias = "gear_id", deserialize_with = "csv::invalid_option")]
+    pub gear: Option<String>,
+    #[serde(default, deserialize_with = "csv::invalid_option")]
+    pub trainer: Option<bool>,
+    #[serde(default, deserialize_with = "csv::invalid_option")]
+    pub commute: Option<bool>,
 }
 
+/// Covers only the columns that decide whether the track has to be
+/// downloaded again. Stored activity keys are built from this hash, so
+/// adding fields here would make every archived activity look changed.
 impl Hash for Activity {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.id.hash(state);
@@ -56,6 +170,34 @@ impl Activity {
         self.hash(&mut hasher);
         format!("{:x}", hasher.finish())
     }
+
+    /// Hash of every column that ends up in the GeoJSON properties. When
+    /// only this changes, the stored properties are rewritten without
+    /// downloading the track again.
+    pub fn compute_properties_hash(&self) -> String {
+        use std::collections::hash_map::DefaultHasher;
+
+        let mut hasher = DefaultHasher::new();
+        self.compute_hash().hash(&mut hasher);
+        self.activity_type.hash(&mut hasher);
+        self.moving_time.hash(&mut hasher);
+        for value in [
+            self.distance,
+            self.total_elevation_gain,
+            self.average_speed,
+            self.max_speed,
+            self.average_heartrate,
+            self.average_watts,
+            self.calories,
+        ] {
+            value.map(f64::to_bits).hash(&mut hasher);
+        }
+        self.device_name.hash(&mut hasher);
+        self.gear.hash(&mut hasher);
+        self.trainer.hash(&mut hasher);
+        self.commute.hash(&mut hasher);
+        format!("{:x}", hasher.finish())
+    }
 }
 
 // OAuth types
@@ -91,14 +233,52 @@ pub struct IntervalsUserProfile {
     pub website: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct StreamResponse {
+    #[serde(rename = "type")]
+    stream_type: String,
+    #[serde(default)]
+    data: Vec<Option<f64>>,
+    /// Second component of two-valued streams; longitude for `latlng`
+    #[serde(default)]
+    data2: Vec<Option<f64>>,
+}
+
+/// Per-sample activity streams, aligned by index. Samples without a value
+/// (e.g. GPS dropouts) are `None`.
+#[derive(Debug, Default)]
+pub struct ActivityStreams {
+    pub latitude: Vec<Option<f64>>,
+    pub longitude: Vec<Option<f64>>,
+    pub altitude: Vec<Option<f64>>,
+    pub time: Vec<Option<f64>>,
+}
+
 #[derive(Deserialize)]
 pub struct ProfileResponse {
     pub athlete: IntervalsUserProfile,
 }
 
+/// Retries transient failures except 429, which `IntervalsClient::send` handles
+/// itself so that it can honour Retry-After and adjust concurrency
+struct NonRateLimitedRetryStrategy;
+
+impl RetryableStrategy for NonRateLimitedRetryStrategy {
+    fn handle(
+        &self,
+        res: &Result<reqwest::Response, reqwest_middleware::Error>,
+    ) -> Option<Retryable> {
+        match res {
+            Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => None,
+            _ => DefaultRetryableStrategy.handle(res),
+        }
+    }
+}
+
 pub struct IntervalsClient {
     client: ClientWithMiddleware,
     auth_header: Option<String>,
+    rate_limiter: AdaptiveRateLimiter,
 }
 
 impl Default for IntervalsClient {
@@ -109,15 +289,23 @@ impl Default for IntervalsClient {
 
 impl IntervalsClient {
     pub fn new() -> Self {
+        Self::with_rate_limit(RateLimitConfig::default())
+    }
+
+    pub fn with_rate_limit(rate_limit: RateLimitConfig) -> Self {
         // Create client with retry middleware for OAuth flows
         let retry_policy = ExponentialBackoff::builder().build_with_max_retries(2);
         let client = ClientBuilder::new(reqwest::Client::new())
-            .with(RetryTransientMiddleware::new_with_policy(retry_policy))
+            .with(RetryTransientMiddleware::new_with_policy_and_strategy(
+                retry_policy,
+                NonRateLimitedRetryStrategy,
+            ))
             .build();
 
         Self {
             client,
             auth_header: None,
+            rate_limiter: AdaptiveRateLimiter::new(rate_limit),
         }
     }
 
@@ -125,113 +313,202 @@ impl IntervalsClient {
         self.auth_header = Some(format!("Bearer {access_token}"));
     }
 
-    pub async fn fetch_activities(&self) -> Result<Vec<Activity>> {
-        let path = format!("{ENDPOINT}/api/v1/athlete/0/activities.csv");
-
-        let auth_header = self
-            .auth_header
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("No access token set"))?;
-
-        match self
-            .client
-            .get(path)
-            .header("Authorization", auth_header)
-            .send()
-            .await
-        {
-            Ok(response) => match response.text().await {
-                Ok(body) => {
-                    metrics::increment_intervals_api_success();
-
-                    let mut rdr = csv::Reader::from_reader(body.as_bytes());
-                    let mut activities = Vec::new();
-
-                    for result in rdr.deserialize() {
-                        let activity: Activity = result?;
-                        activities.push(activity);
-                    }
-
-                    Ok(activities)
-                }
-                Err(e) => {
-                    metrics::increment_intervals_api_failure();
-                    Err(e.into())
-                }
-            },
-            Err(e) => {
-                metrics::increment_intervals_api_failure();
-                Err(e.into())
+    /// Upper bound on concurrent requests callers should keep in flight
+    pub fn max_concurrency(&self) -> usize {
+        self.rate_limiter.config().max_concurrency
+    }
+
+    /// Send a request through the adaptive rate limiter, waiting out and
+    /// retrying 429 responses. The final 429 is returned if retries run out.
+    async fn send(
+        &self,
+        request: RequestBuilder,
+    ) -> Result<reqwest::Response, reqwest_middleware::Error> {
+        let max_retries = self.rate_limiter.config().max_retries;
+        let mut attempt = 0;
+
+        loop {
+            let attempt_request = request.try_clone().ok_or_else(|| {
+                reqwest_middleware::Error::Middleware(anyhow::anyhow!(
+                    "Request body cannot be retried"
+                ))
+            })?;
+
+            let permit = self.rate_limiter.acquire().await;
+            let response = attempt_request.send().await?;
+            drop(permit);
+
+            if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                self.rate_limiter.record_success();
+                return Ok(response);
             }
+
+            metrics::increment_intervals_api_rate_limited();
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(parse_retry_after);
+            let delay = self.rate_limiter.record_rate_limited(retry_after);
+
+            if attempt >= max_retries {
+                return Ok(response);
+            }
+            attempt += 1;
+
+            warn!(
+                "Rate limited by intervals.icu, retrying in {:?} (attempt {}/{}, concurrency {})",
+                delay,
+                attempt,
+                max_retries,
+                self.rate_limiter.current_limit()
+            );
         }
     }
 
-    pub async fn download_fit(&self, activity_id: &str) -> Result<Option<Vec<u8>>, DownloadError> {
-        let path = format!("{ENDPOINT}/api/v1/activity/{activity_id}/fit-file");
+    fn auth_header(&self) -> Result<&str, IntervalsError> {
+        self.auth_header
+            .as_deref()
+            .ok_or_else(|| IntervalsError::Auth("No access token set".to_string()))
+    }
 
-        let auth_header = self.auth_header.as_ref().ok_or_else(|| {
-            DownloadError::Network(reqwest_middleware::Error::Middleware(anyhow::anyhow!(
-                "No access token set"
-            )))
+    /// Send a request and fail with the matching `IntervalsError` on any
+    /// unsuccessful status, recording the API success/failure metrics
+    async fn send_checked(
+        &self,
+        request: RequestBuilder,
+    ) -> Result<reqwest::Response, IntervalsError> {
+        let response = self.send(request).await.inspect_err(|_e| {
+            metrics::increment_intervals_api_failure();
         })?;
 
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        if status == StatusCode::UNPROCESSABLE_ENTITY {
+            // Expected for activities without GPS data, so not an API failure
+            metrics::increment_intervals_api_success();
+        } else {
+            metrics::increment_intervals_api_failure();
+        }
+        Err(IntervalsError::from_response(&response))
+    }
+
+    /// List an athlete's activities, optionally limited to those starting between `oldest`
+    /// and `newest` (inclusive, local dates). Omitting both lists every activity.
+    pub async fn fetch_activities(
+        &self,
+        athlete_id: &str,
+        oldest: Option<NaiveDate>,
+        newest: Option<NaiveDate>,
+    ) -> Result<Vec<Activity>, IntervalsError> {
+        let mut path = format!("{ENDPOINT}/api/v1/athlete/{athlete_id}/activities.csv");
+        let params: Vec<String> = [("oldest", oldest), ("newest", newest)]
+            .into_iter()
+            .filter_map(|(name, date)| date.map(|d| format!("{name}={d}")))
+            .collect();
+        if !params.is_empty() {
+            path = format!("{path}?{}", params.join("&"));
+        }
+
+        let auth_header = self.auth_header()?;
         let response = self
-            .client
-            .get(path)
-            .header("Authorization", auth_header)
-            .send()
-            .await
+            .send_checked(self.client.get(path).header("Authorization", auth_header))
+            .await?;
+
+        let body = response.text().await.inspect_err(|_e| {
+            metrics::increment_intervals_api_failure();
+        })?;
+
+        let mut rdr = csv::Reader::from_reader(body.as_bytes());
+        let activities = rdr
+            .deserialize()
+            .collect::<Result<Vec<Activity>, _>>()
             .map_err(|e| {
                 metrics::increment_intervals_api_failure();
-                DownloadError::Network(e)
+                IntervalsError::Decode(format!("Failed to parse activities CSV: {e}"))
             })?;
 
-        let status = response.status();
-        if !status.is_success() {
-            if status.as_u16() == 422 {
-                // HTTP 422 means no GPS data available - return None
-                metrics::increment_intervals_api_success();
-                return Ok(None);
-            } else {
-                metrics::increment_intervals_api_failure();
-                return Err(DownloadError::Http(status));
-            }
-        }
+        metrics::increment_intervals_api_success();
+        Ok(activities)
+    }
+
+    /// Download the original FIT file. Fails with `IntervalsError::NoGps` when
+    /// intervals.icu has no GPS data for the activity.
+    pub async fn download_fit(&self, activity_id: &str) -> Result<Vec<u8>, IntervalsError> {
+        let path = format!("{ENDPOINT}/api/v1/activity/{activity_id}/fit-file");
 
-        let body = response.bytes().await.map_err(|e| {
+        let auth_header = self.auth_header()?;
+        let response = self
+            .send_checked(self.client.get(path).header("Authorization", auth_header))
+            .await?;
+
+        let body = response.bytes().await.inspect_err(|_e| {
             metrics::increment_intervals_api_failure();
-            DownloadError::Network(reqwest_middleware::Error::Reqwest(e))
         })?;
 
         metrics::increment_intervals_api_success();
-        Ok(Some(body.to_vec()))
+        Ok(body.to_vec())
     }
 
-    // OAuth methods
-    pub async fn exchange_oauth_code(
+    /// Fetch the latlng, altitude and time streams for an activity. Used when
+    /// there is no FIT file, e.g. for manual uploads. Fails with
+    /// `IntervalsError::NoGps` when the activity has no latlng stream.
+    pub async fn fetch_streams(
         &self,
-        request: OAuthTokenRequest,
-    ) -> Result<OAuthTokenResponse> {
-        let path = format!("{ENDPOINT}/api/oauth/token");
+        activity_id: &str,
+    ) -> Result<ActivityStreams, IntervalsError> {
+        let path =
+            format!("{ENDPOINT}/api/v1/activity/{activity_id}/streams?types=latlng,altitude,time");
 
+        let auth_header = self.auth_header()?;
         let response = self
-            .client
-            .post(path)
-            .form(&request)
-            .send()
-            .await
-            .inspect_err(|_e| {
+            .send_checked(self.client.get(path).header("Authorization", auth_header))
+            .await?;
+
+        let response_text = response.text().await.inspect_err(|_e| {
+            metrics::increment_intervals_api_failure();
+        })?;
+
+        let stream_responses: Vec<StreamResponse> =
+            serde_json::from_str(&response_text).map_err(|e| {
                 metrics::increment_intervals_api_failure();
+                IntervalsError::Decode(format!("Failed to parse activity streams: {e}"))
             })?;
+        metrics::increment_intervals_api_success();
 
-        if !response.status().is_success() {
-            metrics::increment_intervals_api_failure();
-            return Err(anyhow::anyhow!(
-                "OAuth token exchange failed with status: {}",
-                response.status()
-            ));
+        let mut streams = ActivityStreams::default();
+        for stream in stream_responses {
+            match stream.stream_type.as_str() {
+                "latlng" => {
+                    streams.latitude = stream.data;
+                    streams.longitude = stream.data2;
+                }
+                "altitude" => streams.altitude = stream.data,
+                "time" => streams.time = stream.data,
+                _ => {}
+            }
         }
 
+        if streams.latitude.is_empty() || streams.longitude.is_empty() {
+            return Err(IntervalsError::NoGps);
+        }
+
+        Ok(streams)
+    }
+
+    // OAuth methods
+    pub async fn exchange_oauth_code(
+        &self,
+        request: OAuthTokenRequest,
+    ) -> Result<OAuthTokenResponse, IntervalsError> {
+        let path = format!("{ENDPOINT}/api/oauth/token");
+
+        let response = self
+            .send_checked(self.client.post(path).form(&request))
+            .await?;
+
         let response_text = response.text().await.inspect_err(|_e| {
             metrics::increment_intervals_api_failure();
         })?;
@@ -239,38 +516,24 @@ impl IntervalsClient {
         let token_response: OAuthTokenResponse =
             serde_json::from_str(&response_text).map_err(|e| {
                 metrics::increment_intervals_api_failure();
-                anyhow::anyhow!("Failed to parse OAuth token response: {}", e)
+                IntervalsError::Decode(format!("Failed to parse OAuth token response: {e}"))
             })?;
 
         metrics::increment_intervals_api_success();
         Ok(token_response)
     }
 
-    pub async fn get_user_profile(&self) -> Result<IntervalsUserProfile> {
-        let path = format!("{ENDPOINT}/api/v1/athlete/0/profile");
-
-        let auth_header = self
-            .auth_header
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("No access token set"))?;
+    /// Fetch an athlete's profile; pass `TOKEN_OWNER_ATHLETE_ID` for the token owner
+    pub async fn get_user_profile(
+        &self,
+        athlete_id: &str,
+    ) -> Result<IntervalsUserProfile, IntervalsError> {
+        let path = format!("{ENDPOINT}/api/v1/athlete/{athlete_id}/profile");
 
+        let auth_header = self.auth_header()?;
         let response = self
-            .client
-            .get(path)
-            .header("Authorization", auth_header)
-            .send()
-            .await
-            .inspect_err(|_e| {
-                metrics::increment_intervals_api_failure();
-            })?;
-
-        if !response.status().is_success() {
-            metrics::increment_intervals_api_failure();
-            return Err(anyhow::anyhow!(
-                "Failed to get user profile with status: {}",
-                response.status()
-            ));
-        }
+            .send_checked(self.client.get(path).header("Authorization", auth_header))
+            .await?;
 
         let response_text = response.text().await.inspect_err(|_e| {
             metrics::increment_intervals_api_failure();
@@ -279,10 +542,36 @@ impl IntervalsClient {
         let profile_response: ProfileResponse =
             serde_json::from_str(&response_text).map_err(|e| {
                 metrics::increment_intervals_api_failure();
-                anyhow::anyhow!("Failed to parse user profile response: {}", e)
+                IntervalsError::Decode(format!("Failed to parse user profile response: {e}"))
             })?;
 
         metrics::increment_intervals_api_success();
         Ok(profile_response.athlete)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activity_csv_tolerates_schema_drift() {
+        let csv_data = "\
+id,start_date_local,type,name,elapsed_time,distance,moving_time,icu_average_watts,trainer,some_new_column
+i1,2024-05-01T08:00:00,Ride,Morning Ride,3600,30000.5,3400,180.5,false,x
+i2,2024-05-02T08:00:00,Run,Evening Run,1800,,n/a,,,y
+";
+        let mut rdr = csv::Reader::from_reader(csv_data.as_bytes());
+        let activities: Vec<Activity> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+
+        assert_eq!(activities.len(), 2);
+        assert_eq!(activities[0].distance, Some(30000.5));
+        assert_eq!(activities[0].moving_time, Some(3400));
+        assert_eq!(activities[0].average_watts, Some(180.5));
+        assert_eq!(activities[0].trainer, Some(false));
+        assert_eq!(activities[0].commute, None);
+        assert_eq!(activities[1].distance, None);
+        assert_eq!(activities[1].moving_time, None);
+        assert_eq!(activities[1].device_name, None);
+    }
+}
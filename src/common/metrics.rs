@@ -1,4 +1,5 @@
-use metrics::{counter, gauge};
+use metrics::{counter, gauge, histogram};
+use std::time::Duration;
 
 pub const METRICS_NAMESPACE: &str = "ridelines";
 
@@ -11,6 +12,10 @@ pub fn increment_intervals_api_failure() {
     counter!("intervals_api_total", "result" => "failure").increment(1);
 }
 
+pub fn increment_intervals_api_rate_limited() {
+    counter!("intervals_api_rate_limited").increment(1);
+}
+
 pub fn increment_s3_upload_success() {
     counter!("s3_upload_total", "result" => "success").increment(1);
 }
@@ -19,6 +24,35 @@ pub fn increment_s3_upload_failure() {
     counter!("s3_upload_total", "result" => "failure").increment(1);
 }
 
+pub fn increment_pmtiles_gc_reclaimed(objects: u64, bytes: u64) {
+    counter!("pmtiles_gc_reclaimed_objects_total").increment(objects);
+    counter!("pmtiles_gc_reclaimed_bytes_total").increment(bytes);
+}
+
+pub fn increment_pmtiles_gc_failures(count: u64) {
+    counter!("pmtiles_gc_failures_total").increment(count);
+}
+
+pub fn increment_cdn_invalidation_success(provider: &'static str) {
+    counter!("cdn_invalidation_total", "provider" => provider, "result" => "success").increment(1);
+}
+
+pub fn increment_cdn_invalidation_failure(provider: &'static str) {
+    counter!("cdn_invalidation_total", "provider" => provider, "result" => "failure").increment(1);
+}
+
+pub fn record_cdn_invalidation_duration(provider: &'static str, duration: Duration) {
+    histogram!("cdn_invalidation_duration", "provider" => provider).record(duration.as_secs_f64());
+}
+
+pub fn increment_multipart_upload_aborted() {
+    counter!("multipart_upload_aborted_total").increment(1);
+}
+
+pub fn increment_pmtiles_upload_skipped() {
+    counter!("pmtiles_upload_skipped_total").increment(1);
+}
+
 pub fn increment_tippecanoe_success() {
     counter!("tippecanoe_total", "result" => "success").increment(1);
 }
@@ -27,6 +61,75 @@ pub fn increment_tippecanoe_failure() {
     counter!("tippecanoe_total", "result" => "failure").increment(1);
 }
 
+pub fn increment_tippecanoe_timeout() {
+    counter!("tippecanoe_total", "result" => "timeout").increment(1);
+}
+
+pub fn increment_pmtiles_validation_success() {
+    counter!("pmtiles_validation_total", "result" => "success").increment(1);
+}
+
+pub fn increment_pmtiles_validation_failure() {
+    counter!("pmtiles_validation_total", "result" => "failure").increment(1);
+}
+
+pub fn increment_tile_build(mode: &'static str) {
+    counter!("tile_build_total", "mode" => mode).increment(1);
+}
+
+pub fn increment_incremental_build_fallback() {
+    counter!("incremental_build_fallback_total").increment(1);
+}
+
+pub fn increment_heatmap_success() {
+    counter!("heatmap_total", "result" => "success").increment(1);
+}
+
+pub fn increment_heatmap_failure() {
+    counter!("heatmap_total", "result" => "failure").increment(1);
+}
+
+pub fn increment_explorer_export_success() {
+    counter!("explorer_export_total", "result" => "success").increment(1);
+}
+
+pub fn increment_explorer_export_failure() {
+    counter!("explorer_export_total", "result" => "failure").increment(1);
+}
+
+pub fn increment_thumbnails_success() {
+    counter!("thumbnails_total", "result" => "success").increment(1);
+}
+
+pub fn increment_thumbnails_failure() {
+    counter!("thumbnails_total", "result" => "failure").increment(1);
+}
+
+pub fn record_thumbnails_rendered(count: u64) {
+    counter!("thumbnails_rendered_total").increment(count);
+}
+
+pub fn increment_group_map_success() {
+    counter!("group_map_total", "result" => "success").increment(1);
+}
+
+pub fn increment_group_map_failure() {
+    counter!("group_map_total", "result" => "failure").increment(1);
+}
+
+pub fn record_group_map_members(included: u64, excluded: u64) {
+    counter!("group_map_members_total", "result" => "included").increment(included);
+    counter!("group_map_members_total", "result" => "excluded").increment(excluded);
+}
+
+pub fn increment_native_tiler_success() {
+    counter!("native_tiler_total", "result" => "success").increment(1);
+}
+
+pub fn increment_native_tiler_failure() {
+    counter!("native_tiler_total", "result" => "failure").increment(1);
+}
+
 pub fn increment_lambda_success() {
     counter!("lambda_total", "result" => "success").increment(1);
 }
@@ -44,6 +147,10 @@ pub fn increment_activities_without_gps(count: u64) {
     counter!("activities_without_gps").increment(count);
 }
 
+pub fn increment_activities_from_streams(count: u64) {
+    counter!("activities_from_streams").increment(count);
+}
+
 pub fn increment_activities_skipped_unchanged(count: u64) {
     counter!("activities_skipped_unchanged").increment(count);
 }
@@ -56,11 +163,19 @@ pub fn increment_activities_failed(count: u64) {
     counter!("activities_failed").increment(count);
 }
 
+pub fn increment_activities_quarantined(count: u64) {
+    counter!("activities_quarantined").increment(count);
+}
+
 /// Resource Usage Metrics
 pub fn record_pmtiles_file_size(size_bytes: u64) {
     gauge!("pmtiles_file_size_bytes").set(size_bytes as f64);
 }
 
+pub fn record_intervals_api_concurrency(limit: usize) {
+    gauge!("intervals_api_concurrency").set(limit as f64);
+}
+
 /// Archive-specific Metrics
 pub fn record_archive_compression_ratio(ratio: f64) {
     gauge!("archive_compression_ratio").set(ratio);
@@ -1,3 +1,4 @@
 pub mod intervals_client;
 pub mod metrics;
+pub mod rate_limiter;
 pub mod types;
@@ -0,0 +1,244 @@
+use crate::common::metrics;
+use chrono::{DateTime, Utc};
+use reqwest::header::HeaderValue;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+const DEFAULT_MAX_CONCURRENCY: usize = 5;
+const DEFAULT_MIN_CONCURRENCY: usize = 1;
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(120);
+const DEFAULT_MAX_RATE_LIMIT_RETRIES: u32 = 5;
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Upper bound for concurrent requests; the limiter starts here
+    pub max_concurrency: usize,
+    /// Lower bound the limiter will back off to while being throttled
+    pub min_concurrency: usize,
+    /// Pause applied after a 429 that carries no usable Retry-After header
+    pub default_retry_after: Duration,
+    /// How many times a single request is retried after a 429 before giving up
+    pub max_retries: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            min_concurrency: DEFAULT_MIN_CONCURRENCY,
+            default_retry_after: DEFAULT_RETRY_AFTER,
+            max_retries: DEFAULT_MAX_RATE_LIMIT_RETRIES,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Build a config from INTERVALS_MAX_CONCURRENCY, INTERVALS_MIN_CONCURRENCY
+    /// and INTERVALS_RATE_LIMIT_RETRIES, falling back to defaults
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let max_concurrency =
+            env_usize("INTERVALS_MAX_CONCURRENCY").unwrap_or(defaults.max_concurrency);
+        let min_concurrency =
+            env_usize("INTERVALS_MIN_CONCURRENCY").unwrap_or(defaults.min_concurrency);
+        let max_retries = env::var("INTERVALS_RATE_LIMIT_RETRIES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.max_retries);
+
+        let max_concurrency = max_concurrency.max(1);
+        Self {
+            max_concurrency,
+            min_concurrency: min_concurrency.clamp(1, max_concurrency),
+            max_retries,
+            ..defaults
+        }
+    }
+}
+
+fn env_usize(name: &str) -> Option<usize> {
+    env::var(name).ok().and_then(|v| v.parse().ok())
+}
+
+struct LimiterState {
+    limit: usize,
+    in_flight: usize,
+    successes: usize,
+    paused_until: Option<Instant>,
+}
+
+/// Concurrency limiter for intervals.icu requests.
+///
+/// Halves the number of concurrent requests on every 429 and pauses all
+/// requests until the server's Retry-After has elapsed. Each run of `limit`
+/// consecutive unthrottled responses raises the limit by one, up to the
+/// configured maximum.
+pub struct AdaptiveRateLimiter {
+    config: RateLimitConfig,
+    state: Mutex<LimiterState>,
+    notify: Notify,
+}
+
+pub struct RateLimitPermit<'a> {
+    limiter: &'a AdaptiveRateLimiter,
+}
+
+impl Drop for RateLimitPermit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.in_flight -= 1;
+        drop(state);
+        self.limiter.notify.notify_waiters();
+    }
+}
+
+impl AdaptiveRateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let state = LimiterState {
+            limit: config.max_concurrency,
+            in_flight: 0,
+            successes: 0,
+            paused_until: None,
+        };
+        metrics::record_intervals_api_concurrency(state.limit);
+
+        Self {
+            config,
+            state: Mutex::new(state),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    pub fn current_limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Wait until a request slot is free and no Retry-After pause is active
+    pub async fn acquire(&self) -> RateLimitPermit<'_> {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let paused_until = {
+                let mut state = self.state.lock().unwrap();
+                match state.paused_until {
+                    Some(until) if until > Instant::now() => Some(until),
+                    _ => {
+                        state.paused_until = None;
+                        if state.in_flight < state.limit {
+                            state.in_flight += 1;
+                            return RateLimitPermit { limiter: self };
+                        }
+                        None
+                    }
+                }
+            };
+
+            match paused_until {
+                Some(until) => tokio::time::sleep_until(until).await,
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Record a response that was not throttled
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.successes += 1;
+        if state.successes >= state.limit && state.limit < self.config.max_concurrency {
+            state.limit += 1;
+            state.successes = 0;
+            metrics::record_intervals_api_concurrency(state.limit);
+            drop(state);
+            self.notify.notify_waiters();
+        }
+    }
+
+    /// Record a 429 response, returning how long requests are paused for
+    pub fn record_rate_limited(&self, retry_after: Option<Duration>) -> Duration {
+        let delay = retry_after
+            .unwrap_or(self.config.default_retry_after)
+            .min(MAX_RETRY_AFTER);
+        let resume_at = Instant::now() + delay;
+
+        let mut state = self.state.lock().unwrap();
+        state.limit = (state.limit / 2).max(self.config.min_concurrency);
+        state.successes = 0;
+        state.paused_until = Some(match state.paused_until {
+            Some(until) if until > resume_at => until,
+            _ => resume_at,
+        });
+        metrics::record_intervals_api_concurrency(state.limit);
+
+        delay
+    }
+}
+
+/// Parse a Retry-After header given either as delay-seconds or an HTTP date
+pub fn parse_retry_after(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    let delta = date.with_timezone(&Utc) - Utc::now();
+    Some(delta.to_std().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max: usize, min: usize) -> RateLimitConfig {
+        RateLimitConfig {
+            max_concurrency: max,
+            min_concurrency: min,
+            default_retry_after: Duration::from_millis(10),
+            max_retries: 1,
+        }
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(
+            parse_retry_after(&HeaderValue::from_static("30")),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after(&HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after(&HeaderValue::from_static("soon")), None);
+    }
+
+    #[tokio::test]
+    async fn test_limit_backs_off_and_recovers() {
+        let limiter = AdaptiveRateLimiter::new(config(8, 2));
+        assert_eq!(limiter.current_limit(), 8);
+
+        limiter.record_rate_limited(Some(Duration::ZERO));
+        assert_eq!(limiter.current_limit(), 4);
+        limiter.record_rate_limited(Some(Duration::ZERO));
+        limiter.record_rate_limited(Some(Duration::ZERO));
+        assert_eq!(limiter.current_limit(), 2);
+
+        for _ in 0..2 {
+            limiter.record_success();
+        }
+        assert_eq!(limiter.current_limit(), 3);
+
+        let _permits = [limiter.acquire().await, limiter.acquire().await];
+        assert_eq!(limiter.state.lock().unwrap().in_flight, 2);
+    }
+}
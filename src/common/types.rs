@@ -14,6 +14,60 @@ pub struct User {
     pub last_login: DateTime<Utc>,
 }
 
+/// Athlete ID that intervals.icu resolves to the owner of the access token
+pub const TOKEN_OWNER_ATHLETE_ID: &str = "0";
+
+/// Whose activities are being mapped: the user's own, or those of an athlete
+/// the user coaches and can read with their own intervals.icu token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapTarget {
+    pub user_id: String,
+    pub athlete_id: Option<String>,
+}
+
+impl MapTarget {
+    pub fn own(user_id: &str) -> Self {
+        Self {
+            user_id: user_id.to_string(),
+            athlete_id: None,
+        }
+    }
+
+    pub fn coached(user_id: &str, athlete_id: &str) -> Self {
+        Self {
+            user_id: user_id.to_string(),
+            athlete_id: Some(athlete_id.to_string()),
+        }
+    }
+
+    /// Athlete ID to use in intervals.icu API paths
+    pub fn intervals_athlete_id(&self) -> &str {
+        self.athlete_id.as_deref().unwrap_or(TOKEN_OWNER_ATHLETE_ID)
+    }
+
+    /// Path segment identifying this target in S3 keys
+    pub fn storage_id(&self) -> String {
+        match &self.athlete_id {
+            Some(athlete_id) => format!("{}/coached/{athlete_id}", self.user_id),
+            None => self.user_id.clone(),
+        }
+    }
+
+    /// Like `storage_id`, but safe to use as a single file name
+    pub fn file_stem(&self) -> String {
+        self.storage_id().replace('/', "_")
+    }
+}
+
+impl fmt::Display for MapTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.athlete_id {
+            Some(athlete_id) => write!(f, "user {} (athlete {athlete_id})", self.user_id),
+            None => write!(f, "user {}", self.user_id),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum CommonError {
     Http(reqwest::StatusCode),
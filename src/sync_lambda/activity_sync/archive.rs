@@ -1,32 +1,47 @@
-use super::{ActivityIndex, ActivitySync};
+use super::{ActivityIndex, ActivitySync, SyncedActivities};
+use crate::explorer::ExplorerTiles;
+use crate::fit_converter;
 use anyhow::Result;
 use aws_sdk_s3::primitives::ByteStream;
 use function_timer::time;
 use geojson::FeatureCollection;
+use ridelines_drivetrain::common::intervals_client::Activity;
 use ridelines_drivetrain::common::metrics;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read, Write};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 impl ActivitySync {
     /// Finalize archive by streaming existing activities and appending new ones from temp directory
-    /// Returns the path to the uncompressed concatenated GeoJSON file
+    /// Returns the uncompressed concatenated GeoJSON file and the activities that changed.
+    /// Existing activities in `refreshed` have their properties rewritten, and
+    /// with `reclip` every existing track is clipped by the current privacy settings.
     #[time("finalize_archive_duration")]
     pub async fn finalize_archive(
         &self,
         temp_dir_path: &std::path::Path,
         mut copied_index: ActivityIndex,
-    ) -> Result<std::path::PathBuf> {
+        refreshed: &HashMap<String, Activity>,
+        reclip: bool,
+        previous_version: Option<String>,
+    ) -> Result<SyncedActivities> {
         // Update timestamp on copied index
         copied_index.last_updated = chrono::Utc::now().to_rfc3339();
 
         // Create temporary file for new GeoJSON content in work directory
         let temp_geojson_path = self
             .work_dir
-            .join(format!("activities_{}.geojson", self.user_id));
+            .join(format!("activities_{}.geojson", self.target.file_stem()));
         let temp_geojson_file = File::create(&temp_geojson_path)?;
         let mut geojson_writer = std::io::BufWriter::new(temp_geojson_file);
 
+        // Both versions of every activity that changed, for incremental tiling
+        let changes_path = self
+            .work_dir
+            .join(format!("changes_{}.geojson", self.target.file_stem()));
+        let mut changes_writer = std::io::BufWriter::new(File::create(&changes_path)?);
+
         info!(
             "Creating new activity index. Beginning with {} ({} GeoJSON, {} empty) existing entries.",
             copied_index.total_activities(),
@@ -34,10 +49,24 @@ impl ActivitySync {
             copied_index.empty_activities.len()
         );
 
+        // Explorer tiles are updated as activities are added and removed
+        // when they match the previous archive, otherwise recomputed below
+        let mut explorer = self.load_explorer(previous_version.as_deref()).await;
+
         // Copy existing GeoJSON activities from the existing archive
-        let copied_activities = self
-            .copy_existing_activities(&copied_index, &mut geojson_writer)
-            .await?;
+        let copied_activities = if previous_version.is_some() {
+            self.copy_existing_activities(
+                &mut copied_index,
+                refreshed,
+                reclip,
+                &mut geojson_writer,
+                &mut changes_writer,
+                explorer.as_mut(),
+            )
+            .await?
+        } else {
+            0
+        };
         info!(
             "Copied existing GeoJSON data for {} activities",
             copied_activities
@@ -45,7 +74,13 @@ impl ActivitySync {
 
         // Add new activities from temp directory
         let (new_geojson, new_empty) = self
-            .add_new_activities(temp_dir_path, &mut copied_index, &mut geojson_writer)
+            .add_new_activities(
+                temp_dir_path,
+                &mut copied_index,
+                &mut geojson_writer,
+                &mut changes_writer,
+                explorer.as_mut(),
+            )
             .await?;
         info!(
             "Added {} new activities ({} GeoJSON, {} empty)",
@@ -62,8 +97,11 @@ impl ActivitySync {
             new_geojson + new_empty,
         );
 
-        // Flush and close the writer
+        // Flush and close the writers
+        geojson_writer.flush()?;
         drop(geojson_writer);
+        changes_writer.flush()?;
+        drop(changes_writer);
 
         // Compress and upload GeoJSON file
         self.upload_geojson(&temp_geojson_path.to_string_lossy())
@@ -72,30 +110,43 @@ impl ActivitySync {
         // Save index
         self.upload_index(&copied_index).await?;
 
+        let explorer = self
+            .save_explorer(explorer, &temp_geojson_path, &copied_index.last_updated)
+            .await;
+
         // Clean up temp directory (but keep the final GeoJSON file)
         std::fs::remove_dir_all(temp_dir_path).ok();
 
-        // Return path to uncompressed GeoJSON file for tile generation
-        Ok(temp_geojson_path)
+        // Return paths to the uncompressed GeoJSON files for tile generation
+        Ok(SyncedActivities {
+            geojson_path: temp_geojson_path,
+            changes_path,
+            previous_version,
+            version: copied_index.last_updated,
+            explorer,
+        })
     }
 
-    /// Copy existing GeoJSON activities from S3 archive to the new GeoJSON file
+    /// Copy existing GeoJSON activities from S3 archive to the new GeoJSON file.
+    /// Activities that were changed or deleted are written to the changes file.
+    /// Clipping again only removes more of a track; parts removed under older
+    /// privacy settings come back when the activity is next downloaded.
     async fn copy_existing_activities(
         &self,
-        copied_index: &ActivityIndex,
+        copied_index: &mut ActivityIndex,
+        refreshed: &HashMap<String, Activity>,
+        reclip: bool,
         geojson_writer: &mut std::io::BufWriter<File>,
+        changes_writer: &mut std::io::BufWriter<File>,
+        mut explorer: Option<&mut ExplorerTiles>,
     ) -> Result<usize> {
-        if copied_index.geojson_activities.is_empty() {
-            return Ok(0);
-        }
-
         let existing_geojson_content = self.download_geojson().await?;
         let reader = BufReader::new(existing_geojson_content.as_bytes());
         let mut copied_activities = 0;
 
         for line_result in reader.lines() {
             let line = line_result?;
-            let feature_collection: FeatureCollection = match serde_json::from_str(&line) {
+            let mut feature_collection: FeatureCollection = match serde_json::from_str(&line) {
                 Ok(fc) => fc,
                 Err(e) => {
                     error!(
@@ -116,10 +167,10 @@ impl ActivitySync {
                 }
             };
 
-            let key = match feature.properties.as_ref().and_then(|props| {
+            let (activity_id, key) = match feature.properties.as_ref().and_then(|props| {
                 let id = props.get("id").and_then(|v| v.as_str())?;
                 let hash = props.get("activity_hash").and_then(|v| v.as_str())?;
-                Some(ActivityIndex::create_key(id, hash))
+                Some((id.to_string(), ActivityIndex::create_key(id, hash)))
             }) {
                 Some(key) => key,
                 None => {
@@ -132,8 +183,49 @@ impl ActivitySync {
             };
 
             if copied_index.geojson_activities.contains(&key) {
-                writeln!(geojson_writer, "{line}")?;
+                if reclip {
+                    let original = feature_collection.clone();
+                    let kept = fit_converter::clip_activity(&mut feature_collection, &self.privacy);
+                    if let Some(explorer) = explorer.as_deref_mut() {
+                        explorer.remove_activity(&original);
+                        if kept {
+                            explorer.add_activity(&feature_collection);
+                        }
+                    }
+                    if !kept {
+                        // Nothing of the track may be shown any more
+                        writeln!(changes_writer, "{line}")?;
+                        copied_index.geojson_activities.remove(&key);
+                        copied_index.property_hashes.remove(&activity_id);
+                        copied_index.empty_activities.insert(key);
+                        continue;
+                    }
+                }
+                let refreshed = refreshed.get(&activity_id);
+                if let Some(activity) = refreshed {
+                    fit_converter::refresh_properties(&mut feature_collection, activity);
+                }
+                // Activities converted before start/finish markers existed
+                // only have their line feature
+                let markers = fit_converter::endpoint_features(&feature_collection.features[0]);
+                if reclip || refreshed.is_some() || feature_collection.features[1..] != markers[..]
+                {
+                    feature_collection.features.truncate(1);
+                    feature_collection.features.extend(markers);
+                    let updated = serde_json::to_string(&feature_collection)?;
+                    writeln!(geojson_writer, "{updated}")?;
+                    // Tiles under the old track and markers need rebuilding too
+                    writeln!(changes_writer, "{line}")?;
+                    writeln!(changes_writer, "{updated}")?;
+                } else {
+                    writeln!(geojson_writer, "{line}")?;
+                }
                 copied_activities += 1;
+            } else {
+                writeln!(changes_writer, "{line}")?;
+                if let Some(explorer) = explorer.as_deref_mut() {
+                    explorer.remove_activity(&feature_collection);
+                }
             }
         }
 
@@ -146,6 +238,8 @@ impl ActivitySync {
         temp_dir_path: &std::path::Path,
         copied_index: &mut ActivityIndex,
         geojson_writer: &mut std::io::BufWriter<File>,
+        changes_writer: &mut std::io::BufWriter<File>,
+        mut explorer: Option<&mut ExplorerTiles>,
     ) -> Result<(usize, usize)> {
         let mut new_geojson = 0;
         let mut new_empty = 0;
@@ -166,7 +260,30 @@ impl ActivitySync {
                     "geojson" => {
                         if let Ok(geojson_content) = std::fs::read_to_string(&file_path) {
                             writeln!(geojson_writer, "{}", geojson_content.trim())?;
+                            writeln!(changes_writer, "{}", geojson_content.trim())?;
                             new_geojson += 1;
+
+                            match serde_json::from_str::<FeatureCollection>(&geojson_content) {
+                                Ok(collection) => {
+                                    if let Some(properties_hash) = collection
+                                        .features
+                                        .first()
+                                        .and_then(|line| line.property("properties_hash"))
+                                        .and_then(|hash| hash.as_str())
+                                    {
+                                        copied_index.property_hashes.insert(
+                                            activity_id.clone(),
+                                            properties_hash.to_string(),
+                                        );
+                                    }
+                                    if let Some(explorer) = explorer.as_deref_mut() {
+                                        explorer.add_activity(&collection);
+                                    }
+                                }
+                                Err(e) => {
+                                    error!("Failed to parse new activity {}: {}", activity_id, e)
+                                }
+                            }
                         }
                         copied_index.insert_geojson(&activity_id, &activity_hash);
                     }
@@ -192,7 +309,7 @@ impl ActivitySync {
     /// Load existing activity index from S3 (returns the raw ActivityIndex)
     #[time("download_index_duration")]
     pub async fn download_index(&self) -> Result<ActivityIndex> {
-        let index_key = format!("athletes/{}/activities.index", self.user_id);
+        let index_key = format!("athletes/{}/activities.index", self.target.storage_id());
 
         match self
             .s3_client
@@ -207,8 +324,7 @@ impl ActivitySync {
                 let index_data = response.body.collect().await?.to_vec();
 
                 // Deserialize
-                let index: ActivityIndex =
-                    bincode::decode_from_slice(&index_data, bincode::config::standard())?.0;
+                let index = ActivityIndex::decode(&index_data)?;
                 info!(
                     "Loaded index with {} total activities ({} geojson, {} empty)",
                     index.total_activities(),
@@ -225,7 +341,7 @@ impl ActivitySync {
     }
 
     #[time("upload_index_duration")]
-    async fn upload_index(&self, index: &ActivityIndex) -> Result<()> {
+    pub(super) async fn upload_index(&self, index: &ActivityIndex) -> Result<()> {
         info!(
             "Saving index with {} total activities ({} geojson, {} empty)",
             index.total_activities(),
@@ -240,7 +356,7 @@ impl ActivitySync {
         metrics::record_index_size_bytes(serialized_data.len() as u64);
 
         // Upload to S3
-        let index_key = format!("athletes/{}/activities.index", index.user_id);
+        let index_key = format!("athletes/{}/activities.index", self.target.storage_id());
         match self
             .s3_client
             .put_object()
@@ -264,10 +380,97 @@ impl ActivitySync {
         }
     }
 
+    /// Explorer tiles to update in place: empty when starting a fresh archive,
+    /// the stored tiles when they match the previous archive, and None when
+    /// they are missing or stale and must be recomputed
+    async fn load_explorer(&self, previous_version: Option<&str>) -> Option<ExplorerTiles> {
+        let previous_version = match previous_version {
+            Some(version) => version,
+            None => return Some(ExplorerTiles::default()),
+        };
+
+        let explorer_key = format!("athletes/{}/explorer.bin", self.target.storage_id());
+        let response = match self
+            .s3_client
+            .get_object()
+            .bucket(&self.s3_bucket)
+            .key(&explorer_key)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                info!("No explorer tiles loaded from {}: {}", explorer_key, e);
+                return None;
+            }
+        };
+
+        let explorer = match response.body.collect().await {
+            Ok(data) => ExplorerTiles::decode(&data.to_vec()),
+            Err(e) => Err(e.into()),
+        };
+        match explorer {
+            Ok(explorer) if explorer.source_version == previous_version => Some(explorer),
+            Ok(_) => {
+                info!("Stored explorer tiles are out of date, recomputing them");
+                None
+            }
+            Err(e) => {
+                warn!("Failed to read explorer tiles, recomputing them: {e:#}");
+                None
+            }
+        }
+    }
+
+    /// Recompute the explorer tiles from the new archive if they could not be
+    /// updated in place, then store them. Failures are logged rather than
+    /// failing the sync; the next sync recomputes the tiles.
+    async fn save_explorer(
+        &self,
+        explorer: Option<ExplorerTiles>,
+        geojson_path: &std::path::Path,
+        version: &str,
+    ) -> Option<ExplorerTiles> {
+        let mut explorer = match explorer {
+            Some(explorer) => explorer,
+            None => match ExplorerTiles::from_geojson(geojson_path) {
+                Ok(explorer) => explorer,
+                Err(e) => {
+                    error!("Failed to compute explorer tiles: {e:#}");
+                    return None;
+                }
+            },
+        };
+        explorer.source_version = version.to_string();
+
+        let explorer_key = format!("athletes/{}/explorer.bin", self.target.storage_id());
+        let result = match explorer.encode() {
+            Ok(data) => self
+                .s3_client
+                .put_object()
+                .bucket(&self.s3_bucket)
+                .key(&explorer_key)
+                .body(ByteStream::from(data))
+                .content_type("application/octet-stream")
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Failed to save explorer tiles to {}: {e:#}", explorer_key);
+        }
+        Some(explorer)
+    }
+
     /// Download and decompress GeoJSON file from S3
     #[time("download_geojson_duration")]
     async fn download_geojson(&self) -> Result<String> {
-        let geojson_key = format!("athletes/{}/activities.geojson.zst", self.user_id);
+        let geojson_key = format!(
+            "athletes/{}/activities.geojson.zst",
+            self.target.storage_id()
+        );
 
         let response = self
             .s3_client
@@ -310,7 +513,10 @@ impl ActivitySync {
         );
 
         // Upload to S3
-        let geojson_key = format!("athletes/{}/activities.geojson.zst", self.user_id);
+        let geojson_key = format!(
+            "athletes/{}/activities.geojson.zst",
+            self.target.storage_id()
+        );
         self.s3_client
             .put_object()
             .bucket(&self.s3_bucket)
@@ -1,6 +1,6 @@
 use ridelines_drivetrain::common::intervals_client::Activity;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 #[derive(Debug, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
 pub struct ActivityIndex {
@@ -8,9 +8,136 @@ pub struct ActivityIndex {
     pub last_updated: String,
     pub geojson_activities: HashSet<String>,
     pub empty_activities: HashSet<String>,
+    /// When the index was last reconciled against a full activity listing
+    pub last_full_sync: Option<String>,
+    /// Activities whose latest version could not be downloaded or
+    /// converted, keyed by activity ID
+    pub failed_activities: HashMap<String, FailedActivity>,
+    /// Properties hash of each activity with GeoJSON, keyed by activity ID.
+    /// Activities whose properties changed without their track changing are
+    /// rewritten in the archive rather than downloaded again.
+    pub property_hashes: HashMap<String, String>,
+    /// Fingerprint of the privacy settings the archived tracks were clipped
+    /// with
+    pub track_privacy: Option<String>,
+}
+
+/// Consecutive failures to process one version of an activity
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct FailedActivity {
+    pub activity_hash: String,
+    pub failures: u32,
+    /// Short description of the latest failure
+    pub reason: String,
+}
+
+/// Index layout written before `track_privacy` was added
+#[derive(bincode::Decode)]
+struct UnclippedActivityIndex {
+    user_id: String,
+    last_updated: String,
+    geojson_activities: HashSet<String>,
+    empty_activities: HashSet<String>,
+    last_full_sync: Option<String>,
+    failed_activities: HashMap<String, FailedActivity>,
+    property_hashes: HashMap<String, String>,
+}
+
+/// Index layout written before `property_hashes` was added
+#[derive(bincode::Decode)]
+struct UnhashedActivityIndex {
+    user_id: String,
+    last_updated: String,
+    geojson_activities: HashSet<String>,
+    empty_activities: HashSet<String>,
+    last_full_sync: Option<String>,
+    failed_activities: HashMap<String, FailedActivity>,
+}
+
+/// Index layout written before `failed_activities` was added
+#[derive(bincode::Decode)]
+struct UntrackedActivityIndex {
+    user_id: String,
+    last_updated: String,
+    geojson_activities: HashSet<String>,
+    empty_activities: HashSet<String>,
+    last_full_sync: Option<String>,
+}
+
+/// Index layout written before `last_full_sync` was added
+#[derive(bincode::Decode)]
+struct LegacyActivityIndex {
+    user_id: String,
+    last_updated: String,
+    geojson_activities: HashSet<String>,
+    empty_activities: HashSet<String>,
 }
 
 impl ActivityIndex {
+    pub fn decode(data: &[u8]) -> Result<Self, bincode::error::DecodeError> {
+        let config = bincode::config::standard();
+        match bincode::decode_from_slice::<Self, _>(data, config) {
+            Ok((index, _)) => Ok(index),
+            Err(e) => {
+                if let Ok((unclipped, _)) =
+                    bincode::decode_from_slice::<UnclippedActivityIndex, _>(data, config)
+                {
+                    return Ok(Self {
+                        user_id: unclipped.user_id,
+                        last_updated: unclipped.last_updated,
+                        geojson_activities: unclipped.geojson_activities,
+                        empty_activities: unclipped.empty_activities,
+                        last_full_sync: unclipped.last_full_sync,
+                        failed_activities: unclipped.failed_activities,
+                        property_hashes: unclipped.property_hashes,
+                        track_privacy: None,
+                    });
+                }
+                if let Ok((unhashed, _)) =
+                    bincode::decode_from_slice::<UnhashedActivityIndex, _>(data, config)
+                {
+                    return Ok(Self {
+                        user_id: unhashed.user_id,
+                        last_updated: unhashed.last_updated,
+                        geojson_activities: unhashed.geojson_activities,
+                        empty_activities: unhashed.empty_activities,
+                        last_full_sync: unhashed.last_full_sync,
+                        failed_activities: unhashed.failed_activities,
+                        property_hashes: HashMap::new(),
+                        track_privacy: None,
+                    });
+                }
+                if let Ok((untracked, _)) =
+                    bincode::decode_from_slice::<UntrackedActivityIndex, _>(data, config)
+                {
+                    return Ok(Self {
+                        user_id: untracked.user_id,
+                        last_updated: untracked.last_updated,
+                        geojson_activities: untracked.geojson_activities,
+                        empty_activities: untracked.empty_activities,
+                        last_full_sync: untracked.last_full_sync,
+                        failed_activities: HashMap::new(),
+                        property_hashes: HashMap::new(),
+                        track_privacy: None,
+                    });
+                }
+                let (legacy, _) =
+                    bincode::decode_from_slice::<LegacyActivityIndex, _>(data, config)
+                        .map_err(|_| e)?;
+                Ok(Self {
+                    user_id: legacy.user_id,
+                    last_updated: legacy.last_updated,
+                    geojson_activities: legacy.geojson_activities,
+                    empty_activities: legacy.empty_activities,
+                    last_full_sync: None,
+                    failed_activities: HashMap::new(),
+                    property_hashes: HashMap::new(),
+                    track_privacy: None,
+                })
+            }
+        }
+    }
+
     pub fn insert_geojson(&mut self, activity_id: &str, activity_hash: &str) {
         let key = Self::create_key(activity_id, activity_hash);
         self.geojson_activities.insert(key);
@@ -31,6 +158,10 @@ impl ActivityIndex {
             last_updated: chrono::Utc::now().to_rfc3339(),
             geojson_activities: HashSet::new(),
             empty_activities: HashSet::new(),
+            last_full_sync: None,
+            failed_activities: HashMap::new(),
+            property_hashes: HashMap::new(),
+            track_privacy: None,
         }
     }
 
@@ -40,6 +171,11 @@ impl ActivityIndex {
 
         if self.geojson_activities.contains(&key) {
             target.geojson_activities.insert(key);
+            if let Some(properties_hash) = self.property_hashes.get(&activity.id) {
+                target
+                    .property_hashes
+                    .insert(activity.id.clone(), properties_hash.clone());
+            }
             true
         } else if self.empty_activities.contains(&key) {
             target.empty_activities.insert(key);
@@ -49,7 +185,180 @@ impl ActivityIndex {
         }
     }
 
+    /// Whether the stored GeoJSON of an unchanged activity has properties
+    /// from an older version of it, including archives written before
+    /// properties were hashed
+    pub fn properties_outdated(&self, activity: &Activity) -> bool {
+        let key = Self::create_key(&activity.id, &activity.compute_hash());
+        self.geojson_activities.contains(&key)
+            && self.property_hashes.get(&activity.id) != Some(&activity.compute_properties_hash())
+    }
+
+    /// Copy the failure record of an activity that has failed at least
+    /// `threshold` times in a row in its current version, so it is skipped
+    /// until it changes. Returns whether the activity is quarantined.
+    pub fn try_copy_quarantined(
+        &self,
+        activity: &Activity,
+        threshold: u32,
+        target: &mut ActivityIndex,
+    ) -> bool {
+        match self.failed_activities.get(&activity.id) {
+            Some(failed)
+                if failed.failures >= threshold
+                    && failed.activity_hash == activity.compute_hash() =>
+            {
+                target
+                    .failed_activities
+                    .insert(activity.id.clone(), failed.clone());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Record a failure to process an activity, counting on from this
+    /// index's failures for the same version of it. Returns the number of
+    /// consecutive failures.
+    pub fn record_failure(
+        &mut self,
+        previous: Option<&ActivityIndex>,
+        activity_id: &str,
+        activity_hash: &str,
+        reason: &str,
+    ) -> u32 {
+        let previous_failures = previous
+            .and_then(|index| index.failed_activities.get(activity_id))
+            .filter(|failed| failed.activity_hash == activity_hash)
+            .map_or(0, |failed| failed.failures);
+
+        let failures = previous_failures + 1;
+        self.failed_activities.insert(
+            activity_id.to_string(),
+            FailedActivity {
+                activity_hash: activity_hash.to_string(),
+                failures,
+                reason: reason.to_string(),
+            },
+        );
+        failures
+    }
+
+    /// Copy every entry whose activity ID is not in `listed_ids`, i.e. activities
+    /// outside the window of a partial listing
+    pub fn copy_unlisted(&self, listed_ids: &HashSet<&str>, target: &mut ActivityIndex) -> usize {
+        for (activity_id, failed) in &self.failed_activities {
+            if !listed_ids.contains(activity_id.as_str()) {
+                target
+                    .failed_activities
+                    .insert(activity_id.clone(), failed.clone());
+            }
+        }
+        for (activity_id, properties_hash) in &self.property_hashes {
+            if !listed_ids.contains(activity_id.as_str()) {
+                target
+                    .property_hashes
+                    .insert(activity_id.clone(), properties_hash.clone());
+            }
+        }
+
+        let mut copied = 0;
+        for (source, dest) in [
+            (&self.geojson_activities, &mut target.geojson_activities),
+            (&self.empty_activities, &mut target.empty_activities),
+        ] {
+            for key in source {
+                if !listed_ids.contains(Self::activity_id_from_key(key)) {
+                    dest.insert(key.clone());
+                    copied += 1;
+                }
+            }
+        }
+        copied
+    }
+
     pub fn create_key(activity_id: &str, activity_hash: &str) -> String {
         format!("{activity_id}:{activity_hash}")
     }
+
+    fn activity_id_from_key(key: &str) -> &str {
+        key.rsplit_once(':').map_or(key, |(id, _)| id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn activity(name: &str) -> Activity {
+        serde_json::from_value(serde_json::json!({
+            "id": "i42",
+            "name": name,
+            "start_date_local": "2026-05-01T07:12:00",
+            "type": "Ride",
+            "elapsed_time": 3600,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_quarantine_until_changed() {
+        let ride = activity("Morning Ride");
+        let hash = ride.compute_hash();
+
+        // Failures count up across syncs for the same version
+        let mut previous: Option<ActivityIndex> = None;
+        for expected in 1..=3 {
+            let mut index = ActivityIndex::new_empty("user_1".to_string());
+            assert!(
+                !previous
+                    .as_ref()
+                    .is_some_and(|p| p.try_copy_quarantined(&ride, 3, &mut index))
+            );
+            let failures = index.record_failure(previous.as_ref(), &ride.id, &hash, "HTTP 500");
+            assert_eq!(failures, expected);
+            previous = Some(index);
+        }
+        let previous = previous.unwrap();
+
+        // Survives an encode and decode, and is then skipped
+        let encoded = bincode::encode_to_vec(&previous, bincode::config::standard()).unwrap();
+        let decoded = ActivityIndex::decode(&encoded).unwrap();
+        let mut next = ActivityIndex::new_empty("user_1".to_string());
+        assert!(decoded.try_copy_quarantined(&ride, 3, &mut next));
+        assert_eq!(next.failed_activities, previous.failed_activities);
+
+        // A changed activity is retried with a fresh count
+        let renamed = activity("Renamed Ride");
+        let mut next = ActivityIndex::new_empty("user_1".to_string());
+        assert!(!decoded.try_copy_quarantined(&renamed, 3, &mut next));
+        let failures = next.record_failure(
+            Some(&decoded),
+            &renamed.id,
+            &renamed.compute_hash(),
+            "HTTP 500",
+        );
+        assert_eq!(failures, 1);
+    }
+
+    #[test]
+    fn test_properties_outdated() {
+        let mut ride = activity("Morning Ride");
+        let mut previous = ActivityIndex::new_empty("user_1".to_string());
+        previous.insert_geojson(&ride.id, &ride.compute_hash());
+
+        // Archives written before properties were hashed are refreshed once
+        assert!(previous.properties_outdated(&ride));
+        previous
+            .property_hashes
+            .insert(ride.id.clone(), ride.compute_properties_hash());
+        assert!(!previous.properties_outdated(&ride));
+
+        // A new gear keeps the activity key, so the track is not downloaded again
+        ride.gear = Some("Gravel bike".to_string());
+        let mut next = ActivityIndex::new_empty("user_1".to_string());
+        assert!(previous.try_copy(&ride, &mut next));
+        assert!(previous.properties_outdated(&ride));
+        assert_eq!(next.property_hashes, previous.property_hashes);
+    }
 }
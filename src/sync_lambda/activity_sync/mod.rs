@@ -1,27 +1,59 @@
 use aws_sdk_s3::Client as S3Client;
 use ridelines_drivetrain::common::intervals_client::IntervalsClient;
+use ridelines_drivetrain::common::types::MapTarget;
+use std::env;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 mod archive;
 mod index;
 mod sync;
 
+use crate::explorer::ExplorerTiles;
 use crate::sync_status::SyncStatusUpdater;
+use crate::track_privacy::TrackPrivacy;
 pub use index::ActivityIndex;
+pub use sync::ListingMode;
+
+/// Output of a sync that changed the activity archive
+pub struct SyncedActivities {
+    /// Every activity with GPS data, one FeatureCollection per line
+    pub geojson_path: PathBuf,
+    /// Old versions of changed or deleted activities and new versions of
+    /// added or changed ones, in the same format
+    pub changes_path: PathBuf,
+    /// Index version the previous archive belongs to, None when starting fresh
+    pub previous_version: Option<String>,
+    /// Index version written by this sync
+    pub version: String,
+    /// Explorer tiles matching the new archive, None if they could not be computed
+    pub explorer: Option<ExplorerTiles>,
+}
+
+const DEFAULT_RECENT_WINDOW_DAYS: i64 = 30;
+const DEFAULT_FULL_SYNC_INTERVAL_DAYS: i64 = 7;
+const DEFAULT_QUARANTINE_AFTER_FAILURES: u32 = 3;
 
 pub struct ActivitySync {
     intervals_client: IntervalsClient,
     s3_client: S3Client,
     s3_bucket: String,
-    user_id: String,
+    target: MapTarget,
+    /// Where start and finish markers may be placed
+    privacy: TrackPrivacy,
     work_dir: std::path::PathBuf,
     sync_status: Arc<SyncStatusUpdater>,
+    recent_window_days: i64,
+    full_sync_interval_days: i64,
+    /// Consecutive failures after which an activity is skipped until it changes
+    quarantine_after_failures: u32,
 }
 
 impl ActivitySync {
     pub fn new(
         intervals_client: IntervalsClient,
-        user_id: &str,
+        target: MapTarget,
+        privacy: TrackPrivacy,
         s3_client: S3Client,
         s3_bucket: &str,
         work_dir: &std::path::Path,
@@ -31,9 +63,28 @@ impl ActivitySync {
             intervals_client,
             s3_client,
             s3_bucket: s3_bucket.to_string(),
-            user_id: user_id.to_string(),
+            target,
+            privacy,
             work_dir: work_dir.to_path_buf(),
             sync_status,
+            recent_window_days: env_days("SYNC_RECENT_WINDOW_DAYS", DEFAULT_RECENT_WINDOW_DAYS),
+            full_sync_interval_days: env_days(
+                "SYNC_FULL_INTERVAL_DAYS",
+                DEFAULT_FULL_SYNC_INTERVAL_DAYS,
+            ),
+            quarantine_after_failures: env::var("SYNC_QUARANTINE_AFTER_FAILURES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|failures| *failures > 0)
+                .unwrap_or(DEFAULT_QUARANTINE_AFTER_FAILURES),
         }
     }
 }
+
+fn env_days(name: &str, default: i64) -> i64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|days| *days > 0)
+        .unwrap_or(default)
+}
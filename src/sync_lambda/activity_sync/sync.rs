@@ -1,15 +1,62 @@
-use super::{ActivityIndex, ActivitySync};
-use crate::fit_converter::convert_fit_to_geojson;
+use super::{ActivityIndex, ActivitySync, SyncedActivities};
+use crate::fit_converter::{convert_fit_to_geojson, convert_streams_to_geojson};
 use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
 use function_timer::time;
 use futures::stream::{self, StreamExt};
-use ridelines_drivetrain::common::intervals_client::Activity;
+use ridelines_drivetrain::common::intervals_client::{Activity, IntervalsError};
 use ridelines_drivetrain::common::metrics;
-use tracing::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, error, info, warn};
+
+/// An activity that could not be downloaded or converted
+struct ActivityFailure {
+    activity_id: String,
+    activity_hash: String,
+    reason: String,
+    /// Rate limits, upstream server errors and network problems say nothing
+    /// about the activity, so they are reported but never quarantine it
+    transient: bool,
+}
+
+impl ActivityFailure {
+    fn from_error(activity_id: String, activity_hash: String, error: &anyhow::Error) -> Self {
+        let transient = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<IntervalsError>())
+            .is_some_and(IntervalsError::is_transient);
+        Self {
+            activity_id,
+            activity_hash,
+            reason: error.to_string(),
+            transient,
+        }
+    }
+}
+
+/// How much of the athlete's history was listed for this sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListingMode {
+    /// Only activities in the recent window; cheap, but cannot detect deletions
+    Recent,
+    /// Every activity; reconciles deletions against the index
+    Full,
+}
+
+impl ListingMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListingMode::Recent => "recent",
+            ListingMode::Full => "full",
+        }
+    }
+}
 
 impl ActivitySync {
     #[time("sync_activities_duration")]
-    pub async fn sync_activities(&self) -> Result<Option<std::path::PathBuf>> {
+    pub async fn sync_activities(&self) -> Result<Option<SyncedActivities>> {
         // Update status: starting analysis phase
         self.sync_status.start_analyzing();
 
@@ -22,36 +69,103 @@ impl ActivitySync {
             }
         };
 
-        let activities = self.intervals_client.fetch_activities().await?;
-        if activities.is_empty() {
-            info!("No activities found for user {}", self.user_id);
+        let listing_mode = self.choose_listing_mode(existing_index.as_ref());
+        let activities = match listing_mode {
+            ListingMode::Full => {
+                self.intervals_client
+                    .fetch_activities(self.target.intervals_athlete_id(), None, None)
+                    .await?
+            }
+            ListingMode::Recent => {
+                let today = Utc::now().date_naive();
+                let oldest = today - Duration::days(self.recent_window_days);
+                // Allow a day of slack since start dates are in the athlete's local time
+                let newest = today + Duration::days(1);
+                self.intervals_client
+                    .fetch_activities(
+                        self.target.intervals_athlete_id(),
+                        Some(oldest),
+                        Some(newest),
+                    )
+                    .await?
+            }
+        };
+        self.sync_status.record_listing_mode(listing_mode);
+
+        // An empty full listing is more likely an upstream problem than every
+        // activity having been deleted, so leave the archive alone
+        if activities.is_empty() && listing_mode == ListingMode::Full {
+            info!("No activities found for {}", self.target);
             return Ok(None);
         }
 
         let total_activities = activities.len();
         info!(
-            "Found {} activities for user {}",
-            total_activities, self.user_id
+            "Found {} activities for {} ({} listing)",
+            total_activities,
+            self.target,
+            listing_mode.as_str()
         );
 
         // Phase 2: Identify unchanged vs new/changed activities and create copied index
-        let (copied_index, changed_activities, has_changes) =
+        let (mut copied_index, changed_activities, refreshed, quarantined, has_changes) =
             if let Some(ref existing) = existing_index {
-                let mut copied = ActivityIndex::new_empty(self.user_id.clone());
+                let mut copied = ActivityIndex::new_empty(self.target.storage_id());
+                copied.track_privacy = Some(self.privacy.fingerprint());
                 let mut changed = Vec::new();
+                // Unchanged tracks whose stored properties are rewritten in place
+                let mut refreshed = HashMap::new();
+                let mut quarantined = 0;
 
                 for activity in &activities {
                     if existing.try_copy(activity, &mut copied) {
                         metrics::increment_activities_skipped_unchanged(1);
+                        if existing.properties_outdated(activity) {
+                            copied
+                                .property_hashes
+                                .insert(activity.id.clone(), activity.compute_properties_hash());
+                            refreshed.insert(activity.id.clone(), activity.clone());
+                        }
+                    } else if existing.try_copy_quarantined(
+                        activity,
+                        self.quarantine_after_failures,
+                        &mut copied,
+                    ) {
+                        // Failed too often in this version; retried once it changes
+                        quarantined += 1;
                     } else {
                         // Activity is new or changed, add to parallel processing queue
                         changed.push(activity.clone());
                     }
                 }
 
-                // Check if activities were deleted (existed before but not in current list)
-                let activities_deleted = existing.total_activities() > copied.total_activities();
-                let has_changes = !changed.is_empty() || activities_deleted;
+                let activities_deleted = match listing_mode {
+                    ListingMode::Full => {
+                        copied.last_full_sync = Some(Utc::now().to_rfc3339());
+
+                        // Check if activities were deleted (existed before but not in current list)
+                        existing.total_activities() > copied.total_activities()
+                    }
+                    ListingMode::Recent => {
+                        // Activities outside the window were not listed, so carry them over
+                        // untouched. Deletions are only detected by the next full listing.
+                        copied.last_full_sync = existing.last_full_sync.clone();
+                        let listed_ids: HashSet<&str> =
+                            activities.iter().map(|a| a.id.as_str()).collect();
+                        let carried = existing.copy_unlisted(&listed_ids, &mut copied);
+                        info!("Carried over {} activities outside the window", carried);
+                        false
+                    }
+                };
+                // Tracks are clipped again when the privacy settings changed
+                let privacy_changed = existing.track_privacy != copied.track_privacy;
+                if privacy_changed {
+                    info!("Privacy settings changed, clipping archived tracks again");
+                }
+                let has_changes = !changed.is_empty()
+                    || !refreshed.is_empty()
+                    || activities_deleted
+                    || privacy_changed;
 
                 if activities_deleted {
                     info!(
@@ -61,20 +175,25 @@ impl ActivitySync {
                 }
 
                 info!(
-                    "Keeping {} unchanged activities, queued {} for download.",
+                    "Keeping {} unchanged activities ({} with refreshed properties), skipping {} quarantined, queued {} for download.",
                     copied.total_activities(),
+                    refreshed.len(),
+                    quarantined,
                     changed.len()
                 );
+                metrics::increment_activities_quarantined(quarantined as u64);
 
-                (copied, changed, has_changes)
+                (copied, changed, refreshed, quarantined, has_changes)
             } else {
                 // No existing index, all activities need processing
                 info!(
                     "No existing index, processing all {} activities",
                     total_activities
                 );
-                let empty_index = ActivityIndex::new_empty(self.user_id.clone());
-                (empty_index, activities, true) // Always has changes when starting fresh
+                let mut empty_index = ActivityIndex::new_empty(self.target.storage_id());
+                empty_index.last_full_sync = Some(Utc::now().to_rfc3339());
+                empty_index.track_privacy = Some(self.privacy.fingerprint());
+                (empty_index, activities, HashMap::new(), 0, true) // Always has changes when starting fresh
             };
 
         // Update status: analysis complete
@@ -82,10 +201,15 @@ impl ActivitySync {
             total_activities,
             copied_index.total_activities(),
             changed_activities.len(),
+            quarantined,
         );
 
         // Short circuit: if no changes detected, skip archive upload and tile generation
         if !has_changes {
+            if listing_mode == ListingMode::Full {
+                // Persist the reconciliation time so the next sync can use a recent listing
+                self.upload_index(&copied_index).await?;
+            }
             info!("No activity changes detected, skipping archive upload and tile generation");
             return Ok(None);
         }
@@ -101,14 +225,38 @@ impl ActivitySync {
             let total_to_process = changed_activities.len();
             let mut processed = 0;
 
+            // The client's rate limiter gates the actual requests; this only bounds
+            // how many activities are in progress at once
             let results = stream::iter(changed_activities)
                 .map(|activity| self.process_activity(activity, &changed_activities_dir))
-                .buffer_unordered(5);
+                .buffer_unordered(self.intervals_client.max_concurrency());
 
             // Process results and update progress every 10 activities
             tokio::pin!(results);
-            while let Some(_result) = results.next().await {
+            while let Some(result) = results.next().await {
                 processed += 1;
+                if let Some(failure) = result? {
+                    let quarantine = !failure.transient && {
+                        let failures = copied_index.record_failure(
+                            existing_index.as_ref(),
+                            &failure.activity_id,
+                            &failure.activity_hash,
+                            &failure.reason,
+                        );
+                        failures >= self.quarantine_after_failures
+                    };
+                    if quarantine {
+                        warn!(
+                            "Activity {} failed {} times in a row, skipping it until it changes",
+                            failure.activity_id, self.quarantine_after_failures
+                        );
+                    }
+                    self.sync_status.record_activity_failure(
+                        &failure.activity_id,
+                        &failure.reason,
+                        quarantine,
+                    );
+                }
 
                 // Update progress every 10 activities or when complete
                 if processed % 10 == 0 || processed == total_to_process {
@@ -121,17 +269,45 @@ impl ActivitySync {
         self.sync_status.complete_downloading();
 
         // Phase 4: Finalize archive by streaming existing + new activities from temp dir
-        let geojson_file_path = self
-            .finalize_archive(&changed_activities_dir, copied_index)
+        let reclip = existing_index
+            .as_ref()
+            .is_some_and(|existing| existing.track_privacy != copied_index.track_privacy);
+        let previous_version = existing_index.map(|index| index.last_updated);
+        let synced = self
+            .finalize_archive(
+                &changed_activities_dir,
+                copied_index,
+                &refreshed,
+                reclip,
+                previous_version,
+            )
             .await?;
 
-        Ok(Some(geojson_file_path))
+        Ok(Some(synced))
+    }
+
+    /// Use a full listing when there is no index to compare against or the last
+    /// full reconciliation is older than the configured interval
+    fn choose_listing_mode(&self, existing_index: Option<&ActivityIndex>) -> ListingMode {
+        let last_full_sync = existing_index
+            .and_then(|index| index.last_full_sync.as_deref())
+            .and_then(|ts| DateTime::parse_from_rfc3339(ts).ok());
+
+        match last_full_sync {
+            Some(ts)
+                if Utc::now() - ts.with_timezone(&Utc)
+                    < Duration::days(self.full_sync_interval_days) =>
+            {
+                ListingMode::Recent
+            }
+            _ => ListingMode::Full,
+        }
     }
 
     async fn download_and_convert_activity(&self, activity: &Activity) -> Result<Option<String>> {
         match self.intervals_client.download_fit(&activity.id).await {
-            Ok(Some(fit_data)) => convert_fit_to_geojson(&fit_data, activity).await,
-            Ok(None) => Ok(None),
+            Ok(fit_data) => convert_fit_to_geojson(&fit_data, activity, &self.privacy).await,
+            Err(IntervalsError::NoGps) => self.convert_streams_fallback(activity).await,
             Err(e) => {
                 error!("Failed to download activity {}: {}", activity.id, e);
                 Err(e.into())
@@ -139,7 +315,37 @@ impl ActivitySync {
         }
     }
 
-    async fn process_activity(&self, activity: Activity, temp_dir: &std::path::Path) -> Result<()> {
+    /// Some activities without a FIT file (manual uploads, some integrations)
+    /// still have latlng streams
+    async fn convert_streams_fallback(&self, activity: &Activity) -> Result<Option<String>> {
+        match self.intervals_client.fetch_streams(&activity.id).await {
+            Ok(streams) => {
+                let geojson = convert_streams_to_geojson(&streams, activity, &self.privacy).await?;
+                if geojson.is_some() {
+                    debug!("Built GeoJSON from streams for activity {}", activity.id);
+                    metrics::increment_activities_from_streams(1);
+                }
+                Ok(geojson)
+            }
+            Err(IntervalsError::NoGps | IntervalsError::NotFound) => Ok(None),
+            Err(e) => {
+                error!(
+                    "Failed to fetch streams for activity {}: {}",
+                    activity.id, e
+                );
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Download and convert one activity into the temp directory. Failures
+    /// that only affect this activity are returned rather than failing the
+    /// sync; failing to write to the temp directory fails the sync.
+    async fn process_activity(
+        &self,
+        activity: Activity,
+        temp_dir: &std::path::Path,
+    ) -> Result<Option<ActivityFailure>> {
         info!(
             "Processing activity: {} ID: {} Date: {}",
             activity.name, activity.id, activity.start_date_local
@@ -163,11 +369,16 @@ impl ActivitySync {
                         debug!("Saved GeoJSON to: {}", temp_file_path.display());
                     }
                     Err(e) => {
+                        // The Lambda's own disk is at fault, not the activity
                         error!(
                             "Failed to write activity {} to temp file: {}",
                             activity.id, e
                         );
                         metrics::increment_activities_failed(1);
+                        return Err(anyhow::anyhow!(
+                            "Failed to save activity {}: {e}",
+                            activity.id
+                        ));
                     }
                 }
             }
@@ -184,15 +395,53 @@ impl ActivitySync {
                     Err(e) => {
                         error!("Failed to write stub for activity {}: {}", activity.id, e);
                         metrics::increment_activities_failed(1);
+                        return Err(anyhow::anyhow!(
+                            "Failed to save activity {}: {e}",
+                            activity.id
+                        ));
                     }
                 }
             }
             Err(e) => {
                 error!("Failed to download/convert activity {}: {}", activity.id, e);
                 metrics::increment_activities_failed(1);
+
+                // An expired or revoked token fails every remaining download, so abort the sync
+                if let Some(IntervalsError::Auth(_)) = e.downcast_ref::<IntervalsError>() {
+                    return Err(e);
+                }
+                return Ok(Some(ActivityFailure::from_error(
+                    activity.id,
+                    activity_hash,
+                    &e,
+                )));
             }
         }
 
-        Ok(())
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn test_transient_failures_are_not_counted() {
+        let failure = |error: IntervalsError| {
+            ActivityFailure::from_error("i42".to_string(), "abc".to_string(), &error.into())
+        };
+
+        assert!(failure(IntervalsError::Upstream(StatusCode::BAD_GATEWAY)).transient);
+        assert!(!failure(IntervalsError::Upstream(StatusCode::BAD_REQUEST)).transient);
+        assert!(!failure(IntervalsError::Decode("bad FIT file".to_string())).transient);
+
+        // Found through context added on the way up
+        let wrapped =
+            anyhow::Error::from(IntervalsError::Upstream(StatusCode::SERVICE_UNAVAILABLE))
+                .context("Failed to download activity");
+        let failure = ActivityFailure::from_error("i42".to_string(), "abc".to_string(), &wrapped);
+        assert!(failure.transient);
     }
 }
@@ -0,0 +1,270 @@
+use anyhow::{Context, Result};
+use aws_sdk_s3::Client as S3Client;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use function_timer::time;
+use ridelines_drivetrain::common::metrics;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{error, info};
+
+/// Archives are content-addressed, so they never change once uploaded
+const PMTILES_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Archives larger than this are uploaded in parts of this size
+const MULTIPART_PART_SIZE: usize = 16 * 1024 * 1024;
+
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Uploads and downloads PMTiles archives in the activities bucket
+pub struct ArchiveStore {
+    s3_client: S3Client,
+    bucket: String,
+}
+
+impl ArchiveStore {
+    pub fn new(s3_client: S3Client, bucket: &str) -> Self {
+        Self {
+            s3_client,
+            bucket: bucket.to_string(),
+        }
+    }
+
+    pub async fn put_archive(
+        &self,
+        s3_key: &str,
+        archive_file: &str,
+        content_hash: &str,
+        file_len: u64,
+    ) -> Result<()> {
+        // Upload to activities S3 bucket with hash-based key
+        let result = if file_len <= MULTIPART_PART_SIZE as u64 {
+            self.put_single_part(s3_key, archive_file, content_hash)
+                .await
+        } else {
+            self.put_multipart(s3_key, archive_file, content_hash, file_len)
+                .await
+        };
+
+        match result {
+            Ok(()) => {
+                metrics::increment_s3_upload_success();
+                info!("Successfully uploaded archive to S3: {s3_key}");
+                Ok(())
+            }
+            Err(e) => {
+                metrics::increment_s3_upload_failure();
+                Err(e.context("Failed to upload archive to S3"))
+            }
+        }
+    }
+
+    async fn put_single_part(
+        &self,
+        s3_key: &str,
+        archive_file: &str,
+        content_hash: &str,
+    ) -> Result<()> {
+        let file_content = fs::read(archive_file)
+            .await
+            .context("Failed to read archive file")?;
+
+        self.s3_client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(s3_key)
+            .body(ByteStream::from(file_content))
+            .content_type("application/vnd.pmtiles")
+            .cache_control(PMTILES_CACHE_CONTROL)
+            .metadata("sha256", content_hash)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+        Ok(())
+    }
+
+    #[time("pmtiles_multipart_upload_duration")]
+    async fn put_multipart(
+        &self,
+        s3_key: &str,
+        archive_file: &str,
+        content_hash: &str,
+        file_len: u64,
+    ) -> Result<()> {
+        let upload = self
+            .s3_client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(s3_key)
+            .content_type("application/vnd.pmtiles")
+            .cache_control(PMTILES_CACHE_CONTROL)
+            .metadata("sha256", content_hash)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to start multipart upload: {e}"))?;
+        let upload_id = upload
+            .upload_id()
+            .context("S3 returned no multipart upload ID")?;
+
+        info!(
+            "Uploading {file_len} bytes to {s3_key} in {} parts",
+            file_len.div_ceil(MULTIPART_PART_SIZE as u64)
+        );
+
+        let result = async {
+            let parts = self.upload_parts(s3_key, upload_id, archive_file).await?;
+            self.s3_client
+                .complete_multipart_upload()
+                .bucket(&self.bucket)
+                .key(s3_key)
+                .upload_id(upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build(),
+                )
+                .send()
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to complete multipart upload: {e}"))?;
+            Ok(())
+        }
+        .await;
+
+        if result.is_err() {
+            // Uploaded parts are otherwise kept, and billed, until a lifecycle rule removes them
+            metrics::increment_multipart_upload_aborted();
+            if let Err(e) = self
+                .s3_client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(s3_key)
+                .upload_id(upload_id)
+                .send()
+                .await
+            {
+                error!("Failed to abort multipart upload {upload_id} for {s3_key}: {e}");
+            }
+        }
+        result
+    }
+
+    /// Upload the file one part at a time, so only a single part is in memory
+    async fn upload_parts(
+        &self,
+        s3_key: &str,
+        upload_id: &str,
+        archive_file: &str,
+    ) -> Result<Vec<CompletedPart>> {
+        let mut file = fs::File::open(archive_file)
+            .await
+            .context("Failed to open archive file")?;
+        let mut parts = Vec::new();
+
+        loop {
+            let chunk = read_chunk(&mut file, MULTIPART_PART_SIZE).await?;
+            if chunk.is_empty() {
+                break;
+            }
+
+            let part_number = parts.len() as i32 + 1;
+            let part = self
+                .s3_client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(s3_key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(chunk))
+                .send()
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to upload part {part_number}: {e}"))?;
+
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(part.e_tag().map(String::from))
+                    .build(),
+            );
+        }
+
+        Ok(parts)
+    }
+
+    pub async fn object_exists(&self, s3_key: &str) -> Result<bool> {
+        match self
+            .s3_client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(s3_key)
+            .send()
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(false),
+            Err(e) => Err(anyhow::anyhow!("Failed to check for object {s3_key}: {e}")),
+        }
+    }
+
+    pub async fn clear_expiration_tag(&self, s3_key: &str) -> Result<()> {
+        self.s3_client
+            .delete_object_tagging()
+            .bucket(&self.bucket)
+            .key(s3_key)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to clear tags on object {s3_key}: {e}"))?;
+        Ok(())
+    }
+
+    /// Stream an object to a local file, so large archives are never fully in memory
+    pub async fn download_object(&self, s3_key: &str, path: &str) -> Result<()> {
+        let mut body = self
+            .s3_client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(s3_key)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to download {s3_key}: {e}"))?
+            .body;
+
+        let mut file = fs::File::create(path)
+            .await
+            .with_context(|| format!("Failed to create {path}"))?;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.with_context(|| format!("Failed to download {s3_key}"))?;
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+/// SHA-256 hex digest and length of a file, read in chunks
+pub async fn hash_file(path: &str) -> Result<(String, u64)> {
+    let mut file = fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {path}"))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+    let mut len = 0u64;
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        len += read as u64;
+    }
+
+    Ok((format!("{:x}", hasher.finalize()), len))
+}
+
+/// Read up to `size` bytes, returning fewer only at the end of the file
+async fn read_chunk(file: &mut fs::File, size: usize) -> Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(size);
+    file.take(size as u64).read_to_end(&mut chunk).await?;
+    Ok(chunk)
+}
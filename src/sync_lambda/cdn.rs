@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+use aws_config::SdkConfig;
+use aws_sdk_cloudfront::Client as CloudFrontClient;
+use aws_sdk_cloudfront::types::{InvalidationBatch, Paths};
+use futures::future::BoxFuture;
+use ridelines_drivetrain::common::metrics;
+use ridelines_drivetrain::common::types::MapTarget;
+use std::env;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{error, info};
+
+/// Removes stale copies of published files from a CDN
+pub trait CacheInvalidator: Send + Sync {
+    /// Provider name used in logs and metric labels
+    fn name(&self) -> &'static str;
+
+    /// Invalidate URL paths, which may end in a `*` wildcard
+    fn invalidate<'a>(&'a self, paths: &'a [String]) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Used when no CDN is configured
+pub struct NoopInvalidator;
+
+impl CacheInvalidator for NoopInvalidator {
+    fn name(&self) -> &'static str {
+        "noop"
+    }
+
+    fn invalidate<'a>(&'a self, _paths: &'a [String]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+pub struct CloudFrontInvalidator {
+    client: CloudFrontClient,
+    distribution_id: String,
+}
+
+impl CloudFrontInvalidator {
+    pub fn new(client: CloudFrontClient, distribution_id: &str) -> Self {
+        Self {
+            client,
+            distribution_id: distribution_id.to_string(),
+        }
+    }
+}
+
+impl CacheInvalidator for CloudFrontInvalidator {
+    fn name(&self) -> &'static str {
+        "cloudfront"
+    }
+
+    fn invalidate<'a>(&'a self, paths: &'a [String]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let paths = Paths::builder()
+                .quantity(paths.len() as i32)
+                .set_items(Some(paths.to_vec()))
+                .build()?;
+            let batch = InvalidationBatch::builder()
+                .paths(paths)
+                .caller_reference(uuid::Uuid::new_v4().to_string())
+                .build()?;
+
+            let response = self
+                .client
+                .create_invalidation()
+                .distribution_id(&self.distribution_id)
+                .invalidation_batch(batch)
+                .send()
+                .await
+                .map_err(|e| anyhow::anyhow!("CloudFront invalidation failed: {e}"))?;
+
+            let invalidation_id = response
+                .invalidation()
+                .map(|invalidation| invalidation.id())
+                .context("CloudFront returned no invalidation")?;
+            info!(
+                "Created CloudFront invalidation {invalidation_id} on {}",
+                self.distribution_id
+            );
+            Ok(())
+        })
+    }
+}
+
+/// CloudFront when CLOUDFRONT_DISTRIBUTION_ID is set, otherwise a no-op
+pub fn invalidator_from_env(config: &SdkConfig) -> Arc<dyn CacheInvalidator> {
+    match env::var("CLOUDFRONT_DISTRIBUTION_ID") {
+        Ok(distribution_id) if !distribution_id.is_empty() => Arc::new(CloudFrontInvalidator::new(
+            CloudFrontClient::new(config),
+            &distribution_id,
+        )),
+        _ => Arc::new(NoopInvalidator),
+    }
+}
+
+/// Paths served for a map: the PMTiles archives and any files derived from
+/// them, all under the map's storage prefix
+pub fn map_paths(target: &MapTarget) -> Vec<String> {
+    vec![format!("/activities/{}/*", target.storage_id())]
+}
+
+/// Invalidate the map's paths, recording latency and failures. Published
+/// files are already in place, so a failure is logged rather than returned.
+pub async fn invalidate_map(invalidator: &dyn CacheInvalidator, target: &MapTarget) {
+    invalidate_paths(invalidator, &map_paths(target)).await;
+}
+
+/// Path an object with this S3 key is served at
+pub fn object_path(key: &str) -> String {
+    format!("/{key}")
+}
+
+/// Invalidate the given paths, recording latency and failures like
+/// `invalidate_map`. Used for files rewritten under a fixed key when the
+/// map's pointer did not change.
+pub async fn invalidate_paths(invalidator: &dyn CacheInvalidator, paths: &[String]) {
+    let started = Instant::now();
+    let result = invalidator.invalidate(paths).await;
+    metrics::record_cdn_invalidation_duration(invalidator.name(), started.elapsed());
+
+    match result {
+        Ok(()) => {
+            metrics::increment_cdn_invalidation_success(invalidator.name());
+            info!("Invalidated {paths:?} via {}", invalidator.name());
+        }
+        Err(e) => {
+            metrics::increment_cdn_invalidation_failure(invalidator.name());
+            error!(
+                "Failed to invalidate {paths:?} via {}: {e:#}",
+                invalidator.name()
+            );
+        }
+    }
+}
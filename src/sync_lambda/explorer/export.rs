@@ -0,0 +1,190 @@
+use super::{EXPLORER_ZOOMS, ExplorerTiles, ZoomStats, zoom_stats};
+use crate::cdn::{self, CacheInvalidator};
+use crate::map_keys::{self, MapKeys};
+use crate::tiles::pmtiles;
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_s3::Client as S3Client;
+use aws_sdk_s3::primitives::ByteStream;
+use chrono::Utc;
+use function_timer::time;
+use ridelines_drivetrain::common::types::MapTarget;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::f64::consts::PI;
+use std::sync::Arc;
+use tracing::info;
+
+/// Exports are overwritten in place after every sync that changes activities
+const EXPORT_CACHE_CONTROL: &str = "public, max-age=300";
+
+/// Published summary of a map's explorer tiles
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExplorerSummary {
+    source_version: String,
+    generated_at: String,
+    zooms: Vec<ZoomStats>,
+    /// GeoJSON of the visited tiles, keyed by zoom
+    geojson_keys: BTreeMap<u8, String>,
+}
+
+/// Publishes a map's explorer tiles as one gzipped GeoJSON file per zoom and
+/// a JSON summary under `activities/{storage_id}/explorer/`. The users table
+/// points at the summary with `explorerKey`.
+pub struct ExplorerExporter {
+    s3_client: S3Client,
+    map_keys: MapKeys,
+    target: MapTarget,
+    activities_bucket: String,
+    invalidator: Arc<dyn CacheInvalidator>,
+}
+
+impl ExplorerExporter {
+    pub fn new(
+        s3_client: S3Client,
+        dynamodb_client: DynamoDbClient,
+        target: MapTarget,
+        invalidator: Arc<dyn CacheInvalidator>,
+    ) -> Result<Self> {
+        let activities_bucket = env::var("ACTIVITIES_S3_BUCKET")
+            .context("ACTIVITIES_S3_BUCKET environment variable not set")?;
+        let users_table_name = env::var("USERS_TABLE_NAME")
+            .context("USERS_TABLE_NAME environment variable not set")?;
+
+        Ok(Self {
+            s3_client,
+            map_keys: MapKeys::new(dynamodb_client, &users_table_name, target.clone()),
+            target,
+            activities_bucket,
+            invalidator,
+        })
+    }
+
+    #[time("explorer_export_duration")]
+    pub async fn publish(&self, explorer: &ExplorerTiles) -> Result<()> {
+        let prefix = format!("activities/{}/explorer", self.target.storage_id());
+        let mut summary = ExplorerSummary {
+            source_version: explorer.source_version.clone(),
+            generated_at: Utc::now().to_rfc3339(),
+            zooms: Vec::with_capacity(EXPLORER_ZOOMS.len()),
+            geojson_keys: BTreeMap::new(),
+        };
+
+        for zoom in EXPLORER_ZOOMS {
+            let visited = explorer.visited(zoom);
+            let (stats, cluster) = zoom_stats(zoom, &visited);
+            let geojson = tiles_geojson(zoom, &visited, &cluster, &stats);
+
+            let key = format!("{prefix}/z{zoom}.geojson");
+            let body = pmtiles::gzip(&serde_json::to_vec(&geojson)?)?;
+            self.s3_client
+                .put_object()
+                .bucket(&self.activities_bucket)
+                .key(&key)
+                .body(ByteStream::from(body))
+                .content_type("application/geo+json")
+                .content_encoding("gzip")
+                .cache_control(EXPORT_CACHE_CONTROL)
+                .send()
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to upload explorer tiles {key}: {e}"))?;
+
+            info!(
+                "Explorer tiles for {} at zoom {zoom}: {} tiles, max square {}, max cluster {}",
+                self.target, stats.tile_count, stats.max_square, stats.max_cluster
+            );
+            summary.geojson_keys.insert(zoom, key);
+            summary.zooms.push(stats);
+        }
+
+        let summary_key = format!("{prefix}/summary.json");
+        self.s3_client
+            .put_object()
+            .bucket(&self.activities_bucket)
+            .key(&summary_key)
+            .body(ByteStream::from(serde_json::to_vec(&summary)?))
+            .content_type("application/json")
+            .cache_control(EXPORT_CACHE_CONTROL)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to upload explorer summary {summary_key}: {e}"))?;
+
+        let current = self.map_keys.get(&[map_keys::EXPLORER_KEY]).await?;
+        if current.get(map_keys::EXPLORER_KEY) != Some(&summary_key) {
+            self.map_keys
+                .set(&[(map_keys::EXPLORER_KEY, &summary_key)])
+                .await?;
+        }
+
+        // Exports keep their keys, so cached copies are dropped once all are uploaded
+        let paths = [cdn::object_path(&format!("{prefix}/*"))];
+        cdn::invalidate_paths(self.invalidator.as_ref(), &paths).await;
+        Ok(())
+    }
+}
+
+/// One square polygon per visited tile, flagged when it belongs to the
+/// largest cluster, plus the outline of the largest square
+fn tiles_geojson(
+    zoom: u8,
+    visited: &HashSet<(u32, u32)>,
+    cluster: &HashSet<(u32, u32)>,
+    stats: &ZoomStats,
+) -> serde_json::Value {
+    let mut tiles: Vec<(u32, u32)> = visited.iter().copied().collect();
+    tiles.sort_unstable();
+
+    let mut features: Vec<serde_json::Value> = tiles
+        .into_iter()
+        .map(|(x, y)| {
+            serde_json::json!({
+                "type": "Feature",
+                "geometry": tile_polygon(zoom, [x, y], 1),
+                "properties": {
+                    "zoom": zoom,
+                    "x": x,
+                    "y": y,
+                    "cluster": cluster.contains(&(x, y)),
+                },
+            })
+        })
+        .collect();
+
+    if let Some(origin) = stats.max_square_origin {
+        features.push(serde_json::json!({
+            "type": "Feature",
+            "geometry": tile_polygon(zoom, origin, stats.max_square),
+            "properties": {"zoom": zoom, "maxSquare": stats.max_square},
+        }));
+    }
+
+    serde_json::json!({"type": "FeatureCollection", "features": features})
+}
+
+/// Polygon covering `size` by `size` tiles from the top-left tile `origin`
+fn tile_polygon(zoom: u8, [x, y]: [u32; 2], size: u32) -> serde_json::Value {
+    let scale = f64::from(1u32 << zoom);
+    let lon = |x: u32| round(f64::from(x) / scale * 360.0 - 180.0);
+    let lat = |y: u32| {
+        round(
+            (PI * (1.0 - 2.0 * f64::from(y) / scale))
+                .sinh()
+                .atan()
+                .to_degrees(),
+        )
+    };
+
+    let (west, east) = (lon(x), lon(x + size));
+    let (north, south) = (lat(y), lat(y + size));
+    serde_json::json!({
+        "type": "Polygon",
+        "coordinates": [[[west, north], [west, south], [east, south], [east, north], [west, north]]],
+    })
+}
+
+/// Six decimal places is about 10cm, far finer than a zoom 17 tile
+fn round(degrees: f64) -> f64 {
+    (degrees * 1e6).round() / 1e6
+}
@@ -0,0 +1,278 @@
+use crate::tiles::{WorldPoint, activity_lines};
+use anyhow::{Context, Result};
+use geojson::FeatureCollection;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use tracing::warn;
+
+mod export;
+mod stats;
+
+pub use export::ExplorerExporter;
+pub use stats::{ZoomStats, zoom_stats};
+
+/// Slippy map zooms whose tiles are tracked, as used by VeloViewer's
+/// explorer tiles and squadrats
+pub const EXPLORER_ZOOMS: [u8; 2] = [14, 17];
+
+/// The explorer tiles every archived activity passes through. Each tile
+/// keeps the number of activities touching it, so changed and deleted
+/// activities can be taken out again without rescanning the archive.
+#[derive(Debug, Default, Clone)]
+pub struct ExplorerTiles {
+    /// Activity index version the tiles were computed from
+    pub source_version: String,
+    zooms: BTreeMap<u8, HashMap<(u32, u32), u32>>,
+}
+
+/// Stored layout of `ExplorerTiles`
+#[derive(bincode::Encode, bincode::Decode)]
+struct StoredExplorerTiles {
+    source_version: String,
+    zooms: Vec<StoredZoom>,
+}
+
+/// Tile IDs (y * 2^zoom + x) in ascending order, each stored as the
+/// difference from the previous one so neighbouring tiles take a byte or two
+#[derive(bincode::Encode, bincode::Decode)]
+struct StoredZoom {
+    zoom: u8,
+    id_deltas: Vec<u64>,
+    activity_counts: Vec<u32>,
+}
+
+impl ExplorerTiles {
+    /// Compute the tiles of every activity in a line-delimited GeoJSON archive
+    pub fn from_geojson(geojson_path: &Path) -> Result<Self> {
+        let file = File::open(geojson_path)
+            .with_context(|| format!("Failed to open {}", geojson_path.display()))?;
+
+        let mut explorer = Self::default();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<FeatureCollection>(&line) {
+                Ok(collection) => explorer.add_activity(&collection),
+                Err(e) => warn!("Skipping unparseable activity for explorer tiles: {}", e),
+            }
+        }
+        Ok(explorer)
+    }
+
+    pub fn add_activity(&mut self, collection: &FeatureCollection) {
+        for (zoom, tiles) in activity_tiles(collection) {
+            let counts = self.zooms.entry(zoom).or_default();
+            for tile in tiles {
+                *counts.entry(tile).or_default() += 1;
+            }
+        }
+    }
+
+    pub fn remove_activity(&mut self, collection: &FeatureCollection) {
+        for (zoom, tiles) in activity_tiles(collection) {
+            let Some(counts) = self.zooms.get_mut(&zoom) else {
+                continue;
+            };
+            for tile in tiles {
+                if let Entry::Occupied(mut entry) = counts.entry(tile) {
+                    *entry.get_mut() -= 1;
+                    if *entry.get() == 0 {
+                        entry.remove();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tiles at least one activity passes through
+    pub fn visited(&self, zoom: u8) -> HashSet<(u32, u32)> {
+        self.zooms
+            .get(&zoom)
+            .map(|counts| counts.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let zooms = self
+            .zooms
+            .iter()
+            .map(|(&zoom, counts)| {
+                let mut tiles: Vec<(u64, u32)> = counts
+                    .iter()
+                    .map(|(&(x, y), &count)| ((u64::from(y) << zoom) + u64::from(x), count))
+                    .collect();
+                tiles.sort_unstable();
+
+                let mut previous = 0;
+                let id_deltas = tiles
+                    .iter()
+                    .map(|&(id, _)| {
+                        let delta = id - previous;
+                        previous = id;
+                        delta
+                    })
+                    .collect();
+                StoredZoom {
+                    zoom,
+                    id_deltas,
+                    activity_counts: tiles.iter().map(|&(_, count)| count).collect(),
+                }
+            })
+            .collect();
+
+        let stored = StoredExplorerTiles {
+            source_version: self.source_version.clone(),
+            zooms,
+        };
+        Ok(bincode::encode_to_vec(stored, bincode::config::standard())?)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let (stored, _): (StoredExplorerTiles, _) =
+            bincode::decode_from_slice(data, bincode::config::standard())?;
+
+        let mut zooms = BTreeMap::new();
+        for zoom in stored.zooms {
+            anyhow::ensure!(
+                zoom.zoom <= 24 && zoom.id_deltas.len() == zoom.activity_counts.len(),
+                "Corrupt explorer tiles for zoom {}",
+                zoom.zoom
+            );
+            let mask = (1u64 << zoom.zoom) - 1;
+            let mut id = 0u64;
+            let mut counts = HashMap::with_capacity(zoom.id_deltas.len());
+            for (delta, count) in zoom.id_deltas.into_iter().zip(zoom.activity_counts) {
+                id += delta;
+                counts.insert(((id & mask) as u32, (id >> zoom.zoom) as u32), count);
+            }
+            zooms.insert(zoom.zoom, counts);
+        }
+
+        Ok(Self {
+            source_version: stored.source_version,
+            zooms,
+        })
+    }
+}
+
+/// Every explorer tile the activity's track passes through, at each
+/// explorer zoom. Start and finish markers lie on the track, so only line
+/// features are used.
+fn activity_tiles(collection: &FeatureCollection) -> BTreeMap<u8, HashSet<(u32, u32)>> {
+    let mut tiles: BTreeMap<u8, HashSet<(u32, u32)>> = EXPLORER_ZOOMS
+        .iter()
+        .map(|&zoom| (zoom, HashSet::new()))
+        .collect();
+
+    for points in activity_lines(collection) {
+        for (zoom, zoom_tiles) in &mut tiles {
+            for segment in points.windows(2) {
+                segment_tiles(segment[0], segment[1], *zoom, zoom_tiles);
+            }
+            if let [point] = points.as_slice() {
+                segment_tiles(*point, *point, *zoom, zoom_tiles);
+            }
+        }
+    }
+
+    tiles
+}
+
+/// Add every tile a segment passes through, walking the tile grid from one
+/// end to the other so tiles whose corner the segment cuts are included
+fn segment_tiles(a: WorldPoint, b: WorldPoint, zoom: u8, tiles: &mut HashSet<(u32, u32)>) {
+    let scale = f64::from(1u32 << zoom);
+    let max = (1i64 << zoom) - 1;
+    let tile = |v: f64| (v.floor() as i64).clamp(0, max);
+
+    let (ax, ay) = (a[0] * scale, a[1] * scale);
+    let (bx, by) = (b[0] * scale, b[1] * scale);
+    let (mut x, mut y) = (tile(ax), tile(ay));
+    let (end_x, end_y) = (tile(bx), tile(by));
+
+    // Distance along the segment, as a fraction of its length, to the next
+    // vertical and horizontal tile edge
+    let (dx, dy) = (bx - ax, by - ay);
+    let step_x = if dx > 0.0 { 1 } else { -1 };
+    let step_y = if dy > 0.0 { 1 } else { -1 };
+    let first_edge = |start: f64, cell: i64, delta: f64| {
+        if delta > 0.0 {
+            ((cell + 1) as f64 - start) / delta
+        } else if delta < 0.0 {
+            (cell as f64 - start) / delta
+        } else {
+            f64::INFINITY
+        }
+    };
+    let mut next_x = first_edge(ax, x, dx);
+    let mut next_y = first_edge(ay, y, dy);
+    let delta_x = if dx != 0.0 {
+        1.0 / dx.abs()
+    } else {
+        f64::INFINITY
+    };
+    let delta_y = if dy != 0.0 {
+        1.0 / dy.abs()
+    } else {
+        f64::INFINITY
+    };
+
+    let steps = (end_x - x).abs() + (end_y - y).abs();
+    tiles.insert((x as u32, y as u32));
+    for _ in 0..steps {
+        if next_x < next_y {
+            x = (x + step_x).clamp(0, max);
+            next_x += delta_x;
+        } else {
+            y = (y + step_y).clamp(0, max);
+            next_y += delta_y;
+        }
+        tiles.insert((x as u32, y as u32));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn activity(track: &[[f64; 2]]) -> FeatureCollection {
+        serde_json::from_value(serde_json::json!({
+            "type": "FeatureCollection",
+            "features": [{
+                "type": "Feature",
+                "geometry": {"type": "LineString", "coordinates": track},
+                "properties": {},
+            }],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_explorer_tiles() {
+        // A diagonal ride across several zoom 14 tiles near London
+        let ride = activity(&[[-0.2, 51.5], [-0.1, 51.45]]);
+        let mut explorer = ExplorerTiles::default();
+        explorer.add_activity(&ride);
+        explorer.add_activity(&activity(&[[-0.15, 51.48], [-0.14, 51.48]]));
+
+        // The walk crosses about five tiles east and four south
+        let visited = explorer.visited(14);
+        assert!(visited.len() >= 6, "{visited:?}");
+        assert!(explorer.visited(17).len() > visited.len());
+
+        let mut restored = ExplorerTiles::decode(&explorer.encode().unwrap()).unwrap();
+        assert_eq!(restored.visited(14), visited);
+        assert_eq!(restored.visited(17), explorer.visited(17));
+
+        // Removing the ride keeps the tiles of the other activity
+        restored.remove_activity(&ride);
+        let remaining = restored.visited(14);
+        assert!(!remaining.is_empty() && remaining.len() < visited.len());
+        assert!(remaining.is_subset(&visited));
+    }
+}
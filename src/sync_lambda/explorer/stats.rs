@@ -0,0 +1,130 @@
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Summary of the explorer tiles at one zoom
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ZoomStats {
+    pub zoom: u8,
+    pub tile_count: usize,
+    /// Side length, in tiles, of the largest square with every tile visited
+    pub max_square: u32,
+    /// Top-left tile of that square
+    pub max_square_origin: Option<[u32; 2]>,
+    /// Tiles in the largest connected group of cluster tiles, i.e. visited
+    /// tiles whose four neighbours are all visited
+    pub max_cluster: usize,
+    /// Cluster tiles in any group
+    pub cluster_tile_count: usize,
+}
+
+/// Statistics for the visited tiles at one zoom, and the tiles of the
+/// largest cluster
+pub fn zoom_stats(zoom: u8, visited: &HashSet<(u32, u32)>) -> (ZoomStats, HashSet<(u32, u32)>) {
+    let (max_square, max_square_origin) = max_square(visited);
+    let cluster_tiles: HashSet<(u32, u32)> = visited
+        .iter()
+        .copied()
+        .filter(|&tile| neighbours(tile).iter().all(|n| visited.contains(n)))
+        .collect();
+    let largest_cluster = largest_group(&cluster_tiles);
+
+    let stats = ZoomStats {
+        zoom,
+        tile_count: visited.len(),
+        max_square,
+        max_square_origin,
+        max_cluster: largest_cluster.len(),
+        cluster_tile_count: cluster_tiles.len(),
+    };
+    (stats, largest_cluster)
+}
+
+/// Largest fully visited square, growing each tile's square from the
+/// squares of the tiles to its right and below
+fn max_square(visited: &HashSet<(u32, u32)>) -> (u32, Option<[u32; 2]>) {
+    let mut tiles: Vec<(u32, u32)> = visited.iter().copied().collect();
+    // Each tile needs the squares of the tiles to its right and below first
+    tiles.sort_unstable_by_key(|&(x, y)| Reverse((y, x)));
+
+    // Side of the largest square whose top-left corner is each tile
+    let mut sides: HashMap<(u32, u32), u32> = HashMap::with_capacity(tiles.len());
+    let mut best = (0, None);
+    for (x, y) in tiles {
+        let side_at = |tile: (u32, u32)| sides.get(&tile).copied().unwrap_or(0);
+        let side = 1 + side_at((x + 1, y))
+            .min(side_at((x, y + 1)))
+            .min(side_at((x + 1, y + 1)));
+        sides.insert((x, y), side);
+        if side > best.0 || (side == best.0 && best.1.is_some_and(|o: [u32; 2]| [x, y] < o)) {
+            best = (side, Some([x, y]));
+        }
+    }
+    best
+}
+
+/// The largest set of edge-connected tiles, preferring the one with the
+/// lowest tile on a tie so the result is stable
+fn largest_group(tiles: &HashSet<(u32, u32)>) -> HashSet<(u32, u32)> {
+    let mut starts: Vec<(u32, u32)> = tiles.iter().copied().collect();
+    starts.sort_unstable();
+
+    let mut seen = HashSet::with_capacity(tiles.len());
+    let mut largest = HashSet::new();
+    for start in starts {
+        if !seen.insert(start) {
+            continue;
+        }
+
+        let mut group = HashSet::from([start]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(tile) = queue.pop_front() {
+            for neighbour in neighbours(tile) {
+                if tiles.contains(&neighbour) && seen.insert(neighbour) {
+                    group.insert(neighbour);
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+        if group.len() > largest.len() {
+            largest = group;
+        }
+    }
+    largest
+}
+
+/// The four edge-adjacent tiles. On the edge of the world the missing
+/// neighbour wraps to a coordinate that is never visited.
+fn neighbours((x, y): (u32, u32)) -> [(u32, u32); 4] {
+    [
+        (x.wrapping_sub(1), y),
+        (x + 1, y),
+        (x, y.wrapping_sub(1)),
+        (x, y + 1),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zoom_stats() {
+        // A 4x4 block with one corner missing, plus a separate 3x3 block
+        let mut visited: HashSet<(u32, u32)> = (10..14)
+            .flat_map(|x| (20..24).map(move |y| (x, y)))
+            .filter(|&tile| tile != (13, 23))
+            .collect();
+        visited.extend((30..33).flat_map(|x| (40..43).map(move |y| (x, y))));
+
+        let (stats, cluster) = zoom_stats(14, &visited);
+        assert_eq!(stats.tile_count, 15 + 9);
+        assert_eq!(stats.max_square, 3);
+        assert_eq!(stats.max_square_origin, Some([10, 20]));
+        // The inner 2x2 of the first block and the centre of the second
+        assert_eq!(stats.cluster_tile_count, 5);
+        assert_eq!(stats.max_cluster, 4);
+        assert!(cluster.contains(&(11, 21)) && cluster.contains(&(12, 22)));
+    }
+}
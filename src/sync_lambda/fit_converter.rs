@@ -1,8 +1,13 @@
+use crate::track_privacy::TrackPrivacy;
 use anyhow::Result;
 use fitparser::{FitDataRecord, Value as FitValue, profile::MesgNum};
 use geo::{Distance, Haversine, point};
 use geojson::{Feature, FeatureCollection, GeoJson, Geometry, Value};
 
+/// Tracks are split into separate lines where consecutive points are further
+/// apart than this
+pub const MAX_GAP_METERS: f64 = 100.0;
+
 fn extract_coordinate_from_record(data_record: &FitDataRecord) -> Option<Vec<f64>> {
     let fields = data_record.fields();
 
@@ -86,11 +91,12 @@ fn split_coordinates_on_gaps(coords: Vec<Vec<f64>>, max_gap_meters: f64) -> Vec<
     segments
 }
 
-use ridelines_drivetrain::common::intervals_client::Activity;
+use ridelines_drivetrain::common::intervals_client::{Activity, ActivityStreams};
 
 pub async fn convert_fit_to_geojson(
     fit_data: &[u8],
     activity: &Activity,
+    privacy: &TrackPrivacy,
 ) -> Result<Option<String>> {
     // Parse FIT data
     let fit_data_records = fitparser::from_bytes(fit_data)?;
@@ -106,15 +112,49 @@ pub async fn convert_fit_to_geojson(
         }
     }
 
+    coordinates_to_geojson(coords, activity, privacy)
+}
+
+/// Build the same GeoJSON as `convert_fit_to_geojson` from intervals.icu
+/// streams, for activities that have no FIT file
+pub async fn convert_streams_to_geojson(
+    streams: &ActivityStreams,
+    activity: &Activity,
+    privacy: &TrackPrivacy,
+) -> Result<Option<String>> {
+    let coords: Vec<Vec<f64>> = streams
+        .latitude
+        .iter()
+        .zip(&streams.longitude)
+        .enumerate()
+        .filter_map(|(i, (lat, lon))| {
+            let (lat, lon) = ((*lat)?, (*lon)?);
+            let mut coord = vec![lon, lat]; // GeoJSON uses [lon, lat] order
+            if let Some(alt) = streams.altitude.get(i).copied().flatten() {
+                coord.push(alt);
+            }
+            Some(coord)
+        })
+        .collect();
+
+    coordinates_to_geojson(coords, activity, privacy)
+}
+
+fn coordinates_to_geojson(
+    coords: Vec<Vec<f64>>,
+    activity: &Activity,
+    privacy: &TrackPrivacy,
+) -> Result<Option<String>> {
     // Return None if no coordinates found
     if coords.len() <= 1 {
         return Ok(None);
     }
 
-    // Split coordinates on gaps larger than 100m
-    let segments = split_coordinates_on_gaps(coords, 100.0);
+    // Split coordinates on gaps larger than MAX_GAP_METERS, then remove the
+    // trimmed ends and privacy zones before anything is built from the track
+    let segments = privacy.clip(&split_coordinates_on_gaps(coords, MAX_GAP_METERS));
 
-    // Return None if no valid segments after splitting
+    // Return None if no valid segments are left
     if segments.is_empty() {
         return Ok(None);
     }
@@ -128,6 +168,32 @@ pub async fn convert_fit_to_geojson(
         Geometry::new(Value::MultiLineString(segments))
     };
 
+    let feature = Feature {
+        bbox: None,
+        geometry: Some(geometry),
+        id: None,
+        properties: Some(line_properties(activity)),
+        foreign_members: None,
+    };
+
+    features.extend(endpoint_features(&feature));
+    features.insert(0, feature);
+
+    // Create FeatureCollection
+    let feature_collection = FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    };
+
+    // Convert to GeoJSON string (compact format for smaller size)
+    let geojson_string = serde_json::to_string(&GeoJson::FeatureCollection(feature_collection))?;
+
+    Ok(Some(geojson_string))
+}
+
+/// Properties of an activity's line feature
+fn line_properties(activity: &Activity) -> serde_json::Map<String, serde_json::Value> {
     let mut properties = serde_json::Map::new();
     properties.insert(
         "name".to_string(),
@@ -149,26 +215,229 @@ pub async fn convert_fit_to_geojson(
         "activity_hash".to_string(),
         serde_json::Value::String(activity.compute_hash()),
     );
+    properties.insert(
+        "properties_hash".to_string(),
+        serde_json::Value::String(activity.compute_properties_hash()),
+    );
+    insert_optional_properties(&mut properties, activity);
+    properties
+}
 
-    let feature = Feature {
-        bbox: None,
-        geometry: Some(geometry),
-        id: None,
-        properties: Some(properties),
-        foreign_members: None,
+/// Replace the properties of a stored activity with the activity's current
+/// ones, leaving its geometry alone
+pub fn refresh_properties(collection: &mut FeatureCollection, activity: &Activity) {
+    let Some((line, markers)) = collection.features.split_first_mut() else {
+        return;
+    };
+    let properties = line_properties(activity);
+    for marker in markers {
+        let marker_properties = marker.properties.get_or_insert_default();
+        for key in ["id", "date", "type"] {
+            if let Some(value) = properties.get(key) {
+                marker_properties.insert(key.to_string(), value.clone());
+            }
+        }
+    }
+    line.properties = Some(properties);
+}
+
+/// Clip a stored activity's track with new privacy settings, placing its
+/// markers again. Returns false when nothing of the track is left.
+pub fn clip_activity(collection: &mut FeatureCollection, privacy: &TrackPrivacy) -> bool {
+    let Some(line) = collection.features.first_mut() else {
+        return false;
+    };
+    let mut segments = match line.geometry.as_ref().map(|g| &g.value) {
+        Some(Value::LineString(points)) => privacy.clip(std::slice::from_ref(points)),
+        Some(Value::MultiLineString(parts)) => privacy.clip(parts),
+        _ => return false,
+    };
+    let geometry = match segments.len() {
+        0 => return false,
+        1 => Value::LineString(segments.remove(0)),
+        _ => Value::MultiLineString(segments),
     };
+    line.geometry = Some(Geometry::new(geometry));
 
-    features.push(feature);
+    let markers = endpoint_features(line);
+    collection.features.truncate(1);
+    collection.features.extend(markers);
+    true
+}
 
-    // Create FeatureCollection
-    let feature_collection = FeatureCollection {
-        bbox: None,
-        features,
-        foreign_members: None,
+/// Start and finish markers for an activity's line feature, taken from the
+/// processed track so they match the line drawn on the map. The track is
+/// clipped by the privacy settings first, so the markers are too.
+pub fn endpoint_features(line_feature: &Feature) -> Vec<Feature> {
+    let segments = match line_feature.geometry.as_ref().map(|g| &g.value) {
+        Some(Value::LineString(line)) => std::slice::from_ref(line),
+        Some(Value::MultiLineString(lines)) => lines.as_slice(),
+        _ => return Vec::new(),
     };
 
-    // Convert to GeoJSON string (compact format for smaller size)
-    let geojson_string = serde_json::to_string(&GeoJson::FeatureCollection(feature_collection))?;
+    let start = segments.first().and_then(|segment| segment.first());
+    let finish = segments.last().and_then(|segment| segment.last());
 
-    Ok(Some(geojson_string))
+    [("start", start), ("finish", finish)]
+        .into_iter()
+        .filter_map(|(marker, position)| {
+            let position = position?;
+            let mut properties = serde_json::Map::new();
+            for key in ["id", "date", "type"] {
+                if let Some(value) = line_feature.property(key) {
+                    properties.insert(key.to_string(), value.clone());
+                }
+            }
+            properties.insert(
+                "marker".to_string(),
+                serde_json::Value::String(marker.to_string()),
+            );
+
+            Some(Feature {
+                bbox: None,
+                geometry: Some(Geometry::new(Value::Point(position[..2].to_vec()))),
+                id: None,
+                properties: Some(properties),
+                foreign_members: None,
+            })
+        })
+        .collect()
+}
+
+/// Add the activity statistics and equipment columns that are present
+fn insert_optional_properties(
+    properties: &mut serde_json::Map<String, serde_json::Value>,
+    activity: &Activity,
+) {
+    properties.insert(
+        "elapsed_time".to_string(),
+        serde_json::Value::from(activity.elapsed_time),
+    );
+
+    let optional_values = [
+        ("distance", activity.distance.map(serde_json::Value::from)),
+        (
+            "moving_time",
+            activity.moving_time.map(serde_json::Value::from),
+        ),
+        (
+            "elevation_gain",
+            activity.total_elevation_gain.map(serde_json::Value::from),
+        ),
+        (
+            "average_speed",
+            activity.average_speed.map(serde_json::Value::from),
+        ),
+        ("max_speed", activity.max_speed.map(serde_json::Value::from)),
+        (
+            "average_heartrate",
+            activity.average_heartrate.map(serde_json::Value::from),
+        ),
+        (
+            "average_watts",
+            activity.average_watts.map(serde_json::Value::from),
+        ),
+        ("calories", activity.calories.map(serde_json::Value::from)),
+        (
+            "device_name",
+            activity.device_name.clone().map(serde_json::Value::from),
+        ),
+        ("gear", activity.gear.clone().map(serde_json::Value::from)),
+        ("trainer", activity.trainer.map(serde_json::Value::from)),
+        ("commute", activity.commute.map(serde_json::Value::from)),
+    ];
+
+    for (key, value) in optional_values {
+        if let Some(value) = value {
+            properties.insert(key.to_string(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track_privacy::PrivacyZone;
+
+    fn activity() -> Activity {
+        serde_json::from_value(serde_json::json!({
+            "id": "i123",
+            "name": "Morning Ride",
+            "start_date_local": "2026-05-01T07:12:00",
+            "type": "Ride",
+            "elapsed_time": 3600,
+        }))
+        .unwrap()
+    }
+
+    /// Eleven points about 56m apart heading north, close enough not to be
+    /// split into separate lines
+    fn track() -> Vec<Vec<f64>> {
+        (0..=10).map(|i| vec![0.0, f64::from(i) * 0.0005]).collect()
+    }
+
+    /// Index along the track of every point of a feature
+    fn lats(feature: &Feature) -> Vec<f64> {
+        let points: Vec<&Vec<f64>> = match feature.geometry.as_ref().map(|g| &g.value) {
+            Some(Value::LineString(points)) => points.iter().collect(),
+            Some(Value::MultiLineString(parts)) => parts.iter().flatten().collect(),
+            Some(Value::Point(point)) => vec![point],
+            _ => panic!("Unexpected geometry"),
+        };
+        points.iter().map(|p| (p[1] * 2000.0).round()).collect()
+    }
+
+    fn convert(privacy: &TrackPrivacy) -> Option<FeatureCollection> {
+        coordinates_to_geojson(track(), &activity(), privacy)
+            .unwrap()
+            .map(|geojson| serde_json::from_str(&geojson).unwrap())
+    }
+
+    #[test]
+    fn test_privacy_clips_track_and_markers() {
+        let open = convert(&TrackPrivacy::default()).unwrap();
+        assert_eq!(lats(&open.features[0]).len(), 11);
+        assert_eq!(lats(&open.features[1]), vec![0.0]);
+        assert_eq!(lats(&open.features[2]), vec![10.0]);
+
+        // A zone around the start and trimmed ends remove points from the
+        // line itself, and the markers sit on what is left
+        let privacy = TrackPrivacy {
+            endpoint_trim_meters: 80.0,
+            zones: vec![PrivacyZone {
+                lat: 0.0,
+                lon: 0.0,
+                radius_meters: 150.0,
+            }],
+        };
+        let clipped = convert(&privacy).unwrap();
+        let [line, start, finish] = clipped.features.as_slice() else {
+            panic!("Expected a line and two markers");
+        };
+        assert_eq!(lats(line), vec![3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+        let line_points = match line.geometry.as_ref().map(|g| &g.value) {
+            Some(Value::LineString(points)) => points.clone(),
+            _ => panic!("Expected a single line"),
+        };
+        assert!(line_points.iter().all(|point| !privacy.in_zone(point)));
+        assert_eq!(lats(start), vec![3.0]);
+        assert_eq!(lats(finish), vec![8.0]);
+
+        // Clipping a stored activity again gives the same result
+        let mut stored = open.clone();
+        assert!(clip_activity(&mut stored, &privacy));
+        assert_eq!(stored.features, clipped.features);
+
+        // Nothing is kept when the whole track is inside a zone
+        let hidden = TrackPrivacy {
+            endpoint_trim_meters: 0.0,
+            zones: vec![PrivacyZone {
+                lat: 0.0025,
+                lon: 0.0,
+                radius_meters: 1000.0,
+            }],
+        };
+        assert!(convert(&hidden).is_none());
+        assert!(!clip_activity(&mut open.clone(), &hidden));
+    }
 }
@@ -0,0 +1,359 @@
+use crate::archive_store::{self, ArchiveStore};
+use crate::pmtiles_gc::PmtilesGc;
+use crate::tile_profile::TileProfile;
+use crate::tiles::{self, VectorTileSettings};
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_s3::Client as S3Client;
+use chrono::Utc;
+use function_timer::time;
+use geojson::FeatureCollection;
+use ridelines_drivetrain::common::metrics;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::{error, info, warn};
+
+mod privacy;
+
+use privacy::{OpaqueIds, PrivacySettings, anonymise_activity};
+
+const GROUP_PMTILES_KEY: &str = "pmtilesKey";
+
+/// Builds one map from the archives of every member of a group, such as a
+/// club, and publishes it at `groups/{group_id}/{hash}.pmtiles`, pointed at
+/// by `pmtilesKey` in the groups table. Only members who consented are
+/// included, with their privacy settings applied and their activities
+/// tagged with an ID that is opaque outside the group.
+pub struct GroupMapBuilder {
+    s3_client: S3Client,
+    store: ArchiveStore,
+    dynamodb_client: DynamoDbClient,
+    groups_table_name: String,
+    users_table_name: String,
+    /// Bucket holding the members' GeoJSON archives
+    s3_bucket: String,
+    activities_bucket: String,
+    profile: TileProfile,
+    work_dir: PathBuf,
+    ids: OpaqueIds,
+}
+
+impl GroupMapBuilder {
+    pub fn new(
+        s3_client: S3Client,
+        dynamodb_client: DynamoDbClient,
+        profile: TileProfile,
+        work_dir: &Path,
+    ) -> Result<Self> {
+        let s3_bucket = env::var("S3_BUCKET").context("S3_BUCKET environment variable not set")?;
+        let activities_bucket = env::var("ACTIVITIES_S3_BUCKET")
+            .context("ACTIVITIES_S3_BUCKET environment variable not set")?;
+        let users_table_name = env::var("USERS_TABLE_NAME")
+            .context("USERS_TABLE_NAME environment variable not set")?;
+        let groups_table_name = env::var("GROUPS_TABLE_NAME")
+            .context("GROUPS_TABLE_NAME environment variable not set")?;
+        let id_secret = env::var("GROUP_MAP_ID_SECRET")
+            .context("GROUP_MAP_ID_SECRET environment variable not set")?;
+        anyhow::ensure!(!id_secret.is_empty(), "GROUP_MAP_ID_SECRET is empty");
+
+        Ok(Self {
+            store: ArchiveStore::new(s3_client.clone(), &activities_bucket),
+            s3_client,
+            dynamodb_client,
+            groups_table_name,
+            users_table_name,
+            s3_bucket,
+            activities_bucket,
+            profile,
+            work_dir: work_dir.to_path_buf(),
+            ids: OpaqueIds::new(id_secret.as_bytes()),
+        })
+    }
+
+    #[time("group_map_duration")]
+    pub async fn build(&self, group_id: &str) -> Result<()> {
+        let member_ids = self.member_ids(group_id).await?;
+        let geojson_path = self.work_dir.join(format!("group_{group_id}.geojson"));
+        std::fs::File::create(&geojson_path)
+            .with_context(|| format!("Failed to create {}", geojson_path.display()))?;
+
+        let (mut included, mut excluded, mut activity_count) = (0u64, 0u64, 0usize);
+        for user_id in &member_ids {
+            let privacy = match self.privacy_settings(user_id).await {
+                Ok(privacy) if privacy.consent => privacy,
+                Ok(_) => {
+                    excluded += 1;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Leaving member {user_id} off group {group_id}: {e:#}");
+                    excluded += 1;
+                    continue;
+                }
+            };
+            let Some(compressed) = self.download_archive(user_id).await? else {
+                info!("Member {user_id} of group {group_id} has no activities yet");
+                excluded += 1;
+                continue;
+            };
+
+            // Decompressing and clipping is CPU-bound, so it runs off the async runtime
+            let member = self.ids.member_id(group_id, user_id);
+            let ids = self.ids.clone();
+            let path = geojson_path.clone();
+            activity_count += tokio::task::spawn_blocking(move || {
+                append_member_activities(&compressed, &privacy, &ids, &member, &path)
+            })
+            .await
+            .context("Group map task panicked")??;
+            included += 1;
+        }
+
+        metrics::record_group_map_members(included, excluded);
+        info!(
+            "Group {group_id}: {activity_count} activities from {included} of {} members",
+            member_ids.len()
+        );
+
+        let result = if activity_count == 0 {
+            // Members may have withdrawn consent, so an empty group takes
+            // its previous map down rather than leaving it published
+            self.unpublish(group_id).await
+        } else {
+            self.build_and_publish(group_id, &geojson_path, included)
+                .await
+        };
+        let _ = fs::remove_file(&geojson_path).await;
+        result
+    }
+
+    async fn build_and_publish(
+        &self,
+        group_id: &str,
+        geojson_path: &Path,
+        member_count: u64,
+    ) -> Result<()> {
+        let output = self.work_dir.join(format!("group_{group_id}.pmtiles"));
+        let settings = self.vector_tile_settings();
+        let input = geojson_path.to_path_buf();
+        let output_path = output.clone();
+
+        // Tiling is CPU bound, so keep it off the async worker threads
+        let summary = tokio::task::spawn_blocking(move || {
+            tiles::build_vector_pmtiles(&input, &output_path, &settings)?;
+            tiles::validate_pmtiles(&output_path, &input, &settings)
+        })
+        .await
+        .context("Group tiler task panicked")??;
+        info!(
+            "Built group map for {group_id}: {} tiles, zooms {}-{}, layers {:?}",
+            summary.tile_count, summary.min_zoom, summary.max_zoom, summary.layers
+        );
+
+        let result = self
+            .publish(group_id, &output.to_string_lossy(), member_count)
+            .await;
+        let _ = fs::remove_file(&output).await;
+        let key = result?;
+
+        self.collect_garbage(group_id, &[&key]).await;
+        Ok(())
+    }
+
+    /// The profile's settings, keeping the member ID even when the profile
+    /// lists the attributes to keep
+    fn vector_tile_settings(&self) -> VectorTileSettings {
+        let mut settings = self.profile.vector_tile_settings();
+        if !settings.include_attributes.is_empty() {
+            settings.include_attributes.push("member".to_string());
+        }
+        settings.description = format!("group map; {}", settings.description);
+        settings
+    }
+
+    /// Upload the archive unless it already exists and point the group at
+    /// it, returning its key
+    async fn publish(
+        &self,
+        group_id: &str,
+        pmtiles_file: &str,
+        member_count: u64,
+    ) -> Result<String> {
+        let (content_hash, file_len) = archive_store::hash_file(pmtiles_file).await?;
+        let key = format!("{}{}.pmtiles", group_prefix(group_id), &content_hash[..16]);
+
+        if self.store.object_exists(&key).await? {
+            info!("Group map already in S3, reusing {key}");
+            // It may have been tagged for expiration when it was last replaced
+            self.store.clear_expiration_tag(&key).await?;
+        } else {
+            self.store
+                .put_archive(&key, pmtiles_file, &content_hash, file_len)
+                .await?;
+        }
+
+        self.dynamodb_client
+            .update_item()
+            .table_name(&self.groups_table_name)
+            .key("id", AttributeValue::S(group_id.to_string()))
+            .update_expression("SET #key = :key, memberCount = :members, updatedAt = :now")
+            .expression_attribute_names("#key", GROUP_PMTILES_KEY)
+            .expression_attribute_values(":key", AttributeValue::S(key.clone()))
+            .expression_attribute_values(":members", AttributeValue::N(member_count.to_string()))
+            .expression_attribute_values(":now", AttributeValue::S(Utc::now().to_rfc3339()))
+            .send()
+            .await
+            .context("Failed to update group map key in DynamoDB")?;
+
+        info!("Updated {GROUP_PMTILES_KEY} for group {group_id} to {key}");
+        Ok(key)
+    }
+
+    /// Remove the group's map pointer and reclaim its archives
+    async fn unpublish(&self, group_id: &str) -> Result<()> {
+        self.dynamodb_client
+            .update_item()
+            .table_name(&self.groups_table_name)
+            .key("id", AttributeValue::S(group_id.to_string()))
+            .update_expression("SET memberCount = :members, updatedAt = :now REMOVE #key")
+            .expression_attribute_names("#key", GROUP_PMTILES_KEY)
+            .expression_attribute_values(":members", AttributeValue::N("0".to_string()))
+            .expression_attribute_values(":now", AttributeValue::S(Utc::now().to_rfc3339()))
+            .send()
+            .await
+            .context("Failed to remove group map key in DynamoDB")?;
+
+        info!("No activities to show for group {group_id}, removed its map");
+        self.collect_garbage(group_id, &[]).await;
+        Ok(())
+    }
+
+    /// User IDs in the group's `memberIds`, as a string set or list
+    async fn member_ids(&self, group_id: &str) -> Result<Vec<String>> {
+        let item = self
+            .dynamodb_client
+            .get_item()
+            .table_name(&self.groups_table_name)
+            .key("id", AttributeValue::S(group_id.to_string()))
+            .projection_expression("memberIds")
+            .send()
+            .await
+            .context("Failed to read group record from DynamoDB")?
+            .item
+            .with_context(|| format!("Group {group_id} not found"))?;
+
+        let mut member_ids: Vec<String> = match item.get("memberIds") {
+            Some(AttributeValue::Ss(ids)) => ids.clone(),
+            Some(AttributeValue::L(ids)) => ids
+                .iter()
+                .filter_map(|id| id.as_s().ok().cloned())
+                .collect(),
+            _ => Vec::new(),
+        };
+        // Archive order decides the order of the combined GeoJSON
+        member_ids.sort_unstable();
+        member_ids.dedup();
+        Ok(member_ids)
+    }
+
+    async fn privacy_settings(&self, user_id: &str) -> Result<PrivacySettings> {
+        let item = self
+            .dynamodb_client
+            .get_item()
+            .table_name(&self.users_table_name)
+            .key("id", AttributeValue::S(user_id.to_string()))
+            .projection_expression("groupMapConsent, groupMapPrivacy")
+            .send()
+            .await
+            .context("Failed to read user record from DynamoDB")?
+            .item
+            .unwrap_or_default();
+        PrivacySettings::from_item(&item)
+    }
+
+    /// The member's compressed GeoJSON archive, or None before their first sync
+    async fn download_archive(&self, user_id: &str) -> Result<Option<Vec<u8>>> {
+        let key = format!("athletes/{user_id}/activities.geojson.zst");
+        let response = match self
+            .s3_client
+            .get_object()
+            .bucket(&self.s3_bucket)
+            .key(&key)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.is_no_such_key() {
+                    return Ok(None);
+                }
+                return Err(anyhow::anyhow!("Failed to download {key}: {e}"));
+            }
+        };
+        Ok(Some(response.body.collect().await?.to_vec()))
+    }
+
+    async fn collect_garbage(&self, group_id: &str, current_keys: &[&str]) {
+        let result = match PmtilesGc::new(
+            self.s3_client.clone(),
+            &self.activities_bucket,
+            &group_prefix(group_id),
+        ) {
+            Ok(gc) => gc.reconcile(current_keys).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            error!("Group map garbage collection failed for {group_id}: {e:#}");
+            metrics::increment_pmtiles_gc_failures(1);
+        }
+    }
+}
+
+fn group_prefix(group_id: &str) -> String {
+    format!("groups/{group_id}/")
+}
+
+/// Append the anonymised activities of one member's archive to the combined
+/// GeoJSON file, returning how many were added
+fn append_member_activities(
+    compressed: &[u8],
+    privacy: &PrivacySettings,
+    ids: &OpaqueIds,
+    member: &str,
+    output: &Path,
+) -> Result<usize> {
+    let decoder = zstd::Decoder::new(compressed)?;
+    let file = OpenOptions::new()
+        .append(true)
+        .open(output)
+        .with_context(|| format!("Failed to open {}", output.display()))?;
+    let mut writer = BufWriter::new(file);
+
+    let mut count = 0;
+    for line in BufReader::new(decoder).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let collection: FeatureCollection = match serde_json::from_str(&line) {
+            Ok(collection) => collection,
+            Err(e) => {
+                warn!("Skipping unparseable activity for group map: {}", e);
+                continue;
+            }
+        };
+        if let Some(anonymised) = anonymise_activity(&collection, privacy, ids, member) {
+            serde_json::to_writer(&mut writer, &anonymised)?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+    }
+    writer.flush()?;
+    Ok(count)
+}
@@ -0,0 +1,234 @@
+use crate::track_privacy::TrackPrivacy;
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::types::AttributeValue;
+use geojson::{Feature, FeatureCollection, Geometry, JsonObject, Value};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+
+/// How a member's activities may appear on group maps, from the
+/// `groupMapConsent` and `groupMapPrivacy` attributes of their user record
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrivacySettings {
+    /// Members who have not agreed to appear on group maps are left out
+    pub consent: bool,
+    /// intervals.icu activity types left off group maps, e.g. "Walk"
+    pub hidden_types: HashSet<String>,
+    /// Track ends and privacy zones removed from every activity
+    pub track: TrackPrivacy,
+}
+
+impl PrivacySettings {
+    /// Read the settings from a user record. Settings that cannot be read
+    /// are an error rather than ignored, so the member is left out instead
+    /// of shown with less privacy than they asked for.
+    pub fn from_item(item: &HashMap<String, AttributeValue>) -> Result<Self> {
+        let consent = matches!(
+            item.get("groupMapConsent"),
+            Some(AttributeValue::Bool(true))
+        );
+        let mut settings = Self {
+            consent,
+            ..Default::default()
+        };
+
+        let privacy = match item.get("groupMapPrivacy") {
+            Some(AttributeValue::M(privacy)) => privacy,
+            Some(_) => anyhow::bail!("groupMapPrivacy is not a map"),
+            None => return Ok(settings),
+        };
+
+        if let Some(types) = privacy.get("hiddenActivityTypes") {
+            settings.hidden_types = string_set(types).context("Invalid hiddenActivityTypes")?;
+        }
+        settings.track = TrackPrivacy::from_map(privacy)?;
+
+        Ok(settings)
+    }
+}
+
+fn string_set(value: &AttributeValue) -> Result<HashSet<String>> {
+    match value {
+        AttributeValue::Ss(values) => Ok(values.iter().cloned().collect()),
+        AttributeValue::L(values) => values
+            .iter()
+            .map(|value| match value {
+                AttributeValue::S(value) => Ok(value.clone()),
+                _ => Err(anyhow::anyhow!("Expected a string")),
+            })
+            .collect(),
+        _ => Err(anyhow::anyhow!("Expected a list of strings")),
+    }
+}
+
+/// Derives the opaque IDs published on group maps. They are keyed with a
+/// secret, so they cannot be recomputed from known user, group and activity
+/// IDs.
+#[derive(Clone)]
+pub struct OpaqueIds {
+    secret: Vec<u8>,
+}
+
+impl OpaqueIds {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            secret: secret.to_vec(),
+        }
+    }
+
+    /// ID a member is shown under on one group's map. It is the same on
+    /// every build, but differs between groups so a member cannot be
+    /// followed from one group map to another.
+    pub fn member_id(&self, group_id: &str, user_id: &str) -> String {
+        self.hmac_hex(&[group_id, user_id])[..12].to_string()
+    }
+
+    /// ID an activity is shown under, unique to the member's group map
+    pub fn activity_id(&self, member_id: &str, activity_id: &str) -> String {
+        self.hmac_hex(&[member_id, activity_id])[..16].to_string()
+    }
+
+    fn hmac_hex(&self, parts: &[&str]) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        for part in parts {
+            mac.update(part.as_bytes());
+            mac.update(&[0]);
+        }
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+}
+
+/// The activity as it may appear on a group map: its track, clipped by the
+/// member's privacy settings, with an opaque ID, its type, the day it
+/// happened and the member ID. Start and finish markers are always left
+/// out. None when nothing of the activity may be shown.
+pub fn anonymise_activity(
+    collection: &FeatureCollection,
+    privacy: &PrivacySettings,
+    ids: &OpaqueIds,
+    member_id: &str,
+) -> Option<FeatureCollection> {
+    let line = collection.features.first()?;
+    let activity_type = line.property("type").and_then(|t| t.as_str());
+    if activity_type.is_some_and(|t| privacy.hidden_types.contains(t)) {
+        return None;
+    }
+
+    let parts = match line.geometry.as_ref().map(|g| &g.value) {
+        Some(Value::LineString(points)) => vec![points.clone()],
+        Some(Value::MultiLineString(parts)) => parts.clone(),
+        _ => return None,
+    };
+    let mut parts = privacy.track.clip(&parts);
+    let geometry = match parts.len() {
+        0 => return None,
+        1 => Value::LineString(parts.remove(0)),
+        _ => Value::MultiLineString(parts),
+    };
+
+    let activity_id = line.property("id").and_then(|id| id.as_str())?;
+    let mut properties = JsonObject::new();
+    properties.insert(
+        "id".to_string(),
+        ids.activity_id(member_id, activity_id).into(),
+    );
+    if let Some(activity_type) = activity_type {
+        properties.insert("type".to_string(), activity_type.into());
+    }
+    // The day is enough to filter by and does not give away when a member
+    // leaves home
+    if let Some(date) = line.property("date").and_then(|d| d.as_str()) {
+        properties.insert(
+            "date".to_string(),
+            date.chars().take(10).collect::<String>().into(),
+        );
+    }
+    properties.insert("member".to_string(), member_id.into());
+
+    Some(FeatureCollection {
+        bbox: None,
+        features: vec![Feature {
+            geometry: Some(Geometry::new(geometry)),
+            properties: Some(properties),
+            ..Default::default()
+        }],
+        foreign_members: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track_privacy::PrivacyZone;
+
+    fn activity(activity_type: &str) -> FeatureCollection {
+        // Eleven points about 111m apart heading north, plus markers
+        let track: Vec<[f64; 2]> = (0..=10).map(|i| [0.0, f64::from(i) * 0.001]).collect();
+        serde_json::from_value(serde_json::json!({
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "geometry": {"type": "LineString", "coordinates": track},
+                    "properties": {
+                        "id": "i123",
+                        "type": activity_type,
+                        "date": "2026-05-01T07:12:00",
+                        "activity_hash": "abc",
+                    },
+                },
+                {
+                    "type": "Feature",
+                    "geometry": {"type": "Point", "coordinates": [0.0, 0.0]},
+                    "properties": {"id": "i123", "marker": "start"},
+                },
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_anonymise_activity() {
+        let ids = OpaqueIds::new(b"secret");
+        let member = ids.member_id("club", "user_1");
+        assert_ne!(member, ids.member_id("other-club", "user_1"));
+        // Without the secret the ID cannot be recomputed
+        assert_ne!(member, OpaqueIds::new(b"guess").member_id("club", "user_1"));
+
+        let privacy = PrivacySettings {
+            consent: true,
+            hidden_types: HashSet::from(["Walk".to_string()]),
+            track: TrackPrivacy {
+                endpoint_trim_meters: 150.0,
+                // Covers the point at 0.005 only
+                zones: vec![PrivacyZone {
+                    lat: 0.005,
+                    lon: 0.0,
+                    radius_meters: 50.0,
+                }],
+            },
+        };
+        assert!(anonymise_activity(&activity("Walk"), &privacy, &ids, &member).is_none());
+
+        let anonymised = anonymise_activity(&activity("Ride"), &privacy, &ids, &member).unwrap();
+        let [line] = anonymised.features.as_slice() else {
+            panic!("Markers should be dropped");
+        };
+        // Two points trimmed from each end and the zone splits the rest
+        let Some(Value::MultiLineString(parts)) = line.geometry.as_ref().map(|g| &g.value) else {
+            panic!("Expected the track to be split");
+        };
+        let lats: Vec<Vec<f64>> = parts
+            .iter()
+            .map(|part| part.iter().map(|p| (p[1] * 1000.0).round()).collect())
+            .collect();
+        assert_eq!(lats, vec![vec![2.0, 3.0, 4.0], vec![6.0, 7.0, 8.0]]);
+
+        let properties = line.properties.as_ref().unwrap();
+        assert_eq!(properties["member"], member.as_str());
+        assert_eq!(properties["date"], "2026-05-01");
+        assert_ne!(properties["id"], "i123");
+        assert!(!properties.contains_key("activity_hash"));
+    }
+}
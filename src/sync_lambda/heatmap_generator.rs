@@ -0,0 +1,175 @@
+use crate::archive_store::{self, ArchiveStore};
+use crate::map_keys::{self, MapKeys};
+use crate::pmtiles_gc::PmtilesGc;
+use crate::tiles::{self, HeatmapSettings, HeatmapSummary, RasterFormat};
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_s3::Client as S3Client;
+use function_timer::time;
+use ridelines_drivetrain::common::metrics;
+use ridelines_drivetrain::common::types::MapTarget;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tracing::{error, info};
+
+/// A heatmap is only started with this much of the Lambda invocation left,
+/// so it never delays recording the sync status
+const MIN_REMAINING_TIME: Duration = Duration::from_secs(120);
+
+const DEFAULT_MAX_ZOOM: u8 = 12;
+
+/// Builds a raster heatmap of every archived activity and publishes it
+/// under `heatmapKey`, next to the vector tiles
+pub struct HeatmapGenerator {
+    s3_client: S3Client,
+    store: ArchiveStore,
+    map_keys: MapKeys,
+    target: MapTarget,
+    activities_bucket: String,
+    settings: HeatmapSettings,
+    /// When the Lambda invocation times out
+    deadline: SystemTime,
+}
+
+impl HeatmapGenerator {
+    /// None unless HEATMAP_ENABLED is "true"
+    pub fn from_env(
+        s3_client: S3Client,
+        dynamodb_client: DynamoDbClient,
+        target: MapTarget,
+        deadline: SystemTime,
+    ) -> Result<Option<Self>> {
+        if env::var("HEATMAP_ENABLED").as_deref() != Ok("true") {
+            return Ok(None);
+        }
+
+        let activities_bucket = env::var("ACTIVITIES_S3_BUCKET")
+            .context("ACTIVITIES_S3_BUCKET environment variable not set")?;
+        let users_table_name = env::var("USERS_TABLE_NAME")
+            .context("USERS_TABLE_NAME environment variable not set")?;
+        let format = match env::var("HEATMAP_FORMAT").as_deref() {
+            Err(_) | Ok("png") => RasterFormat::Png,
+            Ok("webp") => RasterFormat::Webp,
+            Ok(other) => return Err(anyhow::anyhow!("Unknown HEATMAP_FORMAT: {other}")),
+        };
+        let max_zoom = match env::var("HEATMAP_MAX_ZOOM") {
+            Ok(zoom) => zoom
+                .parse::<u8>()
+                .context("HEATMAP_MAX_ZOOM must be a zoom level")?,
+            Err(_) => DEFAULT_MAX_ZOOM,
+        };
+
+        let settings = HeatmapSettings {
+            max_zoom,
+            format,
+            description: format!("heatmap {} z0-{max_zoom}", format.as_str()),
+            ..Default::default()
+        };
+
+        Ok(Some(Self {
+            store: ArchiveStore::new(s3_client.clone(), &activities_bucket),
+            s3_client,
+            map_keys: MapKeys::new(dynamodb_client, &users_table_name, target.clone()),
+            target,
+            activities_bucket,
+            settings,
+            deadline,
+        }))
+    }
+
+    #[time("generate_heatmap_duration")]
+    pub async fn generate_heatmap(&self, geojson_path: &Path) -> Result<()> {
+        let remaining = self
+            .deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        if remaining < MIN_REMAINING_TIME {
+            anyhow::bail!(
+                "Only {}s left before the Lambda deadline, not starting the heatmap",
+                remaining.as_secs()
+            );
+        }
+
+        let output = PathBuf::from(format!("/tmp/{}.heatmap.pmtiles", self.target.file_stem()));
+        let summary = self.render(geojson_path, &output).await?;
+        info!(
+            "Rendered heatmap for {}: {} tiles from {} activities, max density by zoom {:?}",
+            self.target, summary.tile_count, summary.track_count, summary.max_density
+        );
+
+        let result = self.publish(&output.to_string_lossy()).await;
+        let _ = fs::remove_file(&output).await;
+        let key = result?;
+
+        self.collect_garbage(&key).await;
+        Ok(())
+    }
+
+    /// Rasterizing is CPU-bound, so it runs off the async runtime
+    async fn render(&self, geojson_path: &Path, output: &Path) -> Result<HeatmapSummary> {
+        let geojson_path = geojson_path.to_path_buf();
+        let output = output.to_path_buf();
+        let settings = self.settings.clone();
+        tokio::task::spawn_blocking(move || {
+            tiles::build_heatmap_pmtiles(&geojson_path, &output, &settings)
+        })
+        .await
+        .context("Heatmap task panicked")?
+    }
+
+    /// Upload the archive unless it already exists and point the users
+    /// table at it, returning its key
+    async fn publish(&self, pmtiles_file: &str) -> Result<String> {
+        let (content_hash, file_len) = archive_store::hash_file(pmtiles_file).await?;
+        let key = format!("{}{}.pmtiles", self.prefix(), &content_hash[..16]);
+
+        let current = self.map_keys.get(&[map_keys::HEATMAP_KEY]).await?;
+        let pointer_unchanged = current.get(map_keys::HEATMAP_KEY) == Some(&key);
+
+        if self.store.object_exists(&key).await? {
+            if pointer_unchanged {
+                info!(
+                    "Heatmap unchanged for {}, skipping upload: {key}",
+                    self.target
+                );
+                return Ok(key);
+            }
+            info!("Heatmap already in S3, reusing {key}");
+            self.store.clear_expiration_tag(&key).await?;
+        } else {
+            self.store
+                .put_archive(&key, pmtiles_file, &content_hash, file_len)
+                .await?;
+        }
+
+        self.map_keys.set(&[(map_keys::HEATMAP_KEY, &key)]).await?;
+        Ok(key)
+    }
+
+    /// Heatmaps sit in their own folder so they are reconciled separately
+    /// from the vector archives
+    fn prefix(&self) -> String {
+        format!("activities/{}/heatmap/", self.target.storage_id())
+    }
+
+    async fn collect_garbage(&self, current_key: &str) {
+        let result = match PmtilesGc::new(
+            self.s3_client.clone(),
+            &self.activities_bucket,
+            &self.prefix(),
+        ) {
+            Ok(gc) => gc.reconcile(&[current_key]).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            error!(
+                "Heatmap garbage collection failed for {}: {e:#}",
+                self.target
+            );
+            metrics::increment_pmtiles_gc_failures(1);
+        }
+    }
+}
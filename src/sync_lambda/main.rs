@@ -12,7 +12,12 @@ use tempdir::TempDir;
 
 use clerk_rs::apis::users_api::User as ClerkUser;
 use clerk_rs::{ClerkConfiguration, clerk::Clerk};
-use ridelines_drivetrain::common::{intervals_client::IntervalsClient, metrics};
+use ridelines_drivetrain::common::{
+    intervals_client::{IntervalsClient, IntervalsError},
+    metrics,
+    rate_limiter::RateLimitConfig,
+    types::MapTarget,
+};
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -20,16 +25,62 @@ pub struct SyncRequest {
     pub user_id: String,
     pub sync_id: String,
     pub timestamp: String,
+    /// intervals.icu athlete to map with the user's token, for coaches and
+    /// club admins. The user's own activities are mapped when absent.
+    #[serde(default)]
+    pub athlete_id: Option<String>,
+    /// Named tile profile, e.g. "lite" for free users. Defaults to TILE_PROFILE.
+    #[serde(default)]
+    pub tile_profile: Option<String>,
+}
+
+/// Rebuild the combined map of a group's consenting members
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupSyncRequest {
+    pub group_id: String,
+    pub sync_id: String,
+    pub timestamp: String,
+    /// Named tile profile for the group map. Defaults to TILE_PROFILE.
+    #[serde(default)]
+    pub tile_profile: Option<String>,
+}
+
+/// The `type` of an SQS message. Messages without one are user syncs,
+/// which predate the other types.
+#[derive(Debug, Deserialize)]
+struct MessageType {
+    #[serde(rename = "type", default)]
+    kind: Option<String>,
 }
 
 mod activity_sync;
+mod archive_store;
+mod cdn;
+mod explorer;
 mod fit_converter;
+mod group_map;
+mod heatmap_generator;
+mod map_keys;
+mod pmtiles_gc;
 mod sync_status;
+mod thumbnail_generator;
 mod tile_generator;
+mod tile_manifest;
+mod tile_profile;
+mod tiles;
+mod track_privacy;
 
 use crate::activity_sync::ActivitySync;
+use crate::explorer::ExplorerExporter;
+use crate::group_map::GroupMapBuilder;
+use crate::heatmap_generator::HeatmapGenerator;
+use crate::thumbnail_generator::ThumbnailGenerator;
 use crate::tile_generator::TileGenerator;
+use crate::tile_profile::TileProfile;
+use crate::track_privacy::TrackPrivacy;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -54,7 +105,7 @@ async fn main() -> Result<(), Error> {
 #[time("lambda_handler_duration")]
 pub(crate) async fn function_handler(event: LambdaEvent<SqsEvent>) -> Result<(), Error> {
     // Extract some useful information from the request
-    let (sqs_event, _context) = event.into_parts();
+    let (sqs_event, context) = event.into_parts();
     tracing::info!(
         "Received SQS event with {} records",
         sqs_event.records.len()
@@ -67,23 +118,62 @@ pub(crate) async fn function_handler(event: LambdaEvent<SqsEvent>) -> Result<(),
             .body
             .as_ref()
             .ok_or_else(|| Error::from("SQS message body is empty"))?;
+        let message_type: MessageType = serde_json::from_str(body)
+            .map_err(|e| Error::from(format!("Failed to parse SQS message body: {e}")))?;
+        match message_type.kind.as_deref() {
+            None | Some("userSync") => {}
+            Some("groupSync") => {
+                let group_request: GroupSyncRequest = serde_json::from_str(body)
+                    .map_err(|e| Error::from(format!("Failed to parse group sync request: {e}")))?;
+                process_group_sync(group_request).await?;
+                continue;
+            }
+            Some(other) => return Err(Error::from(format!("Unknown message type: {other}"))),
+        }
+
         let sync_request: SyncRequest = serde_json::from_str(body)
             .map_err(|e| Error::from(format!("Failed to parse SQS message body: {e}")))?;
 
+        let target = match sync_request.athlete_id.as_deref() {
+            Some(athlete_id) => {
+                // The athlete ID ends up in S3 keys, so only accept intervals.icu style IDs
+                if athlete_id.is_empty() || !athlete_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    return Err(Error::from(format!("Invalid athlete ID: {athlete_id}")));
+                }
+                MapTarget::coached(&sync_request.user_id, athlete_id)
+            }
+            None => MapTarget::own(&sync_request.user_id),
+        };
+
         tracing::info!(
-            "Processing sync request for user: {} sync: {}",
-            sync_request.user_id,
+            "Processing sync request for {} sync: {}",
+            target,
             sync_request.sync_id
         );
 
+        let tile_profile = TileProfile::resolve(sync_request.tile_profile.as_deref())
+            .map_err(|e| Error::from(format!("Invalid tile profile: {e}")))?;
+
         // Process the sync for this user
-        process_user_sync(&sync_request.user_id, &sync_request.sync_id).await?;
+        process_user_sync(
+            target,
+            &sync_request.sync_id,
+            tile_profile,
+            context.deadline(),
+        )
+        .await?;
     }
 
     Ok(())
 }
 
-async fn process_user_sync(user_id: &str, sync_id: &str) -> Result<(), Error> {
+async fn process_user_sync(
+    target: MapTarget,
+    sync_id: &str,
+    tile_profile: TileProfile,
+    deadline: SystemTime,
+) -> Result<(), Error> {
+    let user_id = target.user_id.as_str();
     let s3_bucket =
         env::var("S3_BUCKET").map_err(|_| Error::from("S3_BUCKET environment variable not set"))?;
 
@@ -98,31 +188,57 @@ async fn process_user_sync(user_id: &str, sync_id: &str) -> Result<(), Error> {
         user_id.to_string(),
         sync_id.to_string(),
     ));
+
+    // SQS delivers at least once, so a message for a finished sync is a duplicate
+    match sync_status.fetch().await {
+        Ok(Some(record)) if record.status.is_completed() => {
+            tracing::info!("Sync {sync_id} already completed, ignoring redelivered message");
+            return Ok(());
+        }
+        Ok(_) => {}
+        Err(e) => tracing::warn!("Could not read sync status, syncing anyway: {e:#}"),
+    }
     sync_status.initialize().await?;
 
     // Create shared work directory for all temporary files
-    let work_dir = TempDir::new(&format!("intervals_mapper_{}", user_id))
+    let work_dir = TempDir::new(&format!("intervals_mapper_{}", target.file_stem()))
         .map_err(|e| Error::from(format!("Failed to create work directory: {e}")))?;
 
-    // Get intervals.icu access token from Clerk
+    // Get intervals.icu access token from Clerk. For coached athletes this is
+    // still the requesting user's token.
     let access_token = get_intervals_access_token_from_clerk(user_id).await?;
 
     // Create IntervalsClient with access token
-    let mut intervals_client = IntervalsClient::new();
+    let mut intervals_client = IntervalsClient::with_rate_limit(RateLimitConfig::from_env());
     intervals_client.set_access_token(&access_token);
 
+    // Markers are only placed where the user's privacy settings allow
+    let privacy = match TrackPrivacy::fetch(&dynamodb_client, &target).await {
+        Ok(privacy) => privacy,
+        Err(e) => {
+            let _ = sync_status
+                .mark_failed(&format!("Failed to read privacy settings: {e}"))
+                .await;
+            metrics::increment_lambda_failure();
+            return Err(Error::from(format!(
+                "Failed to read privacy settings: {e:#}"
+            )));
+        }
+    };
+
     // Sync activities and get path to concatenated GeoJSON file
     let sync_job = ActivitySync::new(
         intervals_client,
-        user_id,
+        target.clone(),
+        privacy,
         s3_client.clone(),
         &s3_bucket,
         work_dir.path(),
         sync_status.clone(),
     );
 
-    let geojson_file_path = match sync_job.sync_activities().await {
-        Ok(Some(path)) => path,
+    let synced = match sync_job.sync_activities().await {
+        Ok(Some(synced)) => synced,
         Ok(None) => {
             // No changes detected, skip tile generation
             tracing::info!("No activity changes detected, Lambda execution completed successfully");
@@ -135,6 +251,23 @@ async fn process_user_sync(user_id: &str, sync_id: &str) -> Result<(), Error> {
                 .mark_failed(&format!("Activity sync failed: {e}"))
                 .await;
             metrics::increment_lambda_failure();
+
+            // Redelivering the message cannot fix an auth, not-found or decode
+            // failure, so only surface transient intervals.icu errors to SQS
+            if let Some(intervals_error) = e
+                .chain()
+                .find_map(|cause| cause.downcast_ref::<IntervalsError>())
+                && !intervals_error.is_transient()
+            {
+                tracing::warn!(
+                    "Not retrying sync {} for user {}: {}",
+                    sync_id,
+                    user_id,
+                    intervals_error
+                );
+                return Ok(());
+            }
+
             return Err(Error::from(format!("Sync failed: {e}")));
         }
     };
@@ -143,15 +276,81 @@ async fn process_user_sync(user_id: &str, sync_id: &str) -> Result<(), Error> {
     sync_status.start_generating();
 
     // Generate PMTiles from the concatenated GeoJSON file
-    let tile_generator = TileGenerator::new(s3_client, dynamodb_client, user_id.to_string())
-        .map_err(|e| Error::from(format!("Failed to create TileGenerator: {e}")))?;
+    let invalidator = cdn::invalidator_from_env(&config);
+    let tile_generator = TileGenerator::new(
+        s3_client.clone(),
+        dynamodb_client.clone(),
+        target.clone(),
+        tile_profile,
+        deadline,
+        invalidator.clone(),
+    )
+    .map_err(|e| Error::from(format!("Failed to create TileGenerator: {e}")))?;
+
+    let tile_result = tile_generator.generate_pmtiles(&synced).await;
+
+    // Explorer tiles, thumbnails and the heatmap are optional, so failing to publish
+    // them never fails the sync
+    if tile_result.is_ok() {
+        if let Some(explorer) = &synced.explorer {
+            let result = match ExplorerExporter::new(
+                s3_client.clone(),
+                dynamodb_client.clone(),
+                target.clone(),
+                invalidator.clone(),
+            ) {
+                Ok(exporter) => exporter.publish(explorer).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => metrics::increment_explorer_export_success(),
+                Err(e) => {
+                    tracing::error!("Failed to publish explorer tiles: {e:#}");
+                    metrics::increment_explorer_export_failure();
+                }
+            }
+        }
 
-    let tile_result = tile_generator
-        .generate_pmtiles_from_file(&geojson_file_path.to_string_lossy())
-        .await;
+        match ThumbnailGenerator::from_env(
+            s3_client.clone(),
+            dynamodb_client.clone(),
+            target.clone(),
+            deadline,
+            invalidator.clone(),
+        ) {
+            Ok(Some(thumbnails)) => match thumbnails.generate_thumbnails(&synced).await {
+                Ok(()) => metrics::increment_thumbnails_success(),
+                Err(e) => {
+                    tracing::error!("Failed to generate thumbnails: {e:#}");
+                    metrics::increment_thumbnails_failure();
+                }
+            },
+            Ok(None) => {}
+            Err(e) => {
+                tracing::error!("Failed to create ThumbnailGenerator: {e:#}");
+                metrics::increment_thumbnails_failure();
+            }
+        }
 
-    // Clean up the GeoJSON file regardless of tile generation success/failure
-    let _ = std::fs::remove_file(&geojson_file_path);
+        match HeatmapGenerator::from_env(s3_client, dynamodb_client, target.clone(), deadline) {
+            Ok(Some(heatmap)) => match heatmap.generate_heatmap(&synced.geojson_path).await {
+                Ok(()) => metrics::increment_heatmap_success(),
+                Err(e) => {
+                    tracing::error!("Failed to generate heatmap: {e:#}");
+                    metrics::increment_heatmap_failure();
+                }
+            },
+            Ok(None) => {}
+            Err(e) => {
+                tracing::error!("Failed to create HeatmapGenerator: {e:#}");
+                metrics::increment_heatmap_failure();
+            }
+        }
+    }
+
+    // Clean up the GeoJSON files regardless of tile generation success/failure
+    let _ = std::fs::remove_file(&synced.geojson_path);
+    let _ = std::fs::remove_file(&synced.changes_path);
 
     match tile_result {
         Ok(()) => {
@@ -174,6 +373,51 @@ async fn process_user_sync(user_id: &str, sync_id: &str) -> Result<(), Error> {
     }
 }
 
+async fn process_group_sync(request: GroupSyncRequest) -> Result<(), Error> {
+    // The group ID ends up in S3 keys and file names
+    let group_id = request.group_id.as_str();
+    if group_id.is_empty()
+        || !group_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Error::from(format!("Invalid group ID: {group_id}")));
+    }
+    tracing::info!(
+        "Processing group sync for group {} sync: {}",
+        group_id,
+        request.sync_id
+    );
+
+    let tile_profile = TileProfile::resolve(request.tile_profile.as_deref())
+        .map_err(|e| Error::from(format!("Invalid tile profile: {e}")))?;
+
+    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let s3_client = S3Client::new(&config);
+    let dynamodb_client = aws_sdk_dynamodb::Client::new(&config);
+
+    let work_dir = TempDir::new(&format!("group_mapper_{group_id}"))
+        .map_err(|e| Error::from(format!("Failed to create work directory: {e}")))?;
+
+    let result =
+        match GroupMapBuilder::new(s3_client, dynamodb_client, tile_profile, work_dir.path()) {
+            Ok(builder) => builder.build(group_id).await,
+            Err(e) => Err(e),
+        };
+
+    match result {
+        Ok(()) => {
+            metrics::increment_group_map_success();
+            Ok(())
+        }
+        Err(e) => {
+            tracing::error!("Failed to build group map for {group_id}: {e:#}");
+            metrics::increment_group_map_failure();
+            Err(Error::from(format!("Group map failed: {e}")))
+        }
+    }
+}
+
 async fn get_intervals_access_token_from_clerk(user_id: &str) -> Result<String, Error> {
     let clerk_secret_key = env::var("CLERK_SECRET_KEY")
         .map_err(|_| Error::from("CLERK_SECRET_KEY environment variable not set"))?;
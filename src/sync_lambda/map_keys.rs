@@ -0,0 +1,158 @@
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::AttributeValue;
+use ridelines_drivetrain::common::types::MapTarget;
+use std::collections::HashMap;
+use tracing::info;
+
+pub const PMTILES_KEY: &str = "pmtilesKey";
+pub const MANIFEST_KEY: &str = "manifestKey";
+pub const HEATMAP_KEY: &str = "heatmapKey";
+pub const EXPLORER_KEY: &str = "explorerKey";
+pub const THUMBNAIL_KEY: &str = "thumbnailKey";
+
+/// S3 keys of a map's published files, stored in the users table. The
+/// user's own map uses top-level attributes such as `pmtilesKey`; maps of
+/// coached athletes use the matching `coachedPmtilesKeys` map, keyed by
+/// athlete ID.
+pub struct MapKeys {
+    dynamodb_client: DynamoDbClient,
+    users_table_name: String,
+    target: MapTarget,
+}
+
+impl MapKeys {
+    pub fn new(dynamodb_client: DynamoDbClient, users_table_name: &str, target: MapTarget) -> Self {
+        Self {
+            dynamodb_client,
+            users_table_name: users_table_name.to_string(),
+            target,
+        }
+    }
+
+    /// Current values of the given attributes; missing ones are left out
+    pub async fn get(&self, attributes: &[&str]) -> Result<HashMap<String, String>> {
+        let mut request = self
+            .dynamodb_client
+            .get_item()
+            .table_name(&self.users_table_name)
+            .key("id", AttributeValue::S(self.target.user_id.clone()));
+
+        let mut projection = Vec::with_capacity(attributes.len());
+        for (i, attribute) in attributes.iter().enumerate() {
+            let name = format!("#a{i}");
+            request = request.expression_attribute_names(&name, self.stored_attribute(attribute));
+            projection.push(match &self.target.athlete_id {
+                Some(_) => format!("{name}.#athlete"),
+                None => name,
+            });
+        }
+        if let Some(athlete_id) = &self.target.athlete_id {
+            request = request.expression_attribute_names("#athlete", athlete_id);
+        }
+
+        let item = request
+            .projection_expression(projection.join(", "))
+            .send()
+            .await
+            .context("Failed to read user record from DynamoDB")?
+            .item
+            .unwrap_or_default();
+
+        let mut values = HashMap::new();
+        for attribute in attributes {
+            let value = match &self.target.athlete_id {
+                Some(athlete_id) => match item.get(&self.stored_attribute(attribute)) {
+                    Some(AttributeValue::M(keys)) => keys.get(athlete_id),
+                    _ => None,
+                },
+                None => item.get(*attribute),
+            };
+            if let Some(AttributeValue::S(value)) = value {
+                values.insert(attribute.to_string(), value.clone());
+            }
+        }
+        Ok(values)
+    }
+
+    /// Set several attributes in a single update, so readers never see a
+    /// mix of old and new keys
+    pub async fn set(&self, values: &[(&str, &str)]) -> Result<()> {
+        let mut request = self
+            .dynamodb_client
+            .update_item()
+            .table_name(&self.users_table_name)
+            .key("id", AttributeValue::S(self.target.user_id.clone()));
+
+        let mut assignments = Vec::with_capacity(values.len());
+        for (i, (attribute, value)) in values.iter().enumerate() {
+            request = request
+                .expression_attribute_names(format!("#a{i}"), self.stored_attribute(attribute))
+                .expression_attribute_values(
+                    format!(":v{i}"),
+                    AttributeValue::S(value.to_string()),
+                );
+            assignments.push(match &self.target.athlete_id {
+                Some(_) => format!("#a{i}.#athlete = :v{i}"),
+                None => format!("#a{i} = :v{i}"),
+            });
+        }
+
+        if let Some(athlete_id) = &self.target.athlete_id {
+            self.initialize_coached_maps(values).await?;
+            request = request.expression_attribute_names("#athlete", athlete_id);
+        }
+
+        request
+            .update_expression(format!("SET {}", assignments.join(", ")))
+            .send()
+            .await
+            .context("Failed to update map keys in DynamoDB")?;
+
+        for (attribute, value) in values {
+            info!("Updated {attribute} for {} to {value}", self.target);
+        }
+        Ok(())
+    }
+
+    /// A nested path can only be set once the parent map exists
+    async fn initialize_coached_maps(&self, values: &[(&str, &str)]) -> Result<()> {
+        let mut request = self
+            .dynamodb_client
+            .update_item()
+            .table_name(&self.users_table_name)
+            .key("id", AttributeValue::S(self.target.user_id.clone()))
+            .expression_attribute_values(":empty", AttributeValue::M(Default::default()));
+
+        let mut assignments = Vec::with_capacity(values.len());
+        for (i, (attribute, _)) in values.iter().enumerate() {
+            request = request
+                .expression_attribute_names(format!("#a{i}"), self.stored_attribute(attribute));
+            assignments.push(format!("#a{i} = if_not_exists(#a{i}, :empty)"));
+        }
+
+        request
+            .update_expression(format!("SET {}", assignments.join(", ")))
+            .send()
+            .await
+            .context("Failed to initialize coached map keys in DynamoDB")?;
+        Ok(())
+    }
+
+    /// Attribute holding `attribute` for this map, e.g. `pmtilesKey` or
+    /// `coachedPmtilesKeys`
+    fn stored_attribute(&self, attribute: &str) -> String {
+        match self.target.athlete_id {
+            Some(_) => coached_attribute(attribute),
+            None => attribute.to_string(),
+        }
+    }
+}
+
+fn coached_attribute(attribute: &str) -> String {
+    let mut chars = attribute.chars();
+    match chars.next() {
+        Some(first) => format!("coached{}{}s", first.to_ascii_uppercase(), chars.as_str()),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::{Context, Result};
+use aws_sdk_s3::Client as S3Client;
+use aws_sdk_s3::types::{Tag, Tagging};
+use ridelines_drivetrain::common::metrics;
+use std::env;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+/// What happens to published objects that nothing points at any more
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcMode {
+    /// Tag with status=expired for the bucket's lifecycle rule to delete
+    Tag,
+    Delete,
+}
+
+impl GcMode {
+    /// Read PMTILES_GC_MODE ("tag" or "delete"), defaulting to tag
+    fn from_env() -> Result<Self> {
+        match env::var("PMTILES_GC_MODE").as_deref() {
+            Err(_) | Ok("tag") => Ok(GcMode::Tag),
+            Ok("delete") => Ok(GcMode::Delete),
+            Ok(other) => Err(anyhow::anyhow!("Unknown PMTILES_GC_MODE: {other}")),
+        }
+    }
+}
+
+/// Outcome of one reconciliation run
+#[derive(Debug, Default, Clone)]
+pub struct GcReport {
+    /// Archives and manifests under the prefix
+    pub scanned: usize,
+    /// Objects other than the current ones, of any age
+    pub orphans: usize,
+    /// Orphans tagged or deleted
+    pub reclaimed: usize,
+    pub reclaimed_bytes: u64,
+    /// Orphans still inside the grace period
+    pub within_grace_period: usize,
+    /// Orphans that could not be tagged or deleted, retried on the next run
+    pub failed: usize,
+}
+
+impl fmt::Display for GcReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "scanned {}, orphaned {}, reclaimed {} ({} bytes), within grace period {}, failed {}",
+            self.scanned,
+            self.orphans,
+            self.reclaimed,
+            self.reclaimed_bytes,
+            self.within_grace_period,
+            self.failed
+        )
+    }
+}
+
+/// Reconciles the PMTiles archives and manifests of one map with its
+/// pointers in the users table. Every object except the current ones is an
+/// orphan, whether it was replaced normally or left behind by a crash between
+/// upload and pointer update.
+pub struct PmtilesGc {
+    s3_client: S3Client,
+    bucket: String,
+    prefix: String,
+    grace_period: Duration,
+    mode: GcMode,
+}
+
+impl PmtilesGc {
+    /// `prefix` is the folder holding the map's objects, ending in a slash
+    pub fn new(s3_client: S3Client, bucket: &str, prefix: &str) -> Result<Self> {
+        let grace_hours = match env::var("PMTILES_GC_GRACE_HOURS") {
+            Ok(hours) => hours
+                .parse::<u64>()
+                .context("PMTILES_GC_GRACE_HOURS must be a whole number of hours")?,
+            Err(_) => 24,
+        };
+
+        Ok(Self {
+            s3_client,
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            grace_period: Duration::from_secs(grace_hours * 3600),
+            mode: GcMode::from_env()?,
+        })
+    }
+
+    /// Tag or delete every orphan older than the grace period. The grace
+    /// period covers uploads from concurrent syncs and clients still reading
+    /// the previous archive.
+    pub async fn reconcile(&self, current_keys: &[&str]) -> Result<GcReport> {
+        let mut report = GcReport::default();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+
+        for (key, size, modified_secs) in self.list_published().await? {
+            report.scanned += 1;
+            if current_keys.contains(&key.as_str()) {
+                continue;
+            }
+
+            report.orphans += 1;
+            if now - modified_secs < self.grace_period.as_secs() as i64 {
+                report.within_grace_period += 1;
+                continue;
+            }
+
+            let result = match self.mode {
+                GcMode::Tag => self.tag_for_expiration(&key).await,
+                GcMode::Delete => self.delete(&key).await,
+            };
+            match result {
+                Ok(()) => {
+                    report.reclaimed += 1;
+                    report.reclaimed_bytes += size;
+                }
+                Err(e) => {
+                    error!("Failed to reclaim orphaned object {key}: {e:#}");
+                    report.failed += 1;
+                }
+            }
+        }
+
+        metrics::increment_pmtiles_gc_reclaimed(report.reclaimed as u64, report.reclaimed_bytes);
+        metrics::increment_pmtiles_gc_failures(report.failed as u64);
+        info!(
+            "PMTiles GC under {} ({:?}): {report}",
+            self.prefix, self.mode
+        );
+        Ok(report)
+    }
+
+    /// Key, size and last-modified time of the archives and manifests
+    /// directly under the prefix. Coached athletes' maps and heatmaps live in
+    /// sub-prefixes and are reconciled with their own pointers.
+    async fn list_published(&self) -> Result<Vec<(String, u64, i64)>> {
+        let mut objects = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let response = self
+                .s3_client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(&self.prefix)
+                .delimiter("/")
+                .set_continuation_token(continuation_token)
+                .send()
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to list {}: {e}", self.prefix))?;
+
+            for object in response.contents() {
+                let (Some(key), Some(modified)) = (object.key(), object.last_modified()) else {
+                    continue;
+                };
+                if key.ends_with(".pmtiles") || key.ends_with(".json") {
+                    let size = object.size().unwrap_or_default().max(0) as u64;
+                    objects.push((key.to_string(), size, modified.secs()));
+                }
+            }
+
+            match response.next_continuation_token() {
+                Some(token) => continuation_token = Some(token.to_string()),
+                None => break,
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn tag_for_expiration(&self, s3_key: &str) -> Result<()> {
+        let tag = Tag::builder().key("status").value("expired").build()?;
+        let tagging = Tagging::builder().tag_set(tag).build()?;
+
+        self.s3_client
+            .put_object_tagging()
+            .bucket(&self.bucket)
+            .key(s3_key)
+            .tagging(tagging)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+        Ok(())
+    }
+
+    async fn delete(&self, s3_key: &str) -> Result<()> {
+        self.s3_client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(s3_key)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+        Ok(())
+    }
+}
@@ -1,14 +1,228 @@
-use anyhow::Result;
+use crate::activity_sync::ListingMode;
+use anyhow::{Context, Result};
 use aws_sdk_dynamodb::Client;
 use aws_sdk_dynamodb::types::AttributeValue;
-use chrono::Utc;
-use std::collections::HashMap;
-use tracing::{error, info};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info, warn};
 
 const TABLE_NAME: &str = "ridelines-sync-status";
 
+/// Attempts at each status update before it is given up on
+const MAX_UPDATE_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry, doubled for each one after it
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(200);
+
+/// Failed activities listed in a status record. More are only counted, so
+/// a sync that fails broadly stays well under DynamoDB's item size limit.
+const MAX_LISTED_FAILURES: usize = 50;
+
+/// Overall state of a sync, stored as `status`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncState {
+    /// Requested by chainring but not yet picked up
+    #[default]
+    Pending,
+    InProgress,
+    Completed,
+    /// Finished, but some activities could not be downloaded or converted
+    CompletedWithErrors,
+    Failed,
+}
+
+impl SyncState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SyncState::Pending => "pending",
+            SyncState::InProgress => "in_progress",
+            SyncState::Completed => "completed",
+            SyncState::CompletedWithErrors => "completed_with_errors",
+            SyncState::Failed => "failed",
+        }
+    }
+
+    /// Whether the sync ran to the end, with or without failed activities
+    pub fn is_completed(&self) -> bool {
+        matches!(self, SyncState::Completed | SyncState::CompletedWithErrors)
+    }
+}
+
+/// Longest failure reason stored in the status record
+const MAX_REASON_CHARS: usize = 200;
+
+/// An activity that could not be downloaded or converted in this sync
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActivityFailure {
+    pub activity_id: String,
+    pub reason: String,
+    /// Whether the activity will be skipped until it changes
+    #[serde(default)]
+    pub quarantined: bool,
+}
+
+/// State of one phase of a sync
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PhaseState {
+    #[default]
+    Pending,
+    InProgress,
+    Completed,
+}
+
+impl PhaseState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PhaseState::Pending => "pending",
+            PhaseState::InProgress => "in_progress",
+            PhaseState::Completed => "completed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AnalyzingPhase {
+    pub status: PhaseState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_activities: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unchanged_activities: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changed_activities: Option<u64>,
+    /// Activities skipped because they failed repeatedly and have not changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quarantined_activities: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listing_mode: Option<ListingMode>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DownloadingPhase {
+    pub status: PhaseState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_to_process: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub processed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GeneratingPhase {
+    pub status: PhaseState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Progress of each phase, stored as the `phases` map
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Phases {
+    pub analyzing: AnalyzingPhase,
+    pub downloading: DownloadingPhase,
+    pub generating: GeneratingPhase,
+}
+
+/// A sync status record as stored in the sync status table
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncRecord {
+    pub user_id: String,
+    pub sync_id: String,
+    #[serde(default)]
+    pub status: SyncState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requested_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub phases: Phases,
+    /// The first `MAX_LISTED_FAILURES` failed activities
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed_activities: Vec<ActivityFailure>,
+    /// Every failed activity, including those not listed
+    #[serde(default)]
+    pub failed_activity_count: u64,
+}
+
+/// Read a sync status record, None if it does not exist
+pub async fn fetch_sync_record(
+    client: &Client,
+    user_id: &str,
+    sync_id: &str,
+) -> Result<Option<SyncRecord>> {
+    let item = client
+        .get_item()
+        .table_name(TABLE_NAME)
+        .key("userId", AttributeValue::S(user_id.to_string()))
+        .key("syncId", AttributeValue::S(sync_id.to_string()))
+        .consistent_read(true)
+        .send()
+        .await
+        .context("Failed to read sync status from DynamoDB")?
+        .item;
+
+    item.map(serde_dynamo::from_item)
+        .transpose()
+        .context("Failed to parse sync status record")
+}
+
+/// Writes the progress of one sync to its status record. Phase updates go
+/// through a single queue, so they are written in the order they were made,
+/// and the queue is drained before the terminal status is written.
 #[derive(Clone)]
 pub struct SyncStatusUpdater {
+    writer: StatusWriter,
+    queue: mpsc::UnboundedSender<QueuedUpdate>,
+    /// Activities that failed so far in this sync
+    failures: Arc<Mutex<FailureReport>>,
+}
+
+/// Activity failures of one sync, listing only the first of them
+#[derive(Default)]
+struct FailureReport {
+    listed: Vec<ActivityFailure>,
+    count: u64,
+}
+
+impl FailureReport {
+    fn record(&mut self, failure: ActivityFailure) {
+        self.count += 1;
+        if self.listed.len() < MAX_LISTED_FAILURES {
+            self.listed.push(failure);
+        }
+    }
+}
+
+/// An entry in the update queue
+enum QueuedUpdate {
+    Update {
+        update: UpdateBuilder,
+        /// Consecutive queued updates with the same key are merged into the
+        /// latest, e.g. download progress that arrives faster than it is written
+        coalesce_key: Option<&'static str>,
+    },
+    /// Signalled once every update queued before it has been written
+    Flush(oneshot::Sender<()>),
+}
+
+/// The status record of one sync
+#[derive(Clone)]
+struct StatusWriter {
     client: Client,
     user_id: String,
     sync_id: String,
@@ -16,10 +230,17 @@ pub struct SyncStatusUpdater {
 
 impl SyncStatusUpdater {
     pub fn new(client: Client, user_id: String, sync_id: String) -> Self {
-        Self {
+        let writer = StatusWriter {
             client,
             user_id,
             sync_id,
+        };
+        let (queue, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_queue(writer.clone(), receiver));
+        Self {
+            writer,
+            queue,
+            failures: Arc::default(),
         }
     }
 
@@ -27,115 +248,135 @@ impl SyncStatusUpdater {
         // Use update to preserve existing fields like requestedAt
         let mut update = UpdateBuilder::new();
         update
-            .set("status", "in_progress")
+            .set("status", SyncState::InProgress.as_str())
             .set("startedAt", &Utc::now().to_rfc3339())
             .set_value(
                 "phases",
-                AttributeValue::M(
-                    [
-                        (
-                            "analyzing".to_string(),
-                            AttributeValue::M(
-                                [(
-                                    "status".to_string(),
-                                    AttributeValue::S("pending".to_string()),
-                                )]
-                                .into_iter()
-                                .collect(),
-                            ),
-                        ),
-                        (
-                            "downloading".to_string(),
-                            AttributeValue::M(
-                                [(
-                                    "status".to_string(),
-                                    AttributeValue::S("pending".to_string()),
-                                )]
-                                .into_iter()
-                                .collect(),
-                            ),
-                        ),
-                        (
-                            "generating".to_string(),
-                            AttributeValue::M(
-                                [(
-                                    "status".to_string(),
-                                    AttributeValue::S("pending".to_string()),
-                                )]
-                                .into_iter()
-                                .collect(),
-                            ),
-                        ),
-                    ]
-                    .into_iter()
-                    .collect(),
-                ),
+                serde_dynamo::to_attribute_value(Phases::default())?,
             );
 
-        self.execute_update(update).await?;
+        self.writer.execute_with_retry(&update).await?;
         info!(
             "Initialized sync status for user {} sync {}",
-            self.user_id, self.sync_id
+            self.writer.user_id, self.writer.sync_id
         );
         Ok(())
     }
 
     pub fn start_analyzing(&self) {
-        self.spawn_update(|u| {
-            u.set("phases.analyzing.status", "in_progress")
+        self.enqueue(None, |u| {
+            u.set("phases.analyzing.status", PhaseState::InProgress.as_str())
                 .set("phases.analyzing.message", "Loading activity data...")
         });
     }
 
-    pub fn complete_analyzing(&self, total: usize, unchanged: usize, changed: usize) {
-        self.spawn_update(move |u| {
-            u.set("phases.analyzing.status", "completed")
+    pub fn complete_analyzing(
+        &self,
+        total: usize,
+        unchanged: usize,
+        changed: usize,
+        quarantined: usize,
+    ) {
+        self.enqueue(None, move |u| {
+            u.set("phases.analyzing.status", PhaseState::Completed.as_str())
                 .set_number("phases.analyzing.totalActivities", total as i64)
                 .set_number("phases.analyzing.unchangedActivities", unchanged as i64)
                 .set_number("phases.analyzing.changedActivities", changed as i64)
+                .set_number("phases.analyzing.quarantinedActivities", quarantined as i64)
         });
         info!(
-            "Completed analyzing: {} total, {} unchanged, {} changed",
-            total, unchanged, changed
+            "Completed analyzing: {} total, {} unchanged, {} changed, {} quarantined",
+            total, unchanged, changed, quarantined
         );
     }
 
+    pub fn record_listing_mode(&self, mode: ListingMode) {
+        self.enqueue(None, move |u| {
+            u.set("phases.analyzing.listingMode", mode.as_str())
+        });
+    }
+
     pub fn start_downloading(&self, total_to_process: usize) {
-        self.spawn_update(move |u| {
-            u.set("phases.downloading.status", "in_progress")
+        self.enqueue(None, move |u| {
+            u.set("phases.downloading.status", PhaseState::InProgress.as_str())
                 .set_number("phases.downloading.totalToProcess", total_to_process as i64)
         });
     }
 
     pub fn update_download_progress(&self, processed: usize) {
-        self.spawn_update(move |u| u.set_number("phases.downloading.processed", processed as i64));
+        self.enqueue(Some("downloadProgress"), move |u| {
+            u.set_number("phases.downloading.processed", processed as i64)
+        });
     }
 
     pub fn complete_downloading(&self) {
-        self.spawn_update(|u| u.set("phases.downloading.status", "completed"));
+        self.enqueue(None, |u| {
+            u.set("phases.downloading.status", PhaseState::Completed.as_str())
+        });
     }
 
     pub fn start_generating(&self) {
-        self.spawn_update(|u| {
-            u.set("phases.generating.status", "in_progress")
+        self.enqueue(None, |u| {
+            u.set("phases.generating.status", PhaseState::InProgress.as_str())
                 .set("phases.generating.message", "Generating map tiles...")
         });
     }
 
     pub fn complete_generating(&self) {
-        self.spawn_update(|u| u.set("phases.generating.status", "completed"));
+        self.enqueue(None, |u| {
+            u.set("phases.generating.status", PhaseState::Completed.as_str())
+        });
+    }
+
+    /// Record an activity that could not be downloaded or converted. The
+    /// sync then completes with errors.
+    pub fn record_activity_failure(&self, activity_id: &str, reason: &str, quarantined: bool) {
+        let (listed, count) = {
+            let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+            failures.record(ActivityFailure {
+                activity_id: activity_id.to_string(),
+                reason: short_reason(reason),
+                quarantined,
+            });
+            (failures.listed.clone(), failures.count)
+        };
+
+        // The list is written with the count so coalescing never drops it
+        match serde_dynamo::to_attribute_value(listed) {
+            Ok(value) => self.enqueue(Some("failedActivities"), move |u| {
+                u.set_value("failedActivities", value)
+                    .set_value("failedActivityCount", AttributeValue::N(count.to_string()))
+            }),
+            Err(e) => error!("Failed to serialize activity failures: {e}"),
+        }
     }
 
     pub async fn mark_completed(&self) -> Result<()> {
+        self.flush().await;
+
+        let failed = self
+            .failures
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .count;
+        let state = if failed == 0 {
+            SyncState::Completed
+        } else {
+            SyncState::CompletedWithErrors
+        };
+
         let mut update = UpdateBuilder::new();
         update
-            .set("status", "completed")
+            .set("status", state.as_str())
             .set("completedAt", &Utc::now().to_rfc3339());
 
-        self.execute_update(update).await?;
+        self.writer.execute_with_retry(&update).await?;
         info!(
-            "Sync completed for user {} sync {}",
-            self.user_id, self.sync_id
+            "Sync {} for user {} sync {} ({} failed activities)",
+            state.as_str(),
+            self.writer.user_id,
+            self.writer.sync_id,
+            failed
         );
         Ok(())
     }
@@ -143,44 +384,91 @@ impl SyncStatusUpdater {
     pub async fn mark_failed(&self, error: &str) -> Result<()> {
         error!(
             "Sync failed for user {} sync {}: {}",
-            self.user_id, self.sync_id, error
+            self.writer.user_id, self.writer.sync_id, error
         );
+        self.flush().await;
 
         let mut update = UpdateBuilder::new();
         update
-            .set("status", "failed")
+            .set("status", SyncState::Failed.as_str())
             .set("completedAt", &Utc::now().to_rfc3339())
             .set("error", error);
 
-        self.execute_update(update).await
+        self.writer.execute_with_retry(&update).await
+    }
+
+    /// The current record for this sync, None if it does not exist yet
+    pub async fn fetch(&self) -> Result<Option<SyncRecord>> {
+        fetch_sync_record(
+            &self.writer.client,
+            &self.writer.user_id,
+            &self.writer.sync_id,
+        )
+        .await
     }
 
-    fn spawn_update<F>(&self, f: F)
+    /// Wait until every update queued so far has been written or given up on
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.queue.send(QueuedUpdate::Flush(done)).is_ok() {
+            let _ = flushed.await;
+        }
+    }
+
+    fn enqueue<F>(&self, coalesce_key: Option<&'static str>, f: F)
     where
-        F: FnOnce(&mut UpdateBuilder) -> &mut UpdateBuilder + Send + 'static,
+        F: FnOnce(&mut UpdateBuilder) -> &mut UpdateBuilder,
     {
-        let updater = self.clone();
-        tokio::spawn(async move {
-            let mut update = UpdateBuilder::new();
-            f(&mut update);
-            let _ = updater.execute_update(update).await;
-        });
+        let mut update = UpdateBuilder::new();
+        f(&mut update);
+        if self
+            .queue
+            .send(QueuedUpdate::Update {
+                update,
+                coalesce_key,
+            })
+            .is_err()
+        {
+            warn!("Sync status queue has stopped, dropping update");
+        }
     }
+}
 
-    async fn execute_update(&self, update: UpdateBuilder) -> Result<()> {
+impl StatusWriter {
+    /// Write an update, retrying with backoff when DynamoDB rejects it
+    async fn execute_with_retry(&self, update: &UpdateBuilder) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            match self.execute_update(update).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < MAX_UPDATE_ATTEMPTS => {
+                    let delay = RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+                    warn!(
+                        "Sync status update failed (attempt {attempt}), retrying in {}ms: {e:#}",
+                        delay.as_millis()
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn execute_update(&self, update: &UpdateBuilder) -> Result<()> {
         let mut req = self
             .client
             .update_item()
             .table_name(TABLE_NAME)
             .key("userId", AttributeValue::S(self.user_id.clone()))
             .key("syncId", AttributeValue::S(self.sync_id.clone()))
-            .update_expression(update.expression);
+            .update_expression(&update.expression);
 
-        for (k, v) in update.values {
-            req = req.expression_attribute_values(k, v);
+        for (k, v) in &update.values {
+            req = req.expression_attribute_values(k, v.clone());
         }
 
-        for (k, v) in update.names {
+        for (k, v) in &update.names {
             req = req.expression_attribute_names(k, v);
         }
 
@@ -194,6 +482,66 @@ impl SyncStatusUpdater {
     }
 }
 
+/// The first line of an error, cut to a length that fits the status record
+fn short_reason(reason: &str) -> String {
+    let line = reason.lines().next().unwrap_or_default().trim();
+    if line.chars().count() <= MAX_REASON_CHARS {
+        return line.to_string();
+    }
+    let mut short: String = line.chars().take(MAX_REASON_CHARS - 1).collect();
+    short.push('…');
+    short
+}
+
+/// Write queued updates one at a time, in order, until every sender is gone.
+/// Updates that queue up while one is being written are coalesced first.
+async fn run_queue(writer: StatusWriter, mut receiver: mpsc::UnboundedReceiver<QueuedUpdate>) {
+    let mut pending = VecDeque::new();
+    loop {
+        if pending.is_empty() {
+            match receiver.recv().await {
+                Some(queued) => push_coalesced(&mut pending, queued),
+                None => break,
+            }
+        }
+        while let Ok(queued) = receiver.try_recv() {
+            push_coalesced(&mut pending, queued);
+        }
+
+        match pending.pop_front() {
+            Some(QueuedUpdate::Update { update, .. }) => {
+                if let Err(e) = writer.execute_with_retry(&update).await {
+                    error!(
+                        "Giving up on sync status update for user {} sync {}: {e:#}",
+                        writer.user_id, writer.sync_id
+                    );
+                }
+            }
+            Some(QueuedUpdate::Flush(done)) => {
+                let _ = done.send(());
+            }
+            None => {}
+        }
+    }
+}
+
+/// Queue an update, replacing the last queued one when they share a coalesce key
+fn push_coalesced(pending: &mut VecDeque<QueuedUpdate>, queued: QueuedUpdate) {
+    if let QueuedUpdate::Update {
+        coalesce_key: Some(key),
+        ..
+    } = &queued
+        && let Some(QueuedUpdate::Update {
+            coalesce_key: Some(last_key),
+            ..
+        }) = pending.back()
+        && last_key == key
+    {
+        pending.pop_back();
+    }
+    pending.push_back(queued);
+}
+
 struct UpdateBuilder {
     parts: Vec<String>,
     values: HashMap<String, AttributeValue>,
@@ -223,16 +571,11 @@ impl UpdateBuilder {
         let val_key = format!(":v{}", self.values.len());
         self.values.insert(val_key.clone(), value);
 
+        // Every segment goes through a name placeholder, so attribute names
+        // that are DynamoDB reserved words never break the expression
         let safe_path = path
             .split('.')
-            .map(|part| match part {
-                "status" | "error" | "processed" => {
-                    let placeholder = format!("#{}", part);
-                    self.names.insert(placeholder.clone(), part.to_string());
-                    placeholder
-                }
-                _ => part.to_string(),
-            })
+            .map(|part| self.alias(part))
             .collect::<Vec<_>>()
             .join(".");
 
@@ -240,12 +583,87 @@ impl UpdateBuilder {
         self.expression = format!("SET {}", self.parts.join(", "));
         self
     }
+
+    /// Placeholder for an attribute name: `#name` for plain names, otherwise
+    /// a numbered one
+    fn alias(&mut self, name: &str) -> String {
+        if let Some((placeholder, _)) = self.names.iter().find(|(_, n)| n.as_str() == name) {
+            return placeholder.clone();
+        }
+        let mut placeholder = format!("#{name}");
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+            placeholder = (self.names.len()..)
+                .map(|i| format!("#n{i}"))
+                .find(|p| !self.names.contains_key(p))
+                .unwrap_or_default();
+        }
+        self.names.insert(placeholder.clone(), name.to_string());
+        placeholder
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_push_coalesced() {
+        let progress = |processed: i64| {
+            let mut update = UpdateBuilder::new();
+            update.set_number("phases.downloading.processed", processed);
+            QueuedUpdate::Update {
+                update,
+                coalesce_key: Some("downloadProgress"),
+            }
+        };
+        let completed = || {
+            let mut update = UpdateBuilder::new();
+            update.set("phases.downloading.status", "completed");
+            QueuedUpdate::Update {
+                update,
+                coalesce_key: None,
+            }
+        };
+
+        let mut pending = VecDeque::new();
+        for queued in [progress(10), progress(20), completed(), progress(30)] {
+            push_coalesced(&mut pending, queued);
+        }
+
+        // Progress merges into the latest value but never jumps past the
+        // completion queued after it
+        let processed: Vec<Option<String>> = pending
+            .iter()
+            .map(|queued| match queued {
+                QueuedUpdate::Update { update, .. } => update
+                    .values
+                    .get(":v0")
+                    .and_then(|v| v.as_n().ok())
+                    .cloned(),
+                QueuedUpdate::Flush(_) => None,
+            })
+            .collect();
+        assert_eq!(
+            processed,
+            vec![Some("20".to_string()), None, Some("30".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_failure_report_lists_the_first_failures() {
+        let mut report = FailureReport::default();
+        for i in 0..MAX_LISTED_FAILURES + 10 {
+            report.record(ActivityFailure {
+                activity_id: format!("i{i}"),
+                reason: "HTTP 404".to_string(),
+                quarantined: false,
+            });
+        }
+        assert_eq!(report.listed.len(), MAX_LISTED_FAILURES);
+        assert_eq!(report.listed[0].activity_id, "i0");
+        assert_eq!(report.count, MAX_LISTED_FAILURES as u64 + 10);
+    }
+
     #[test]
     fn test_update_builder_expression() {
         let mut builder = UpdateBuilder::new();
@@ -260,7 +678,51 @@ mod tests {
 
         assert!(builder.expression.contains("SET"));
         assert!(builder.expression.contains("#status"));
-        assert_eq!(builder.names.len(), 1);
+        assert!(
+            builder
+                .expression
+                .contains("#phases.#analyzing.#status = :v2")
+        );
+        // Each distinct segment is aliased once
+        assert_eq!(builder.names.len(), 4);
         assert_eq!(builder.names.get("#status"), Some(&"status".to_string()));
     }
+
+    #[test]
+    fn test_sync_record_round_trip() {
+        let item: HashMap<String, AttributeValue> = [
+            ("userId", AttributeValue::S("user_1".to_string())),
+            ("syncId", AttributeValue::S("sync_1".to_string())),
+            ("status", AttributeValue::S("in_progress".to_string())),
+            (
+                "startedAt",
+                AttributeValue::S("2026-01-01T00:00:00+00:00".to_string()),
+            ),
+            (
+                "phases",
+                serde_dynamo::to_attribute_value(Phases::default()).unwrap(),
+            ),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+
+        let mut record: SyncRecord = serde_dynamo::from_item(item).unwrap();
+        assert_eq!(record.status, SyncState::InProgress);
+        assert_eq!(record.phases.downloading.status, PhaseState::Pending);
+        assert!(record.phases.analyzing.total_activities.is_none());
+
+        record.phases.analyzing.listing_mode = Some(ListingMode::Recent);
+        let stored: HashMap<String, AttributeValue> = serde_dynamo::to_item(&record).unwrap();
+        let phases = stored["phases"].as_m().unwrap();
+        let analyzing = phases["analyzing"].as_m().unwrap();
+        assert_eq!(
+            analyzing["listingMode"],
+            AttributeValue::S("recent".to_string())
+        );
+        assert_eq!(
+            serde_dynamo::from_item::<_, SyncRecord>(stored).unwrap(),
+            record
+        );
+    }
 }
@@ -0,0 +1,368 @@
+use crate::activity_sync::SyncedActivities;
+use crate::cdn::{self, CacheInvalidator};
+use crate::map_keys::{self, MapKeys};
+use crate::tiles::{self, Thumbnail, ThumbnailContent, ThumbnailSettings};
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_s3::Client as S3Client;
+use aws_sdk_s3::primitives::ByteStream;
+use function_timer::time;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use geojson::FeatureCollection;
+use ridelines_drivetrain::common::metrics;
+use ridelines_drivetrain::common::types::MapTarget;
+use std::collections::HashSet;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+/// Thumbnails are only started with this much of the Lambda invocation left
+const MIN_REMAINING_TIME: Duration = Duration::from_secs(60);
+
+/// Thumbnails keep their keys when an activity changes, so they are cached briefly
+const THUMBNAIL_CACHE_CONTROL: &str = "public, max-age=300";
+
+const DEFAULT_MAX_PER_SYNC: usize = 200;
+
+const UPLOAD_CONCURRENCY: usize = 8;
+
+/// Thumbnails rendered in one pass over the archive
+struct RenderedThumbnails {
+    /// The whole map, None when there are no activities
+    map: Option<Vec<u8>>,
+    /// Activity ID and image of each activity rendered
+    activities: Vec<(String, Vec<u8>)>,
+    /// Every activity ID in the archive
+    current_ids: HashSet<String>,
+    /// Activities left for a later sync by the per-sync limit
+    deferred: usize,
+}
+
+/// Renders PNG previews for sharing links and digests: one of the whole map
+/// at `activities/{storage_id}/thumbnail.png`, pointed at by `thumbnailKey`,
+/// and one per activity at `activities/{storage_id}/thumbnails/{id}.png`
+pub struct ThumbnailGenerator {
+    s3_client: S3Client,
+    map_keys: MapKeys,
+    target: MapTarget,
+    activities_bucket: String,
+    settings: ThumbnailSettings,
+    /// Activity thumbnails rendered per sync; the rest catch up on later syncs
+    max_per_sync: usize,
+    /// When the Lambda invocation times out
+    deadline: SystemTime,
+    invalidator: Arc<dyn CacheInvalidator>,
+}
+
+impl ThumbnailGenerator {
+    /// None unless THUMBNAILS_ENABLED is "true"
+    pub fn from_env(
+        s3_client: S3Client,
+        dynamodb_client: DynamoDbClient,
+        target: MapTarget,
+        deadline: SystemTime,
+        invalidator: Arc<dyn CacheInvalidator>,
+    ) -> Result<Option<Self>> {
+        if env::var("THUMBNAILS_ENABLED").as_deref() != Ok("true") {
+            return Ok(None);
+        }
+
+        let activities_bucket = env::var("ACTIVITIES_S3_BUCKET")
+            .context("ACTIVITIES_S3_BUCKET environment variable not set")?;
+        let users_table_name = env::var("USERS_TABLE_NAME")
+            .context("USERS_TABLE_NAME environment variable not set")?;
+        let max_per_sync = match env::var("THUMBNAIL_MAX_PER_SYNC") {
+            Ok(count) => count
+                .parse::<usize>()
+                .context("THUMBNAIL_MAX_PER_SYNC must be a whole number")?,
+            Err(_) => DEFAULT_MAX_PER_SYNC,
+        };
+        let settings = ThumbnailSettings {
+            basemap_dir: env::var("THUMBNAIL_BASEMAP_DIR").ok().map(PathBuf::from),
+            ..Default::default()
+        };
+
+        Ok(Some(Self {
+            s3_client,
+            map_keys: MapKeys::new(dynamodb_client, &users_table_name, target.clone()),
+            target,
+            activities_bucket,
+            settings,
+            max_per_sync,
+            deadline,
+            invalidator,
+        }))
+    }
+
+    /// Render the map thumbnail and the thumbnails of new, changed and not
+    /// yet rendered activities, and remove those of deleted activities
+    #[time("generate_thumbnails_duration")]
+    pub async fn generate_thumbnails(&self, synced: &SyncedActivities) -> Result<()> {
+        let remaining = self
+            .deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        if remaining < MIN_REMAINING_TIME {
+            anyhow::bail!(
+                "Only {}s left before the Lambda deadline, not starting thumbnails",
+                remaining.as_secs()
+            );
+        }
+
+        let published = self.list_published().await?;
+
+        // Rendering is CPU-bound, so it runs off the async runtime
+        let geojson_path = synced.geojson_path.clone();
+        let changes_path = synced.changes_path.clone();
+        let settings = self.settings.clone();
+        let max_per_sync = self.max_per_sync;
+        let already_published = published.clone();
+        let rendered = tokio::task::spawn_blocking(move || {
+            let changed_ids = activity_ids(&changes_path)?;
+            render_all(
+                &geojson_path,
+                &settings,
+                |id: &str| changed_ids.contains(id) || !already_published.contains(id),
+                max_per_sync,
+            )
+        })
+        .await
+        .context("Thumbnail task panicked")??;
+
+        let rendered_count = rendered.activities.len();
+        stream::iter(rendered.activities)
+            .map(|(id, image)| async move {
+                let key = self.activity_key(&id);
+                self.upload(&key, image).await
+            })
+            .buffer_unordered(UPLOAD_CONCURRENCY)
+            .try_collect::<Vec<()>>()
+            .await?;
+
+        // Thumbnails keep their keys, so cached copies are dropped once uploaded
+        let mut stale_paths = Vec::new();
+        if let Some(image) = rendered.map {
+            let map_key = format!("activities/{}/thumbnail.png", self.target.storage_id());
+            self.upload(&map_key, image).await?;
+            stale_paths.push(cdn::object_path(&map_key));
+
+            let current = self.map_keys.get(&[map_keys::THUMBNAIL_KEY]).await?;
+            if current.get(map_keys::THUMBNAIL_KEY) != Some(&map_key) {
+                self.map_keys
+                    .set(&[(map_keys::THUMBNAIL_KEY, &map_key)])
+                    .await?;
+            }
+        }
+
+        let removed = self.remove_deleted(&published, &rendered.current_ids).await;
+        if rendered_count > 0 || removed > 0 {
+            stale_paths.push(cdn::object_path(&format!("{}*", self.activity_prefix())));
+        }
+        if !stale_paths.is_empty() {
+            cdn::invalidate_paths(self.invalidator.as_ref(), &stale_paths).await;
+        }
+        metrics::record_thumbnails_rendered(rendered_count as u64);
+        info!(
+            "Thumbnails for {}: rendered {}, deferred {}, removed {}",
+            self.target, rendered_count, rendered.deferred, removed
+        );
+        Ok(())
+    }
+
+    async fn upload(&self, key: &str, image: Vec<u8>) -> Result<()> {
+        self.s3_client
+            .put_object()
+            .bucket(&self.activities_bucket)
+            .key(key)
+            .body(ByteStream::from(image))
+            .content_type("image/png")
+            .cache_control(THUMBNAIL_CACHE_CONTROL)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to upload thumbnail {key}: {e}"))?;
+        Ok(())
+    }
+
+    /// Activity IDs that already have a thumbnail
+    async fn list_published(&self) -> Result<HashSet<String>> {
+        let prefix = self.activity_prefix();
+        let prefix = prefix.as_str();
+        let mut ids = HashSet::new();
+        let mut continuation_token = None;
+
+        loop {
+            let response = self
+                .s3_client
+                .list_objects_v2()
+                .bucket(&self.activities_bucket)
+                .prefix(prefix)
+                .set_continuation_token(continuation_token)
+                .send()
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to list {prefix}: {e}"))?;
+
+            for object in response.contents() {
+                if let Some(id) = object
+                    .key()
+                    .and_then(|key| key.strip_prefix(prefix))
+                    .and_then(|name| name.strip_suffix(".png"))
+                {
+                    ids.insert(id.to_string());
+                }
+            }
+
+            match response.next_continuation_token() {
+                Some(token) => continuation_token = Some(token.to_string()),
+                None => break,
+            }
+        }
+
+        Ok(ids)
+    }
+
+    /// Delete the thumbnails of activities no longer in the archive. Failures
+    /// are logged and retried on the next sync.
+    async fn remove_deleted(
+        &self,
+        published: &HashSet<String>,
+        current_ids: &HashSet<String>,
+    ) -> usize {
+        let mut removed = 0;
+        for id in published.difference(current_ids) {
+            let key = self.activity_key(id);
+            match self
+                .s3_client
+                .delete_object()
+                .bucket(&self.activities_bucket)
+                .key(&key)
+                .send()
+                .await
+            {
+                Ok(_) => removed += 1,
+                Err(e) => error!("Failed to delete thumbnail {key}: {e}"),
+            }
+        }
+        removed
+    }
+
+    fn activity_prefix(&self) -> String {
+        format!("activities/{}/thumbnails/", self.target.storage_id())
+    }
+
+    fn activity_key(&self, activity_id: &str) -> String {
+        format!("{}{activity_id}.png", self.activity_prefix())
+    }
+}
+
+/// Render the selected activities as they are read, then read the archive
+/// again to draw every activity onto the map thumbnail, whose framing is
+/// only known once all activities have been seen
+fn render_all(
+    geojson_path: &Path,
+    settings: &ThumbnailSettings,
+    wanted: impl Fn(&str) -> bool,
+    max_per_sync: usize,
+) -> Result<RenderedThumbnails> {
+    let mut rendered = RenderedThumbnails {
+        map: None,
+        activities: Vec::new(),
+        current_ids: HashSet::new(),
+        deferred: 0,
+    };
+    let mut map_bounds: Option<[f64; 4]> = None;
+
+    for_each_activity(geojson_path, |id, content| {
+        if let Some([min_x, min_y, max_x, max_y]) = content.world_bounds() {
+            map_bounds = Some(match map_bounds {
+                Some(b) => [
+                    b[0].min(min_x),
+                    b[1].min(min_y),
+                    b[2].max(max_x),
+                    b[3].max(max_y),
+                ],
+                None => [min_x, min_y, max_x, max_y],
+            });
+        }
+
+        if wanted(id) {
+            if rendered.activities.len() >= max_per_sync {
+                rendered.deferred += 1;
+            } else {
+                match tiles::render_thumbnail(&content, settings) {
+                    Ok(Some(image)) => rendered.activities.push((id.to_string(), image)),
+                    Ok(None) => {}
+                    Err(e) => warn!("Failed to render thumbnail for activity {id}: {e:#}"),
+                }
+            }
+        }
+        rendered.current_ids.insert(id.to_string());
+    })?;
+
+    if let Some(bounds) = map_bounds {
+        let mut map = Thumbnail::new(bounds, settings)?;
+        for_each_activity(geojson_path, |_, mut content| {
+            // Start and finish markers would crowd out the tracks
+            content.start = None;
+            content.finish = None;
+            map.draw(&content);
+        })?;
+        rendered.map = Some(map.finish()?);
+    }
+
+    Ok(rendered)
+}
+
+/// Call `f` with the ID and track of each activity in a line-delimited GeoJSON file
+fn for_each_activity(geojson_path: &Path, mut f: impl FnMut(&str, ThumbnailContent)) -> Result<()> {
+    let file = File::open(geojson_path)
+        .with_context(|| format!("Failed to open {}", geojson_path.display()))?;
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let collection: FeatureCollection = match serde_json::from_str(&line) {
+            Ok(collection) => collection,
+            Err(e) => {
+                warn!("Skipping unparseable activity for thumbnails: {}", e);
+                continue;
+            }
+        };
+        if let Some(id) = activity_id(&collection) {
+            f(&id, ThumbnailContent::from_activity(&collection));
+        }
+    }
+    Ok(())
+}
+
+/// IDs of the activities in a line-delimited GeoJSON file
+fn activity_ids(geojson_path: &Path) -> Result<HashSet<String>> {
+    let file = File::open(geojson_path)
+        .with_context(|| format!("Failed to open {}", geojson_path.display()))?;
+
+    let mut ids = HashSet::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if let Ok(collection) = serde_json::from_str::<FeatureCollection>(&line)
+            && let Some(id) = activity_id(&collection)
+        {
+            ids.insert(id);
+        }
+    }
+    Ok(ids)
+}
+
+/// The `id` property of the activity's line feature
+fn activity_id(collection: &FeatureCollection) -> Option<String> {
+    collection
+        .features
+        .first()?
+        .property("id")?
+        .as_str()
+        .map(String::from)
+}
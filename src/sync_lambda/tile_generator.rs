@@ -1,60 +1,180 @@
+use crate::activity_sync::SyncedActivities;
+use crate::archive_store::{self, ArchiveStore};
+use crate::cdn::{self, CacheInvalidator};
+use crate::map_keys::{self, MapKeys};
+use crate::pmtiles_gc::PmtilesGc;
+use crate::tile_manifest::{BuildInfo, TileManifest};
+use crate::tile_profile::{self, TileProfile};
+use crate::tiles::{self, ArchiveSummary};
 use anyhow::{Context, Result};
 use aws_sdk_dynamodb::Client as DynamoDbClient;
-use aws_sdk_dynamodb::types::AttributeValue;
 use aws_sdk_s3::Client as S3Client;
 use aws_sdk_s3::primitives::ByteStream;
+use chrono::{DateTime, Utc};
 use function_timer::time;
 use ridelines_drivetrain::common::metrics;
-use sha2::{Digest, Sha256};
+use ridelines_drivetrain::common::types::MapTarget;
+use std::collections::{BTreeMap, VecDeque};
 use std::env;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tokio::fs;
-use tracing::{error, info};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tracing::{error, info, warn};
+
+const TIPPECANOE_PATH: &str = "/opt/bin/tippecanoe";
+
+/// Lambda time kept back from tippecanoe for uploading the archive and
+/// recording the sync status
+const DEADLINE_SAFETY_MARGIN: Duration = Duration::from_secs(30);
+
+/// Manifests are rewritten when an unchanged archive is rebuilt from a newer index
+const MANIFEST_CACHE_CONTROL: &str = "public, max-age=300";
+
+/// A previous archive is only patched when fewer than this fraction of its
+/// tiles changed
+const MAX_DIRTY_FRACTION: f64 = 0.5;
+
+const DEFAULT_FULL_REBUILD_DAYS: i64 = 7;
+
+/// Number of trailing tippecanoe stderr lines included in failure messages
+const STDERR_TAIL_LINES: usize = 20;
+
+/// How the PMTiles archive is built from GeoJSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileEngine {
+    /// In-process clipping, simplification and MVT encoding
+    Native,
+    /// The tippecanoe binary from the Lambda layer
+    Tippecanoe,
+}
+
+impl TileEngine {
+    /// Read TILE_ENGINE ("native" or "tippecanoe"), defaulting to native
+    fn from_env() -> Result<Self> {
+        match env::var("TILE_ENGINE").as_deref() {
+            Err(_) | Ok("native") => Ok(TileEngine::Native),
+            Ok("tippecanoe") => Ok(TileEngine::Tippecanoe),
+            Ok(other) => Err(anyhow::anyhow!("Unknown TILE_ENGINE: {other}")),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            TileEngine::Native => "native",
+            TileEngine::Tippecanoe => "tippecanoe",
+        }
+    }
+}
+
+/// The archive and manifest the users table currently points at
+struct PreviousBuild {
+    pmtiles_key: String,
+    manifest: TileManifest,
+}
+
+/// Where the map's archive ended up after publishing
+struct PublishedArchive {
+    key: String,
+    manifest_key: String,
+    /// Whether the users table now points at a different archive
+    pointer_updated: bool,
+}
 
 pub struct TileGenerator {
     s3_client: S3Client,
-    dynamodb_client: DynamoDbClient,
-    user_id: String,
+    store: ArchiveStore,
+    map_keys: MapKeys,
+    target: MapTarget,
     activities_bucket: String,
-    users_table_name: String,
+    engine: TileEngine,
+    profile: TileProfile,
+    /// When the Lambda invocation times out
+    deadline: SystemTime,
+    invalidator: Arc<dyn CacheInvalidator>,
+    /// Patched archives are rebuilt from scratch once their last full build is this old
+    full_rebuild_interval: chrono::Duration,
 }
 
 impl TileGenerator {
     pub fn new(
         s3_client: S3Client,
         dynamodb_client: DynamoDbClient,
-        user_id: String,
+        target: MapTarget,
+        profile: TileProfile,
+        deadline: SystemTime,
+        invalidator: Arc<dyn CacheInvalidator>,
     ) -> Result<Self> {
         let activities_bucket = env::var("ACTIVITIES_S3_BUCKET")
             .context("ACTIVITIES_S3_BUCKET environment variable not set")?;
         let users_table_name = env::var("USERS_TABLE_NAME")
             .context("USERS_TABLE_NAME environment variable not set")?;
+        let engine = TileEngine::from_env()?;
+        let full_rebuild_days = match env::var("TILE_FULL_REBUILD_DAYS") {
+            Ok(days) => days
+                .parse::<i64>()
+                .context("TILE_FULL_REBUILD_DAYS must be a whole number of days")?,
+            Err(_) => DEFAULT_FULL_REBUILD_DAYS,
+        };
 
         Ok(Self {
+            store: ArchiveStore::new(s3_client.clone(), &activities_bucket),
             s3_client,
-            dynamodb_client,
-            user_id,
+            map_keys: MapKeys::new(dynamodb_client, &users_table_name, target.clone()),
+            target,
             activities_bucket,
-            users_table_name,
+            engine,
+            profile,
+            deadline,
+            invalidator,
+            full_rebuild_interval: chrono::Duration::days(full_rebuild_days),
         })
     }
 
     #[time("generate_pmtiles_duration")]
-    pub async fn generate_pmtiles_from_file(&self, geojson_file_path: &str) -> Result<()> {
+    pub async fn generate_pmtiles(&self, synced: &SyncedActivities) -> Result<()> {
+        let geojson_file_path = &*synced.geojson_path.to_string_lossy();
         info!(
-            "Starting PMTiles generation for user {} from file: {}",
-            self.user_id, geojson_file_path
+            "Starting PMTiles generation for {} from file: {}",
+            self.target, geojson_file_path
         );
 
         // Create temporary PMTiles file
-        let temp_pmtiles_file = format!("/tmp/{}.pmtiles", self.user_id);
+        let temp_pmtiles_file = format!("/tmp/{}.pmtiles", self.target.file_stem());
+
+        // Phases 1 and 2: Patch the previous archive where possible, otherwise
+        // build from the full GeoJSON file, and check the result before
+        // anything points at it
+        let (summary, build) = match self.build_incremental(synced, &temp_pmtiles_file).await {
+            Ok(Some(built)) => built,
+            Ok(None) => self.build_full(synced, &temp_pmtiles_file).await?,
+            Err(e) => {
+                warn!("Incremental tile build failed, rebuilding in full: {e:#}");
+                metrics::increment_incremental_build_fallback();
+                self.build_full(synced, &temp_pmtiles_file).await?
+            }
+        };
 
-        // Phase 1: Run tippecanoe directly on the provided GeoJSON file
-        self.run_tippecanoe(geojson_file_path, &temp_pmtiles_file)
+        // Phase 3: Upload PMTiles and its manifest to S3 and update DynamoDB (timed)
+        let published = self
+            .upload_pmtiles(&temp_pmtiles_file, geojson_file_path, &summary, &build)
             .await?;
 
-        // Phase 2: Upload PMTiles to S3 and update DynamoDB (timed)
-        self.upload_pmtiles(&temp_pmtiles_file).await?;
+        // Phase 4: Drop cached copies of anything that changed for this map.
+        // With an unchanged pointer only the manifest was rewritten in place.
+        if published.pointer_updated {
+            cdn::invalidate_map(self.invalidator.as_ref(), &self.target).await;
+        } else {
+            let paths = [cdn::object_path(&published.manifest_key)];
+            cdn::invalidate_paths(self.invalidator.as_ref(), &paths).await;
+        }
+
+        // Phase 5: Clean up archives and manifests that are no longer referenced
+        self.collect_garbage(&[&published.key, &published.manifest_key])
+            .await;
 
         // Clean up temp files
         let _ = fs::remove_file(&temp_pmtiles_file).await;
@@ -62,159 +182,550 @@ impl TileGenerator {
         Ok(())
     }
 
-    #[time("tippecanoe_execution_duration")]
-    async fn run_tippecanoe(&self, input_file: &str, output_file: &str) -> Result<()> {
-        info!("Running tippecanoe: {input_file} -> {output_file}");
-
-        let output = Command::new("/opt/bin/tippecanoe")
-            .args([
-                "--preserve-input-order",
-                "-fl",
-                "activities",
-                "-o",
-                output_file,
-                input_file,
-            ])
-            .output()
-            .context("Failed to execute tippecanoe")?;
+    /// Build every tile from the full GeoJSON file
+    async fn build_full(
+        &self,
+        synced: &SyncedActivities,
+        output_file: &str,
+    ) -> Result<(ArchiveSummary, BuildInfo)> {
+        let geojson_file = &*synced.geojson_path.to_string_lossy();
+        match self.engine {
+            TileEngine::Native => self.run_native_tiler(geojson_file, output_file).await?,
+            TileEngine::Tippecanoe => self.run_tippecanoe(geojson_file, output_file).await?,
+        }
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            error!("Tippecanoe failed with status: {}", output.status);
-            error!("Stderr: {stderr}");
-            error!("Stdout: {stdout}");
-            metrics::increment_tippecanoe_failure();
-            return Err(anyhow::anyhow!("Tippecanoe failed: {stderr}"));
+        let summary = match self.validate_pmtiles(geojson_file, output_file).await {
+            Ok(summary) => summary,
+            Err(e) => {
+                let _ = fs::remove_file(output_file).await;
+                return Err(e);
+            }
+        };
+
+        metrics::increment_tile_build("full");
+        Ok((
+            summary,
+            BuildInfo {
+                mode: "full".to_string(),
+                last_full_build_at: Utc::now().to_rfc3339(),
+                source_version: synced.version.clone(),
+            },
+        ))
+    }
+
+    /// Regenerate only the tiles the changed activities touch and merge them
+    /// into a copy of the previous archive. Returns None when a full build is
+    /// needed: for tippecanoe, a first sync, a previous archive built from a
+    /// different index version, or a full build that is due.
+    #[time("incremental_tiler_duration")]
+    async fn build_incremental(
+        &self,
+        synced: &SyncedActivities,
+        output_file: &str,
+    ) -> Result<Option<(ArchiveSummary, BuildInfo)>> {
+        if self.engine != TileEngine::Native {
+            return Ok(None);
         }
+        let Some(previous_version) = &synced.previous_version else {
+            return Ok(None);
+        };
+        let Some(previous) = self.previous_build().await? else {
+            info!("No previous build for {}, building in full", self.target);
+            return Ok(None);
+        };
+        let Some(previous_info) = previous.manifest.build else {
+            info!("Previous manifest has no build information, building in full");
+            return Ok(None);
+        };
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        if !stdout.is_empty() {
-            info!("Tippecanoe output: {stdout}");
+        // Changes are relative to the previous index, so the archive must
+        // have been built from it, not from an older one
+        if previous_info.source_version != *previous_version {
+            info!(
+                "Previous archive was built from index {}, not {previous_version}; building in full",
+                previous_info.source_version
+            );
+            return Ok(None);
         }
 
-        metrics::increment_tippecanoe_success();
-        Ok(())
-    }
+        let last_full_build = DateTime::parse_from_rfc3339(&previous_info.last_full_build_at)
+            .context("Invalid lastFullBuildAt in previous manifest")?;
+        if Utc::now() - last_full_build.with_timezone(&Utc) >= self.full_rebuild_interval {
+            info!(
+                "Last full build was at {}, rebuilding in full for consistency",
+                previous_info.last_full_build_at
+            );
+            return Ok(None);
+        }
 
-    #[time("pmtiles_upload_duration")]
-    async fn upload_pmtiles(&self, pmtiles_file: &str) -> Result<()> {
-        info!("Uploading PMTiles file to S3: {pmtiles_file}");
+        let previous_file = format!("/tmp/{}.previous.pmtiles", self.target.file_stem());
+        self.store
+            .download_object(&previous.pmtiles_key, &previous_file)
+            .await?;
 
-        // Read the PMTiles file
-        let file_content = fs::read(pmtiles_file)
-            .await
-            .context("Failed to read PMTiles file")?;
+        let previous_path = PathBuf::from(&previous_file);
+        let geojson = synced.geojson_path.clone();
+        let changes = synced.changes_path.clone();
+        let output = PathBuf::from(output_file);
+        let settings = self.profile.vector_tile_settings();
+
+        // Tiling is CPU bound, so keep it off the async worker threads
+        let result = tokio::task::spawn_blocking(move || {
+            tiles::patch_vector_pmtiles(
+                &previous_path,
+                &geojson,
+                &changes,
+                &output,
+                &settings,
+                MAX_DIRTY_FRACTION,
+            )
+        })
+        .await
+        .context("Incremental tiler task panicked");
+        let _ = fs::remove_file(&previous_file).await;
 
-        // Record PMTiles file size
-        metrics::record_pmtiles_file_size(file_content.len() as u64);
-
-        // Compute SHA-256 hash of the file content (first 16 hex chars)
-        let hash = {
-            let mut hasher = Sha256::new();
-            hasher.update(&file_content);
-            let result = hasher.finalize();
-            format!("{result:x}")[..16].to_string()
+        let Some(patch) = result?? else {
+            return Ok(None);
         };
+        info!(
+            "Patched {} of {} tiles ({} copied) from {}",
+            patch.dirty_tiles, patch.tileset.tile_count, patch.copied_tiles, previous.pmtiles_key
+        );
+
+        let summary = self
+            .validate_pmtiles(&synced.geojson_path.to_string_lossy(), output_file)
+            .await?;
 
-        let new_s3_key = format!("activities/{}/{hash}.pmtiles", self.user_id);
+        metrics::increment_tile_build("incremental");
+        Ok(Some((
+            summary,
+            BuildInfo {
+                mode: "incremental".to_string(),
+                last_full_build_at: previous_info.last_full_build_at,
+                source_version: synced.version.clone(),
+            },
+        )))
+    }
 
-        // Upload to activities S3 bucket with hash-based key
-        match self
+    /// The currently published archive and its manifest, if both exist
+    async fn previous_build(&self) -> Result<Option<PreviousBuild>> {
+        let keys = self
+            .map_keys
+            .get(&[map_keys::PMTILES_KEY, map_keys::MANIFEST_KEY])
+            .await?;
+        let (Some(pmtiles_key), Some(manifest_key)) = (
+            keys.get(map_keys::PMTILES_KEY),
+            keys.get(map_keys::MANIFEST_KEY),
+        ) else {
+            return Ok(None);
+        };
+
+        let response = self
             .s3_client
-            .put_object()
+            .get_object()
             .bucket(&self.activities_bucket)
-            .key(&new_s3_key)
-            .body(ByteStream::from(file_content))
-            .content_type("application/vnd.pmtiles")
+            .key(manifest_key)
             .send()
             .await
-        {
-            Ok(_) => {
-                metrics::increment_s3_upload_success();
-                info!("Successfully uploaded PMTiles to activities S3: {new_s3_key}");
+            .map_err(|e| anyhow::anyhow!("Failed to download manifest {manifest_key}: {e}"))?;
+        let body = response.body.collect().await?.into_bytes();
+        let manifest: TileManifest =
+            serde_json::from_slice(&body).context("Failed to parse previous manifest")?;
+
+        Ok(Some(PreviousBuild {
+            pmtiles_key: pmtiles_key.clone(),
+            manifest,
+        }))
+    }
+
+    #[time("native_tiler_duration")]
+    async fn run_native_tiler(&self, input_file: &str, output_file: &str) -> Result<()> {
+        info!(
+            "Building tiles natively with profile {}: {input_file} -> {output_file}",
+            self.profile.name
+        );
+
+        let input = PathBuf::from(input_file);
+        let output = PathBuf::from(output_file);
+        let settings = self.profile.vector_tile_settings();
+
+        // Tiling is CPU bound, so keep it off the async worker threads
+        let result = tokio::task::spawn_blocking(move || {
+            tiles::build_vector_pmtiles(&input, &output, &settings)
+        })
+        .await
+        .context("Native tiler task panicked")?;
+
+        match result {
+            Ok(summary) => {
+                info!(
+                    "Built {} tiles from {} activities and {} markers in layers {:?} covering {:?}",
+                    summary.tile_count,
+                    summary.feature_count,
+                    summary.point_count,
+                    summary.layers,
+                    summary.bounds
+                );
+                metrics::increment_native_tiler_success();
+                Ok(())
+            }
+            Err(e) => {
+                error!("Native tiler failed: {e:#}");
+                metrics::increment_native_tiler_failure();
+                Err(e)
+            }
+        }
+    }
+
+    #[time("pmtiles_validation_duration")]
+    async fn validate_pmtiles(
+        &self,
+        geojson_file: &str,
+        pmtiles_file: &str,
+    ) -> Result<ArchiveSummary> {
+        let geojson = PathBuf::from(geojson_file);
+        let pmtiles = PathBuf::from(pmtiles_file);
+        let settings = self.profile.vector_tile_settings();
+
+        let result = tokio::task::spawn_blocking(move || {
+            tiles::validate_pmtiles(&pmtiles, &geojson, &settings)
+        })
+        .await
+        .context("PMTiles validation task panicked")?;
+
+        match result {
+            Ok(summary) => {
+                info!(
+                    "Validated PMTiles: {} tiles, zooms {}-{}, bounds {:?}, layers {:?}, {} activities",
+                    summary.tile_count,
+                    summary.min_zoom,
+                    summary.max_zoom,
+                    summary.bounds,
+                    summary.layers,
+                    summary
+                        .feature_count
+                        .map_or("unknown".to_string(), |count| count.to_string())
+                );
+                metrics::increment_pmtiles_validation_success();
+                Ok(summary)
             }
             Err(e) => {
-                metrics::increment_s3_upload_failure();
-                return Err(anyhow::anyhow!("Failed to upload PMTiles to S3: {e}"));
+                error!("Generated PMTiles failed validation, not publishing: {e:#}");
+                metrics::increment_pmtiles_validation_failure();
+                Err(e.context("Generated PMTiles failed validation"))
             }
         }
+    }
+
+    #[time("tippecanoe_execution_duration")]
+    async fn run_tippecanoe(&self, input_file: &str, output_file: &str) -> Result<()> {
+        let extra_args = tile_profile::tippecanoe_extra_args();
+        let mut args = self.profile.tippecanoe_args();
+        args.push(format!(
+            "--description={}",
+            self.profile.description(&extra_args)
+        ));
+        args.extend(extra_args);
+
+        let layer_files = self.split_tippecanoe_input(input_file).await?;
+        if layer_files.is_empty() {
+            // Nothing to split, but tippecanoe still needs an input
+            args.push(format!("--layer={}", self.profile.layer_name));
+            args.push(input_file.to_string());
+        } else {
+            for (layer, path) in &layer_files {
+                args.push(format!("--named-layer={layer}:{}", path.display()));
+            }
+        }
+        args.extend(["-o".to_string(), output_file.to_string()]);
+
+        let timeout = self.tippecanoe_timeout()?;
+        info!(
+            "Running tippecanoe with a {}s timeout: {}",
+            timeout.as_secs(),
+            args.join(" ")
+        );
+
+        // The progress indicator would flood the logs, warnings are still printed
+        let mut child = Command::new(TIPPECANOE_PATH)
+            .arg("--no-progress-indicator")
+            .args(&args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Failed to execute tippecanoe")?;
+
+        let stdout = child
+            .stdout
+            .take()
+            .map(|stdout| tokio::spawn(forward_output(stdout, "stdout")));
+        let stderr = child
+            .stderr
+            .take()
+            .map(|stderr| tokio::spawn(forward_output(stderr, "stderr")));
+
+        let status = tokio::time::timeout(timeout, child.wait()).await;
+
+        for path in layer_files.values() {
+            let _ = fs::remove_file(path).await;
+        }
 
-        // Read current pmtilesKey from the users table
-        let old_key = self.get_current_pmtiles_key().await?;
+        let status = match status {
+            Ok(status) => status.context("Failed to wait for tippecanoe")?,
+            Err(_) => {
+                if let Err(e) = child.kill().await {
+                    warn!("Failed to kill timed out tippecanoe: {e}");
+                }
+                error!("Tippecanoe timed out after {}s", timeout.as_secs());
+                metrics::increment_tippecanoe_timeout();
+                return Err(anyhow::anyhow!(
+                    "Tippecanoe timed out after {}s",
+                    timeout.as_secs()
+                ));
+            }
+        };
 
-        // Update the users table with the new pmtilesKey
-        self.update_pmtiles_key(&new_s3_key).await?;
+        // The readers finish once the pipes close with the process exit
+        if let Some(stdout) = stdout {
+            let _ = stdout.await;
+        }
+        let stderr_tail = match stderr {
+            Some(stderr) => stderr.await.unwrap_or_default(),
+            None => Vec::new(),
+        };
 
-        // Tag old S3 object for expiration if it differs from the new one
-        if let Some(old_key) = old_key
-            && old_key != new_s3_key
-        {
-            self.tag_for_expiration(&old_key).await;
+        if !status.success() {
+            error!("Tippecanoe failed with status: {status}");
+            metrics::increment_tippecanoe_failure();
+            return Err(anyhow::anyhow!(
+                "Tippecanoe failed with {status}: {}",
+                stderr_tail.join("\n")
+            ));
         }
 
+        metrics::increment_tippecanoe_success();
         Ok(())
     }
 
-    async fn get_current_pmtiles_key(&self) -> Result<Option<String>> {
-        let result = self
-            .dynamodb_client
-            .get_item()
-            .table_name(&self.users_table_name)
-            .key("id", AttributeValue::S(self.user_id.clone()))
-            .projection_expression("pmtilesKey")
-            .send()
-            .await
-            .context("Failed to read user record from DynamoDB")?;
-
-        Ok(result
-            .item
-            .and_then(|item| item.get("pmtilesKey").cloned())
-            .and_then(|v| match v {
-                AttributeValue::S(s) => Some(s),
-                _ => None,
-            }))
-    }
-
-    async fn update_pmtiles_key(&self, new_key: &str) -> Result<()> {
-        self.dynamodb_client
-            .update_item()
-            .table_name(&self.users_table_name)
-            .key("id", AttributeValue::S(self.user_id.clone()))
-            .update_expression("SET pmtilesKey = :key")
-            .expression_attribute_values(":key", AttributeValue::S(new_key.to_string()))
-            .send()
-            .await
-            .context("Failed to update pmtilesKey in DynamoDB")?;
+    /// Write the features of each layer to their own file next to the input
+    async fn split_tippecanoe_input(&self, input_file: &str) -> Result<BTreeMap<String, PathBuf>> {
+        let input = PathBuf::from(input_file);
+        let output_dir = input
+            .parent()
+            .map(PathBuf::from)
+            .unwrap_or_else(env::temp_dir);
+        let settings = self.profile.vector_tile_settings();
+
+        let layer_files = tokio::task::spawn_blocking(move || {
+            tiles::split_by_layer(&input, &settings, &output_dir)
+        })
+        .await
+        .context("GeoJSON split task panicked")??;
 
-        info!("Updated pmtilesKey for user {} to {new_key}", self.user_id);
-        Ok(())
+        info!(
+            "Split GeoJSON into layers: {}",
+            layer_files.keys().cloned().collect::<Vec<_>>().join(", ")
+        );
+        Ok(layer_files)
     }
 
-    async fn tag_for_expiration(&self, s3_key: &str) {
-        info!("Tagging old PMTiles object for expiration: {s3_key}");
+    /// Time tippecanoe may run for before the Lambda deadline, less the
+    /// safety margin needed to finish the sync
+    fn tippecanoe_timeout(&self) -> Result<Duration> {
+        let remaining = self
+            .deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+
+        match remaining.checked_sub(DEADLINE_SAFETY_MARGIN) {
+            Some(timeout) if !timeout.is_zero() => Ok(timeout),
+            _ => {
+                metrics::increment_tippecanoe_timeout();
+                Err(anyhow::anyhow!(
+                    "Only {}s left before the Lambda deadline, not starting tippecanoe",
+                    remaining.as_secs()
+                ))
+            }
+        }
+    }
 
-        let tag = aws_sdk_s3::types::Tag::builder()
-            .key("status")
-            .value("expired")
-            .build()
-            .expect("valid tag");
+    #[time("pmtiles_upload_duration")]
+    /// Upload the archive and its manifest and point the users table at them
+    async fn upload_pmtiles(
+        &self,
+        pmtiles_file: &str,
+        geojson_file: &str,
+        summary: &ArchiveSummary,
+        build: &BuildInfo,
+    ) -> Result<PublishedArchive> {
+        info!("Uploading PMTiles file to S3: {pmtiles_file}");
 
-        let tagging = aws_sdk_s3::types::Tagging::builder()
-            .tag_set(tag)
-            .build()
-            .expect("valid tagging");
+        // Hash the file in chunks so large archives are never fully in memory
+        let (content_hash, file_len) = archive_store::hash_file(pmtiles_file).await?;
 
-        if let Err(e) = self
-            .s3_client
-            .put_object_tagging()
+        // Record PMTiles file size
+        metrics::record_pmtiles_file_size(file_len);
+
+        // Keys use the first 16 hex chars of the SHA-256 hash, and the
+        // manifest shares its archive's hash
+        let key_stem = format!(
+            "activities/{}/{}",
+            self.target.storage_id(),
+            &content_hash[..16]
+        );
+        let new_s3_key = format!("{key_stem}.pmtiles");
+        let manifest_key = format!("{key_stem}.json");
+
+        // Read current pmtilesKey and manifestKey from the users table
+        let current = self
+            .map_keys
+            .get(&[map_keys::PMTILES_KEY, map_keys::MANIFEST_KEY])
+            .await?;
+        let pointer_unchanged = current.get(map_keys::PMTILES_KEY) == Some(&new_s3_key)
+            && current.get(map_keys::MANIFEST_KEY) == Some(&manifest_key);
+
+        // Keys are content-addressed, so an existing object already has these tiles
+        if self.store.object_exists(&new_s3_key).await? {
+            metrics::increment_pmtiles_upload_skipped();
+            if pointer_unchanged {
+                info!(
+                    "PMTiles unchanged for {}, skipping upload: {new_s3_key}",
+                    self.target
+                );
+            } else {
+                info!("PMTiles already in S3, reusing {new_s3_key}");
+                // It may have been tagged for expiration when it was last replaced
+                self.store.clear_expiration_tag(&new_s3_key).await?;
+            }
+        } else {
+            self.store
+                .put_archive(&new_s3_key, pmtiles_file, &content_hash, file_len)
+                .await?;
+        }
+
+        // The manifest is written before the pointer so it always exists for
+        // a published archive. It is rewritten even for an unchanged archive
+        // to record the index version the archive now matches.
+        self.publish_manifest(&manifest_key, &new_s3_key, geojson_file, summary, build)
+            .await?;
+        if pointer_unchanged {
+            return Ok(PublishedArchive {
+                key: new_s3_key,
+                manifest_key,
+                pointer_updated: false,
+            });
+        }
+
+        // Update the users table with the new pmtilesKey and manifestKey together
+        self.map_keys
+            .set(&[
+                (map_keys::PMTILES_KEY, &new_s3_key),
+                (map_keys::MANIFEST_KEY, &manifest_key),
+            ])
+            .await?;
+
+        Ok(PublishedArchive {
+            key: new_s3_key,
+            manifest_key,
+            pointer_updated: true,
+        })
+    }
+
+    /// Upload the manifest describing the archive. Overwriting an object also
+    /// drops any expiration tag it was given when it was last replaced.
+    async fn publish_manifest(
+        &self,
+        manifest_key: &str,
+        pmtiles_key: &str,
+        geojson_file: &str,
+        summary: &ArchiveSummary,
+        build: &BuildInfo,
+    ) -> Result<()> {
+        let manifest = TileManifest::build(
+            pmtiles_key,
+            Path::new(geojson_file),
+            summary,
+            &self.profile,
+            self.engine.as_str(),
+            &self.description(),
+            build,
+        )?;
+        let body = serde_json::to_vec(&manifest)?;
+
+        self.s3_client
+            .put_object()
             .bucket(&self.activities_bucket)
-            .key(s3_key)
-            .tagging(tagging)
+            .key(manifest_key)
+            .body(ByteStream::from(body))
+            .content_type("application/json")
+            .cache_control(MANIFEST_CACHE_CONTROL)
             .send()
             .await
-        {
-            error!("Failed to tag old PMTiles object {s3_key} for expiration: {e}");
+            .map_err(|e| anyhow::anyhow!("Failed to upload manifest {manifest_key}: {e}"))?;
+
+        info!(
+            "Uploaded manifest for {} activities to {manifest_key}",
+            manifest.activity_count
+        );
+        Ok(())
+    }
+
+    /// Tileset description for the engine in use; tippecanoe also applies
+    /// TIPPECANOE_ARGS
+    fn description(&self) -> String {
+        match self.engine {
+            TileEngine::Native => self.profile.description(&[]),
+            TileEngine::Tippecanoe => self
+                .profile
+                .description(&tile_profile::tippecanoe_extra_args()),
+        }
+    }
+
+    /// Reclaim archives and manifests the pointers no longer reference. A
+    /// failure here leaves the new map published, and the next sync retries.
+    async fn collect_garbage(&self, current_keys: &[&str]) {
+        let result = match PmtilesGc::new(
+            self.s3_client.clone(),
+            &self.activities_bucket,
+            &format!("activities/{}/", self.target.storage_id()),
+        ) {
+            Ok(gc) => gc.reconcile(current_keys).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            error!(
+                "PMTiles garbage collection failed for {}: {e:#}",
+                self.target
+            );
+            metrics::increment_pmtiles_gc_failures(1);
         }
     }
 }
+
+/// Log each line of a tippecanoe output stream as it is written, returning
+/// the last few lines for error reporting
+async fn forward_output(stream: impl AsyncRead + Unpin, name: &'static str) -> Vec<String> {
+    let mut lines = BufReader::new(stream).lines();
+    let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => {
+                info!("tippecanoe {name}: {line}");
+                if tail.len() == STDERR_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(line);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Failed to read tippecanoe {name}: {e}");
+                break;
+            }
+        }
+    }
+
+    tail.into()
+}
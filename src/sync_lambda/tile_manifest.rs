@@ -0,0 +1,255 @@
+use crate::fit_converter;
+use crate::tile_profile::TileProfile;
+use crate::tiles::ArchiveSummary;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// Viewport the suggested zoom is fitted to, in CSS pixels
+const VIEWPORT_SIZE: [f64; 2] = [1024.0, 768.0];
+
+/// Size of a tile in the map client, in CSS pixels
+const TILE_SIZE: f64 = 512.0;
+
+/// Describes a published PMTiles archive, so clients can frame the map and
+/// show a summary without opening the archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TileManifest {
+    pub pmtiles_key: String,
+    /// min lon, min lat, max lon, max lat in degrees
+    pub bounds: [f64; 4],
+    /// lon, lat in degrees
+    pub center: [f64; 2],
+    /// Zoom at which the bounds fit a typical viewport
+    pub suggested_zoom: u8,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    pub tile_count: u64,
+    pub layers: Vec<String>,
+    pub activity_count: usize,
+    /// Activity counts keyed by intervals.icu activity type
+    pub activity_counts: BTreeMap<String, usize>,
+    /// Local start date of the earliest activity
+    pub first_activity_date: Option<String>,
+    /// Local start date of the latest activity
+    pub last_activity_date: Option<String>,
+    pub generated_at: String,
+    pub settings: ManifestSettings,
+    /// Missing from manifests written before incremental builds
+    #[serde(default)]
+    pub build: Option<BuildInfo>,
+}
+
+/// Options the archive was built with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestSettings {
+    pub engine: String,
+    pub profile: String,
+    /// Effective tile options, as stored in the archive's description
+    pub description: String,
+    pub max_gap_meters: f64,
+    pub generator_version: String,
+}
+
+/// How the archive was built, which decides whether the next build may
+/// patch it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    /// "full" or "incremental"
+    pub mode: String,
+    /// When the archive was last rebuilt from scratch; patches carry it forward
+    pub last_full_build_at: String,
+    /// Activity index version the archive was built from
+    pub source_version: String,
+}
+
+/// Activity totals taken from the GeoJSON an archive was built from
+#[derive(Debug, Default, Clone)]
+struct ActivityStats {
+    count: usize,
+    counts_by_type: BTreeMap<String, usize>,
+    first_date: Option<String>,
+    last_date: Option<String>,
+}
+
+/// Only the fields the manifest needs, so the coordinates are skipped
+#[derive(Deserialize)]
+struct CollectionLine {
+    features: Vec<FeatureLine>,
+}
+
+#[derive(Deserialize)]
+struct FeatureLine {
+    #[serde(default)]
+    properties: Option<ActivityProperties>,
+}
+
+#[derive(Deserialize)]
+struct ActivityProperties {
+    #[serde(rename = "type")]
+    activity_type: Option<String>,
+    date: Option<String>,
+}
+
+impl TileManifest {
+    /// Build the manifest for a validated archive from the GeoJSON it was built from
+    pub fn build(
+        pmtiles_key: &str,
+        geojson_path: &Path,
+        summary: &ArchiveSummary,
+        profile: &TileProfile,
+        engine: &str,
+        description: &str,
+        build: &BuildInfo,
+    ) -> Result<Self> {
+        let stats = activity_stats(geojson_path)?;
+        let [min_lon, min_lat, max_lon, max_lat] = summary.bounds;
+
+        Ok(Self {
+            pmtiles_key: pmtiles_key.to_string(),
+            bounds: summary.bounds,
+            center: [(min_lon + max_lon) / 2.0, (min_lat + max_lat) / 2.0],
+            suggested_zoom: suggested_zoom(summary.bounds, summary.min_zoom, summary.max_zoom),
+            min_zoom: summary.min_zoom,
+            max_zoom: summary.max_zoom,
+            tile_count: summary.tile_count,
+            layers: summary.layers.clone(),
+            activity_count: stats.count,
+            activity_counts: stats.counts_by_type,
+            first_activity_date: stats.first_date,
+            last_activity_date: stats.last_date,
+            generated_at: Utc::now().to_rfc3339(),
+            settings: ManifestSettings {
+                engine: engine.to_string(),
+                profile: profile.name.clone(),
+                description: description.to_string(),
+                max_gap_meters: fit_converter::MAX_GAP_METERS,
+                generator_version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+            build: Some(build.clone()),
+        })
+    }
+}
+
+/// Count activities by type and find their date range. Each line is one
+/// activity's FeatureCollection, with the line feature first.
+fn activity_stats(geojson_path: &Path) -> Result<ActivityStats> {
+    let file = File::open(geojson_path)
+        .with_context(|| format!("Failed to open {}", geojson_path.display()))?;
+
+    let mut stats = ActivityStats::default();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let collection: CollectionLine =
+            serde_json::from_str(&line).context("Failed to parse activity GeoJSON")?;
+        stats.count += 1;
+
+        let Some(properties) = collection
+            .features
+            .into_iter()
+            .next()
+            .and_then(|feature| feature.properties)
+        else {
+            continue;
+        };
+
+        let activity_type = properties
+            .activity_type
+            .unwrap_or_else(|| "Unknown".to_string());
+        *stats.counts_by_type.entry(activity_type).or_default() += 1;
+
+        // Local ISO 8601 dates sort chronologically as strings
+        if let Some(date) = properties.date {
+            if stats.first_date.as_ref().is_none_or(|first| date < *first) {
+                stats.first_date = Some(date.clone());
+            }
+            if stats.last_date.as_ref().is_none_or(|last| date > *last) {
+                stats.last_date = Some(date);
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Highest zoom at which the bounds fit the viewport, within the archive's zoom range
+fn suggested_zoom(bounds: [f64; 4], min_zoom: u8, max_zoom: u8) -> u8 {
+    let [min_lon, min_lat, max_lon, max_lat] = bounds;
+    // Web Mercator x and y as fractions of the world
+    let x_span = (max_lon - min_lon) / 360.0;
+    let y_span = (mercator_y(min_lat) - mercator_y(max_lat)).abs();
+
+    let fit = |span: f64, viewport: f64| {
+        if span > 0.0 {
+            (viewport / (TILE_SIZE * span)).log2()
+        } else {
+            f64::INFINITY
+        }
+    };
+    let zoom = fit(x_span, VIEWPORT_SIZE[0]).min(fit(y_span, VIEWPORT_SIZE[1]));
+
+    if zoom.is_finite() {
+        (zoom.floor().max(0.0) as u8).clamp(min_zoom, max_zoom)
+    } else {
+        // A single point, or no activities at all
+        max_zoom
+    }
+}
+
+fn mercator_y(lat: f64) -> f64 {
+    let lat = lat.clamp(-85.051_128, 85.051_128).to_radians();
+    (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activity_stats() {
+        let dir = tempdir::TempDir::new("tile_manifest").unwrap();
+        let path = dir.path().join("activities.geojson");
+        std::fs::write(
+            &path,
+            concat!(
+                r#"{"type":"FeatureCollection","features":[{"type":"Feature","geometry":{"type":"LineString","coordinates":[[0,0],[1,1]]},"properties":{"type":"Ride","date":"2024-03-01T08:00:00"}}]}"#,
+                "\n",
+                r#"{"type":"FeatureCollection","features":[{"type":"Feature","geometry":{"type":"LineString","coordinates":[[0,0],[1,1]]},"properties":{"type":"Run","date":"2023-11-05T07:30:00"}}]}"#,
+                "\n",
+                r#"{"type":"FeatureCollection","features":[{"type":"Feature","geometry":{"type":"LineString","coordinates":[[0,0],[1,1]]},"properties":{"type":"Ride","date":"2024-06-10T18:15:00"}}]}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+
+        let stats = activity_stats(&path).unwrap();
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.counts_by_type["Ride"], 2);
+        assert_eq!(stats.counts_by_type["Run"], 1);
+        assert_eq!(stats.first_date.as_deref(), Some("2023-11-05T07:30:00"));
+        assert_eq!(stats.last_date.as_deref(), Some("2024-06-10T18:15:00"));
+    }
+
+    #[test]
+    fn test_suggested_zoom() {
+        // The whole world fits at zoom 0 or 1
+        assert!(suggested_zoom([-180.0, -85.0, 180.0, 85.0], 0, 14) <= 1);
+        // A city-sized area is framed much closer
+        let city = suggested_zoom([-0.2, 51.45, 0.0, 51.55], 0, 14);
+        assert!((10..=12).contains(&city), "{city}");
+        // Clamped to the archive's zoom range
+        assert_eq!(suggested_zoom([-0.2, 51.45, 0.0, 51.55], 0, 8), 8);
+        assert_eq!(suggested_zoom([0.0, 0.0, 0.0, 0.0], 0, 14), 14);
+    }
+}
@@ -0,0 +1,286 @@
+use crate::tiles::VectorTileSettings;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+
+pub const DEFAULT_PROFILE: &str = "full";
+
+/// Tile build options shared by both tile engines. Profiles are selected per
+/// sync, e.g. a lighter profile for free users.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct TileProfile {
+    #[serde(skip)]
+    pub name: String,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// Rate at which lines are dropped below the maximum zoom; None keeps all
+    pub drop_rate: Option<f64>,
+    /// Simplification tolerance in tile units
+    pub simplification: f64,
+    /// Attributes to keep; empty keeps everything not excluded
+    pub include_attributes: Vec<String>,
+    pub exclude_attributes: Vec<String>,
+    /// Layer for activity types that are not in any category
+    pub layer_name: String,
+    /// intervals.icu activity types for each category; every category gets
+    /// its own tile layer
+    pub categories: BTreeMap<String, Vec<String>>,
+    /// Layer for start and finish markers
+    pub point_layer_name: String,
+    /// Distance in pixels within which markers are clustered; 0 disables it
+    pub cluster_distance: u32,
+    /// Highest zoom at which markers are clustered
+    pub cluster_max_zoom: u8,
+}
+
+impl Default for TileProfile {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_PROFILE.to_string(),
+            min_zoom: 0,
+            max_zoom: 14,
+            drop_rate: None,
+            simplification: 1.0,
+            include_attributes: Vec::new(),
+            exclude_attributes: Vec::new(),
+            layer_name: "activities".to_string(),
+            categories: default_categories(),
+            point_layer_name: "endpoints".to_string(),
+            cluster_distance: 10,
+            cluster_max_zoom: 10,
+        }
+    }
+}
+
+fn default_categories() -> BTreeMap<String, Vec<String>> {
+    let categories: [(&str, &[&str]); 5] = [
+        (
+            "ride",
+            &[
+                "Ride",
+                "VirtualRide",
+                "MountainBikeRide",
+                "GravelRide",
+                "EBikeRide",
+                "EMountainBikeRide",
+                "TrackRide",
+                "Velomobile",
+                "Handcycle",
+            ],
+        ),
+        ("run", &["Run", "TrailRun", "VirtualRun"]),
+        ("walk", &["Walk", "Hike"]),
+        (
+            "water",
+            &[
+                "Swim",
+                "OpenWaterSwim",
+                "Rowing",
+                "Kayaking",
+                "Canoeing",
+                "StandUpPaddling",
+                "Surfing",
+                "Kitesurf",
+                "Windsurf",
+                "Sail",
+            ],
+        ),
+        (
+            "winter",
+            &[
+                "AlpineSki",
+                "BackcountrySki",
+                "NordicSki",
+                "Snowboard",
+                "Snowshoe",
+                "IceSkate",
+            ],
+        ),
+    ];
+
+    categories
+        .into_iter()
+        .map(|(category, types)| {
+            (
+                category.to_string(),
+                types.iter().map(|t| t.to_string()).collect(),
+            )
+        })
+        .collect()
+}
+
+impl TileProfile {
+    fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "full" => Some(Self::default()),
+            "lite" => Some(Self {
+                name: "lite".to_string(),
+                max_zoom: 12,
+                drop_rate: Some(2.5),
+                simplification: 4.0,
+                include_attributes: ["id", "name", "date", "type", "marker"]
+                    .map(String::from)
+                    .to_vec(),
+                ..Self::default()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Look up a profile by name, defaulting to TILE_PROFILE or "full".
+    /// Fields set for a profile in the TILE_PROFILES JSON object override
+    /// those of the built-in "full" or "lite" profile of the same name, or
+    /// of "full" for new profiles.
+    pub fn resolve(name: Option<&str>) -> Result<Self> {
+        let default_name = env::var("TILE_PROFILE").unwrap_or_else(|_| DEFAULT_PROFILE.to_string());
+        let name = name.unwrap_or(&default_name);
+
+        let mut configured: HashMap<String, serde_json::Map<String, serde_json::Value>> =
+            match env::var("TILE_PROFILES") {
+                Ok(json) => serde_json::from_str(&json).context("Failed to parse TILE_PROFILES")?,
+                Err(_) => HashMap::new(),
+            };
+
+        let mut profile = match configured.remove(name) {
+            Some(fields) => Self::configure(name, fields)?,
+            None => Self::builtin(name)
+                .ok_or_else(|| anyhow::anyhow!("Unknown tile profile: {name}"))?,
+        };
+        profile.name = name.to_string();
+
+        anyhow::ensure!(
+            profile.min_zoom <= profile.max_zoom && profile.max_zoom <= 22,
+            "Invalid zoom range {}-{} in tile profile {name}",
+            profile.min_zoom,
+            profile.max_zoom
+        );
+
+        // Layer names double as file names for tippecanoe's per-layer inputs
+        for layer in profile
+            .categories
+            .keys()
+            .chain([&profile.layer_name, &profile.point_layer_name])
+        {
+            anyhow::ensure!(
+                !layer.is_empty()
+                    && layer
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+                "Invalid layer name {layer:?} in tile profile {name}"
+            );
+        }
+        Ok(profile)
+    }
+
+    /// Apply configured fields over the built-in profile of the same name
+    fn configure(name: &str, fields: serde_json::Map<String, serde_json::Value>) -> Result<Self> {
+        let base = Self::builtin(name).unwrap_or_default();
+        let mut merged = match serde_json::to_value(base)? {
+            serde_json::Value::Object(merged) => merged,
+            _ => unreachable!("profiles serialize to objects"),
+        };
+        merged.extend(fields);
+        serde_json::from_value(serde_json::Value::Object(merged))
+            .with_context(|| format!("Invalid tile profile {name} in TILE_PROFILES"))
+    }
+
+    /// Tippecanoe options equivalent to this profile, excluding the per-layer
+    /// inputs and the output
+    pub fn tippecanoe_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "--preserve-input-order".to_string(),
+            "--force".to_string(),
+            format!("--minimum-zoom={}", self.min_zoom),
+            format!("--maximum-zoom={}", self.max_zoom),
+            format!("--simplification={}", self.simplification),
+        ];
+        if self.cluster_distance > 0 {
+            args.push(format!("--cluster-distance={}", self.cluster_distance));
+            args.push(format!("--cluster-maxzoom={}", self.cluster_max_zoom));
+        }
+        if let Some(drop_rate) = self.drop_rate {
+            args.push(format!("--drop-rate={drop_rate}"));
+            args.push("--drop-lines".to_string());
+        }
+        for attribute in &self.include_attributes {
+            args.push(format!("--include={attribute}"));
+        }
+        for attribute in &self.exclude_attributes {
+            args.push(format!("--exclude={attribute}"));
+        }
+        args
+    }
+
+    /// Summary of the effective options, stored as the tileset description
+    pub fn description(&self, extra_args: &[String]) -> String {
+        let mut args = self.tippecanoe_args();
+        args.extend_from_slice(extra_args);
+        format!("profile={}; args={}", self.name, args.join(" "))
+    }
+
+    pub fn vector_tile_settings(&self) -> VectorTileSettings {
+        VectorTileSettings {
+            min_zoom: self.min_zoom,
+            max_zoom: self.max_zoom,
+            layer_name: self.layer_name.clone(),
+            layers_by_type: self.layers_by_type(),
+            point_layer_name: self.point_layer_name.clone(),
+            cluster_distance: f64::from(self.cluster_distance),
+            cluster_max_zoom: self.cluster_max_zoom,
+            simplification: self.simplification,
+            drop_rate: self.drop_rate,
+            include_attributes: self.include_attributes.clone(),
+            exclude_attributes: self.exclude_attributes.clone(),
+            description: self.description(&[]),
+            ..VectorTileSettings::default()
+        }
+    }
+
+    fn layers_by_type(&self) -> HashMap<String, String> {
+        self.categories
+            .iter()
+            .flat_map(|(category, types)| {
+                types
+                    .iter()
+                    .map(move |activity_type| (activity_type.clone(), category.clone()))
+            })
+            .collect()
+    }
+}
+
+/// Extra tippecanoe arguments from TIPPECANOE_ARGS, appended after the
+/// profile's own so they can override it
+pub fn tippecanoe_extra_args() -> Vec<String> {
+    env::var("TIPPECANOE_ARGS")
+        .map(|args| args.split_whitespace().map(String::from).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_configure_overrides_builtin() {
+        let fields = serde_json::json!({"maxZoom": 11, "dropRate": null});
+        let serde_json::Value::Object(fields) = fields else {
+            unreachable!()
+        };
+        let lite = TileProfile::configure("lite", fields).unwrap();
+        assert_eq!(lite.max_zoom, 11);
+        assert_eq!(lite.drop_rate, None);
+        // Fields that were not configured keep lite's values
+        assert_eq!(lite.simplification, 4.0);
+        assert!(lite.include_attributes.contains(&"marker".to_string()));
+
+        let fields = serde_json::json!({"minZoom": 2});
+        let serde_json::Value::Object(fields) = fields else {
+            unreachable!()
+        };
+        let custom = TileProfile::configure("custom", fields).unwrap();
+        assert_eq!(custom.min_zoom, 2);
+        assert_eq!(custom.max_zoom, TileProfile::default().max_zoom);
+    }
+}
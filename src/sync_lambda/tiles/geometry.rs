@@ -0,0 +1,228 @@
+use geo::{Coord, LineString, Simplify};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+/// Web Mercator latitude limit
+const MAX_LATITUDE: f64 = 85.051_128_78;
+
+/// Position in Web Mercator "world" space, where the whole map spans [0, 1]
+pub type WorldPoint = [f64; 2];
+
+pub fn lon_lat_to_world(lon: f64, lat: f64) -> WorldPoint {
+    let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    let x = (lon + 180.0) / 360.0;
+    let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0;
+    [x.clamp(0.0, 1.0), y.clamp(0.0, 1.0)]
+}
+
+/// Douglas-Peucker simplification with a tolerance in world units
+pub fn simplify(line: &[WorldPoint], epsilon: f64) -> Vec<WorldPoint> {
+    if line.len() <= 2 || epsilon <= 0.0 {
+        return line.to_vec();
+    }
+
+    let line_string: LineString<f64> = line.iter().map(|&[x, y]| Coord { x, y }).collect();
+    line_string
+        .simplify(epsilon)
+        .coords()
+        .map(|c| [c.x, c.y])
+        .collect()
+}
+
+/// Split a line into the tiles it crosses at `zoom`, clipping each piece to
+/// the tile extended by `buffer` (a fraction of the tile size)
+pub fn clip_line_to_tiles(
+    line: &[WorldPoint],
+    zoom: u8,
+    buffer: f64,
+) -> HashMap<(u32, u32), Vec<Vec<WorldPoint>>> {
+    let scale = f64::from(1u32 << zoom);
+    let max_index = (1u32 << zoom) - 1;
+    let tile_index = |v: f64| (v.floor().max(0.0) as u32).min(max_index);
+
+    let mut tiles: HashMap<(u32, u32), Vec<Vec<WorldPoint>>> = HashMap::new();
+
+    for segment in line.windows(2) {
+        // Work in tile units at this zoom
+        let a = [segment[0][0] * scale, segment[0][1] * scale];
+        let b = [segment[1][0] * scale, segment[1][1] * scale];
+
+        let (x0, x1) = (
+            tile_index(a[0].min(b[0]) - buffer),
+            tile_index(a[0].max(b[0]) + buffer),
+        );
+        let (y0, y1) = (
+            tile_index(a[1].min(b[1]) - buffer),
+            tile_index(a[1].max(b[1]) + buffer),
+        );
+
+        for tx in x0..=x1 {
+            for ty in y0..=y1 {
+                let min = [f64::from(tx) - buffer, f64::from(ty) - buffer];
+                let max = [f64::from(tx) + 1.0 + buffer, f64::from(ty) + 1.0 + buffer];
+                let Some((start, end)) = clip_segment(a, b, min, max) else {
+                    continue;
+                };
+
+                let start = [start[0] / scale, start[1] / scale];
+                let end = [end[0] / scale, end[1] / scale];
+                let parts = tiles.entry((tx, ty)).or_default();
+                match parts.last_mut() {
+                    // Continue the previous piece when this segment picks up where it left off
+                    Some(part) if part.last() == Some(&start) => part.push(end),
+                    _ => parts.push(vec![start, end]),
+                }
+            }
+        }
+    }
+
+    tiles
+}
+
+/// Convert a world-space line into integer coordinates within tile (x, y),
+/// dropping points that round onto the previous one
+pub fn to_tile_coords(line: &[WorldPoint], zoom: u8, x: u32, y: u32, extent: u32) -> Vec<[i32; 2]> {
+    let scale = f64::from(1u32 << zoom);
+    let extent = f64::from(extent);
+
+    let mut points: Vec<[i32; 2]> = Vec::with_capacity(line.len());
+    for &[wx, wy] in line {
+        let point = [
+            ((wx * scale - f64::from(x)) * extent).round() as i32,
+            ((wy * scale - f64::from(y)) * extent).round() as i32,
+        ];
+        if points.last() != Some(&point) {
+            points.push(point);
+        }
+    }
+    points
+}
+
+/// Group points closer than `distance` (a fraction of the tile size) at
+/// `zoom` on a grid, returning each cluster's centroid and the indices of its
+/// members in input order
+pub fn cluster_points(
+    points: &[WorldPoint],
+    zoom: u8,
+    distance: f64,
+) -> Vec<(WorldPoint, Vec<usize>)> {
+    let cell = distance / f64::from(1u32 << zoom);
+    if cell <= 0.0 {
+        return points
+            .iter()
+            .enumerate()
+            .map(|(i, &p)| (p, vec![i]))
+            .collect();
+    }
+
+    let mut cluster_index: HashMap<(i64, i64), usize> = HashMap::new();
+    let mut clusters: Vec<([f64; 2], Vec<usize>)> = Vec::new();
+
+    for (i, &[x, y]) in points.iter().enumerate() {
+        let key = ((x / cell).floor() as i64, (y / cell).floor() as i64);
+        let index = *cluster_index.entry(key).or_insert_with(|| {
+            clusters.push(([0.0, 0.0], Vec::new()));
+            clusters.len() - 1
+        });
+        let (sum, members) = &mut clusters[index];
+        sum[0] += x;
+        sum[1] += y;
+        members.push(i);
+    }
+
+    clusters
+        .into_iter()
+        .map(|(sum, members)| {
+            let count = members.len() as f64;
+            ([sum[0] / count, sum[1] / count], members)
+        })
+        .collect()
+}
+
+/// Tiles at `zoom` overlapped by the clustering cell that contains `point`,
+/// i.e. every tile the point's cluster could be placed in
+pub fn cluster_cell_tiles(point: WorldPoint, zoom: u8, distance: f64) -> Vec<(u32, u32)> {
+    let scale = f64::from(1u32 << zoom);
+    let max_index = (1u32 << zoom) - 1;
+    let tile_index = |v: f64| ((v * scale).floor().max(0.0) as u32).min(max_index);
+
+    let cell = distance / scale;
+    if cell <= 0.0 {
+        return vec![(tile_index(point[0]), tile_index(point[1]))];
+    }
+
+    let min = [
+        (point[0] / cell).floor() * cell,
+        (point[1] / cell).floor() * cell,
+    ];
+    let mut tiles = Vec::new();
+    for x in tile_index(min[0])..=tile_index(min[0] + cell) {
+        for y in tile_index(min[1])..=tile_index(min[1] + cell) {
+            tiles.push((x, y));
+        }
+    }
+    tiles
+}
+
+/// Liang-Barsky clipping of segment a-b to the rectangle [min, max]
+fn clip_segment(
+    a: [f64; 2],
+    b: [f64; 2],
+    min: [f64; 2],
+    max: [f64; 2],
+) -> Option<([f64; 2], [f64; 2])> {
+    let delta = [b[0] - a[0], b[1] - a[1]];
+    let mut t0: f64 = 0.0;
+    let mut t1: f64 = 1.0;
+
+    for axis in 0..2 {
+        for (p, q) in [
+            (-delta[axis], a[axis] - min[axis]),
+            (delta[axis], max[axis] - a[axis]),
+        ] {
+            if p == 0.0 {
+                if q < 0.0 {
+                    return None;
+                }
+            } else {
+                let r = q / p;
+                if p < 0.0 {
+                    t0 = t0.max(r);
+                } else {
+                    t1 = t1.min(r);
+                }
+            }
+        }
+    }
+
+    if t0 > t1 {
+        return None;
+    }
+
+    // Keep unclipped endpoints bit-identical so consecutive segments join up
+    let point_at = |t: f64| match t {
+        0.0 => a,
+        1.0 => b,
+        _ => [a[0] + t * delta[0], a[1] + t * delta[1]],
+    };
+    Some((point_at(t0), point_at(t1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cluster_points() {
+        let points = [[0.1, 0.1], [0.100_001, 0.1], [0.9, 0.9]];
+
+        // At zoom 0 a 10px cluster distance covers the two nearby points
+        let clusters = cluster_points(&points, 0, 10.0 / 256.0);
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].1, vec![0, 1]);
+        assert_eq!(clusters[1].1, vec![2]);
+
+        // Disabled clustering keeps every point
+        assert_eq!(cluster_points(&points, 0, 0.0).len(), 3);
+    }
+}
@@ -0,0 +1,901 @@
+use anyhow::{Context, Result};
+use geojson::{Feature, GeoJson, Value};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+mod geometry;
+mod mvt;
+pub mod pmtiles;
+mod raster;
+mod thumbnail;
+mod validate;
+
+use geometry::{clip_line_to_tiles, cluster_cell_tiles, cluster_points, simplify, to_tile_coords};
+use mvt::{PropertyValue, TileBuilder};
+use pmtiles::{PmtilesReader, PmtilesWriter, TilesetInfo};
+
+/// Written to the metadata so archives from other tools are never patched
+const GENERATOR: &str = concat!("ridelines-drivetrain ", env!("CARGO_PKG_VERSION"));
+
+pub use geometry::{WorldPoint, lon_lat_to_world};
+pub use raster::{HeatmapSettings, HeatmapSummary, RasterFormat, build_heatmap_pmtiles};
+pub use thumbnail::{Thumbnail, ThumbnailContent, ThumbnailSettings, render_thumbnail};
+pub use validate::{ArchiveSummary, validate_pmtiles};
+
+#[derive(Debug, Clone)]
+pub struct VectorTileSettings {
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// Layer for features whose activity type has no entry in `layers_by_type`
+    pub layer_name: String,
+    /// Layer for each activity type, matched against the `type` property
+    pub layers_by_type: HashMap<String, String>,
+    /// Layer for point features such as start and finish markers
+    pub point_layer_name: String,
+    /// Points closer than this many 256px screen pixels are merged into one
+    /// feature with a `point_count` property; 0 disables clustering
+    pub cluster_distance: f64,
+    /// Highest zoom at which points are clustered
+    pub cluster_max_zoom: u8,
+    /// Tile coordinate resolution
+    pub extent: u32,
+    /// Extra area clipped into each tile, in 256px screen pixels
+    pub buffer_pixels: f64,
+    /// Douglas-Peucker tolerance, in tile coordinate units
+    pub simplification: f64,
+    /// Each zoom below the maximum keeps 1/drop_rate of the features of the
+    /// zoom above it; None keeps every feature at every zoom
+    pub drop_rate: Option<f64>,
+    /// Properties to keep; empty keeps everything not excluded
+    pub include_attributes: Vec<String>,
+    pub exclude_attributes: Vec<String>,
+    /// Stored as the tileset description in the PMTiles metadata
+    pub description: String,
+}
+
+impl Default for VectorTileSettings {
+    fn default() -> Self {
+        // Mirrors tippecanoe's defaults
+        Self {
+            min_zoom: 0,
+            max_zoom: 14,
+            layer_name: "activities".to_string(),
+            layers_by_type: HashMap::new(),
+            point_layer_name: "endpoints".to_string(),
+            cluster_distance: 0.0,
+            cluster_max_zoom: 0,
+            extent: 4096,
+            buffer_pixels: 5.0,
+            simplification: 1.0,
+            drop_rate: None,
+            include_attributes: Vec::new(),
+            exclude_attributes: Vec::new(),
+            description: String::new(),
+        }
+    }
+}
+
+impl VectorTileSettings {
+    /// Layer a feature belongs in: points go in the point layer, lines in
+    /// the layer for their activity type
+    pub fn layer_for(&self, feature: &Feature) -> &str {
+        if let Some(Value::Point(_)) = feature.geometry.as_ref().map(|g| &g.value) {
+            return &self.point_layer_name;
+        }
+
+        feature
+            .property("type")
+            .and_then(|activity_type| activity_type.as_str())
+            .and_then(|activity_type| self.layers_by_type.get(activity_type))
+            .unwrap_or(&self.layer_name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TilesetSummary {
+    pub tile_count: usize,
+    /// Number of line features, i.e. activities
+    pub feature_count: usize,
+    /// Number of point features before clustering
+    pub point_count: usize,
+    /// min lon, min lat, max lon, max lat in degrees
+    pub bounds: [f64; 4],
+    /// Names of the layers that have features
+    pub layers: Vec<String>,
+}
+
+/// Outcome of patching a previous archive
+#[derive(Debug, Clone)]
+pub struct PatchSummary {
+    pub tileset: TilesetSummary,
+    /// Tiles touched by the changed activities, regenerated or removed
+    pub dirty_tiles: usize,
+    /// Tiles copied unchanged from the previous archive
+    pub copied_tiles: usize,
+}
+
+struct SourceFeature {
+    id: u64,
+    layer: String,
+    geometry: SourceGeometry,
+    /// min x, min y, max x, max y in world space
+    world_bounds: [f64; 4],
+    properties: Vec<(String, PropertyValue)>,
+}
+
+enum SourceGeometry {
+    Lines(Vec<Vec<WorldPoint>>),
+    Point(WorldPoint),
+}
+
+/// Build a PMTiles archive of MVT tiles from line-delimited GeoJSON, clipping
+/// and simplifying features per zoom like tippecanoe does
+pub fn build_vector_pmtiles(
+    geojson_path: &Path,
+    output_path: &Path,
+    settings: &VectorTileSettings,
+) -> Result<TilesetSummary> {
+    anyhow::ensure!(
+        settings.min_zoom <= settings.max_zoom && settings.max_zoom <= 22,
+        "Invalid zoom range {}-{}",
+        settings.min_zoom,
+        settings.max_zoom
+    );
+
+    let (features, bounds) = read_features(geojson_path, settings)?;
+    let layer_stats = collect_layer_stats(&features, settings);
+    let points = PointSet::new(&features);
+
+    let mut writer = PmtilesWriter::create(output_path)?;
+    for zoom in settings.min_zoom..=settings.max_zoom {
+        let tiles = render_zoom(&features, &points, zoom, settings, None);
+        debug!("Zoom {}: {} tiles", zoom, tiles.len());
+        for (tile_id, tile) in tiles {
+            writer.add_tile(tile_id, &pmtiles::gzip(&tile.encode())?)?;
+        }
+    }
+
+    let tile_count = writer.tile_count();
+    writer.finish(
+        &tileset_info(settings, bounds),
+        &tileset_metadata(settings, &layer_stats),
+    )?;
+
+    Ok(TilesetSummary {
+        tile_count,
+        feature_count: features.len() - points.features.len(),
+        point_count: points.features.len(),
+        bounds,
+        layers: layer_stats.into_keys().collect(),
+    })
+}
+
+/// Update a previous archive built with the same settings: only the tiles
+/// touched by the features in `changes_path`, the old and new versions of
+/// every added, changed or deleted activity, are regenerated from the full
+/// GeoJSON, and all other tiles are copied. Returns None when the previous
+/// archive cannot be patched or so much changed that a full build is cheaper.
+pub fn patch_vector_pmtiles(
+    previous_path: &Path,
+    geojson_path: &Path,
+    changes_path: &Path,
+    output_path: &Path,
+    settings: &VectorTileSettings,
+    max_dirty_fraction: f64,
+) -> Result<Option<PatchSummary>> {
+    let mut previous = PmtilesReader::open(previous_path)?;
+    let header = previous.header().clone();
+    let metadata = previous.metadata()?;
+
+    if header.tile_type != pmtiles::TILE_TYPE_MVT
+        || header.tile_compression != pmtiles::COMPRESSION_GZIP
+        || (header.min_zoom, header.max_zoom) != (settings.min_zoom, settings.max_zoom)
+        || metadata["generator"].as_str() != Some(GENERATOR)
+        || metadata["description"].as_str() != Some(settings.description.as_str())
+    {
+        info!("Previous archive was built differently, it cannot be patched");
+        return Ok(None);
+    }
+
+    let entries = previous.entries()?;
+    let previous_tiles: u64 = entries.iter().map(|e| u64::from(e.run_length)).sum();
+
+    let (changed, _) = read_features(changes_path, settings)?;
+    let dirty = touched_tiles(&changed, settings);
+    let dirty_count: usize = dirty.values().map(HashSet::len).sum();
+    if dirty_count as f64 > previous_tiles as f64 * max_dirty_fraction {
+        info!(
+            "{dirty_count} of {previous_tiles} tiles changed, a full build is cheaper than patching"
+        );
+        return Ok(None);
+    }
+
+    let (features, bounds) = read_features(geojson_path, settings)?;
+    let layer_stats = collect_layer_stats(&features, settings);
+    let points = PointSet::new(&features);
+
+    // Regenerated tiles are few enough to keep in memory until merged
+    let mut dirty_ids = HashSet::with_capacity(dirty_count);
+    let mut fresh = BTreeMap::new();
+    for (&zoom, tiles) in &dirty {
+        dirty_ids.extend(
+            tiles
+                .iter()
+                .map(|&(x, y)| pmtiles::zxy_to_tile_id(zoom, x, y)),
+        );
+        for (tile_id, tile) in render_zoom(&features, &points, zoom, settings, Some(tiles)) {
+            fresh.insert(tile_id, pmtiles::gzip(&tile.encode())?);
+        }
+    }
+
+    // Merge both sorted sequences, dropping dirty tiles that are now empty
+    let mut writer = PmtilesWriter::create(output_path)?;
+    let mut fresh = fresh.into_iter().peekable();
+    let mut copied_tiles = 0;
+    for entry in &entries {
+        let data = previous.read_tile(entry)?;
+        for tile_id in entry.tile_id..entry.tile_id + u64::from(entry.run_length) {
+            while let Some((fresh_id, tile)) = fresh.next_if(|(id, _)| *id <= tile_id) {
+                writer.add_tile(fresh_id, &tile)?;
+            }
+            if !dirty_ids.contains(&tile_id) {
+                writer.add_tile(tile_id, &data)?;
+                copied_tiles += 1;
+            }
+        }
+    }
+    for (tile_id, tile) in fresh {
+        writer.add_tile(tile_id, &tile)?;
+    }
+
+    let tile_count = writer.tile_count();
+    writer.finish(
+        &tileset_info(settings, bounds),
+        &tileset_metadata(settings, &layer_stats),
+    )?;
+
+    Ok(Some(PatchSummary {
+        tileset: TilesetSummary {
+            tile_count,
+            feature_count: features.len() - points.features.len(),
+            point_count: points.features.len(),
+            bounds,
+            layers: layer_stats.into_keys().collect(),
+        },
+        dirty_tiles: dirty_count,
+        copied_tiles,
+    }))
+}
+
+/// Point features and their positions, clustered together at each zoom
+struct PointSet<'a> {
+    features: Vec<&'a SourceFeature>,
+    positions: Vec<WorldPoint>,
+}
+
+impl<'a> PointSet<'a> {
+    fn new(features: &'a [SourceFeature]) -> Self {
+        let (features, positions) = features
+            .iter()
+            .filter_map(|f| match f.geometry {
+                SourceGeometry::Point(position) => Some((f, position)),
+                SourceGeometry::Lines(_) => None,
+            })
+            .unzip();
+        Self {
+            features,
+            positions,
+        }
+    }
+}
+
+/// Encode the tiles of one zoom level, in tile ID order. With `only`, just
+/// those tiles are built and features that cannot reach them are skipped.
+fn render_zoom(
+    features: &[SourceFeature],
+    points: &PointSet,
+    zoom: u8,
+    settings: &VectorTileSettings,
+    only: Option<&HashSet<(u32, u32)>>,
+) -> Vec<(u64, TileBuilder)> {
+    let buffer = settings.buffer_pixels / 256.0;
+    let epsilon = simplification_epsilon(settings, zoom);
+    let keep_fraction = keep_fraction(settings, zoom);
+    let mut tiles: HashMap<(u32, u32), TileBuilder> = HashMap::new();
+
+    for feature in features {
+        let SourceGeometry::Lines(lines) = &feature.geometry else {
+            continue;
+        };
+        if !keep_feature(feature.id, keep_fraction) {
+            continue;
+        }
+        if let Some(only) = only
+            && !reaches_any(feature.world_bounds, zoom, buffer, only)
+        {
+            continue;
+        }
+
+        let mut parts_by_tile: HashMap<(u32, u32), Vec<Vec<[i32; 2]>>> = HashMap::new();
+
+        for line in lines {
+            // Lines were already simplified for the maximum zoom
+            let line = if zoom == settings.max_zoom {
+                Cow::Borrowed(line)
+            } else {
+                Cow::Owned(simplify(line, epsilon))
+            };
+
+            for ((x, y), pieces) in clip_line_to_tiles(&line, zoom, buffer) {
+                if only.is_some_and(|only| !only.contains(&(x, y))) {
+                    continue;
+                }
+                for piece in pieces {
+                    let coords = to_tile_coords(&piece, zoom, x, y, settings.extent);
+                    if coords.len() >= 2 {
+                        parts_by_tile.entry((x, y)).or_default().push(coords);
+                    }
+                }
+            }
+        }
+
+        for (tile, parts) in parts_by_tile {
+            tiles
+                .entry(tile)
+                .or_default()
+                .layer(&feature.layer, settings.extent)
+                .add_line_feature(feature.id, &parts, &feature.properties);
+        }
+    }
+
+    add_points(&mut tiles, points, zoom, settings);
+    if let Some(only) = only {
+        tiles.retain(|tile, _| only.contains(tile));
+    }
+
+    let mut tiles: Vec<(u64, TileBuilder)> = tiles
+        .into_iter()
+        .map(|((x, y), tile)| (pmtiles::zxy_to_tile_id(zoom, x, y), tile))
+        .collect();
+    tiles.sort_unstable_by_key(|(tile_id, _)| *tile_id);
+    tiles
+}
+
+/// Tiles at each zoom whose content depends on any of `features`
+fn touched_tiles(
+    features: &[SourceFeature],
+    settings: &VectorTileSettings,
+) -> BTreeMap<u8, HashSet<(u32, u32)>> {
+    let buffer = settings.buffer_pixels / 256.0;
+    let mut touched: BTreeMap<u8, HashSet<(u32, u32)>> = BTreeMap::new();
+
+    for zoom in settings.min_zoom..=settings.max_zoom {
+        let epsilon = simplification_epsilon(settings, zoom);
+        let keep_fraction = keep_fraction(settings, zoom);
+        let clustered = zoom <= settings.cluster_max_zoom && settings.cluster_distance > 0.0;
+        let tiles = touched.entry(zoom).or_default();
+
+        for feature in features {
+            match &feature.geometry {
+                SourceGeometry::Lines(lines) => {
+                    if !keep_feature(feature.id, keep_fraction) {
+                        continue;
+                    }
+                    for line in lines {
+                        let line = if zoom == settings.max_zoom {
+                            Cow::Borrowed(line)
+                        } else {
+                            Cow::Owned(simplify(line, epsilon))
+                        };
+                        tiles.extend(clip_line_to_tiles(&line, zoom, buffer).into_keys());
+                    }
+                }
+                SourceGeometry::Point(position) => {
+                    // A clustered point can move its cluster into any tile its cell overlaps
+                    let distance = if clustered {
+                        settings.cluster_distance / 256.0
+                    } else {
+                        0.0
+                    };
+                    tiles.extend(cluster_cell_tiles(*position, zoom, distance));
+                }
+            }
+        }
+    }
+
+    touched.retain(|_, tiles| !tiles.is_empty());
+    touched
+}
+
+/// Whether a feature with these world bounds can reach any of `tiles` at
+/// `zoom`, allowing for the clipping buffer
+fn reaches_any(world_bounds: [f64; 4], zoom: u8, buffer: f64, tiles: &HashSet<(u32, u32)>) -> bool {
+    let scale = f64::from(1u32 << zoom);
+    let [min_x, min_y, max_x, max_y] = world_bounds;
+    let min = [min_x * scale - buffer, min_y * scale - buffer];
+    let max = [max_x * scale + buffer, max_y * scale + buffer];
+
+    tiles.iter().any(|&(x, y)| {
+        let (x, y) = (f64::from(x), f64::from(y));
+        x + 1.0 >= min[0] && x <= max[0] && y + 1.0 >= min[1] && y <= max[1]
+    })
+}
+
+fn tileset_info(settings: &VectorTileSettings, bounds: [f64; 4]) -> TilesetInfo {
+    TilesetInfo {
+        tile_type: pmtiles::TILE_TYPE_MVT,
+        tile_compression: pmtiles::COMPRESSION_GZIP,
+        min_zoom: settings.min_zoom,
+        max_zoom: settings.max_zoom,
+        bounds,
+        center_zoom: settings.min_zoom,
+    }
+}
+
+/// Split line-delimited GeoJSON into one file of features per layer in
+/// `output_dir`, for tippecanoe's per-layer inputs
+pub fn split_by_layer(
+    geojson_path: &Path,
+    settings: &VectorTileSettings,
+    output_dir: &Path,
+) -> Result<BTreeMap<String, PathBuf>> {
+    let file = File::open(geojson_path)
+        .with_context(|| format!("Failed to open {}", geojson_path.display()))?;
+
+    let mut writers: BTreeMap<String, (PathBuf, BufWriter<File>)> = BTreeMap::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let features = match line.parse::<GeoJson>() {
+            Ok(GeoJson::FeatureCollection(collection)) => collection.features,
+            Ok(GeoJson::Feature(feature)) => vec![feature],
+            Ok(GeoJson::Geometry(_)) => continue,
+            Err(e) => {
+                warn!("Skipping unparseable GeoJSON line: {}", e);
+                continue;
+            }
+        };
+
+        for feature in features {
+            let layer = settings.layer_for(&feature);
+            if !writers.contains_key(layer) {
+                let path = output_dir.join(format!("layer_{layer}.geojson"));
+                let file = File::create(&path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                writers.insert(layer.to_string(), (path, BufWriter::new(file)));
+            }
+
+            let (_, writer) = writers.get_mut(layer).expect("writer was just inserted");
+            serde_json::to_writer(&mut *writer, &feature)?;
+            writer.write_all(b"\n")?;
+        }
+    }
+
+    let mut paths = BTreeMap::new();
+    for (layer, (path, mut writer)) in writers {
+        writer.flush()?;
+        paths.insert(layer, path);
+    }
+    Ok(paths)
+}
+
+/// Add point features to the tiles at `zoom`, clustering nearby points at
+/// low zooms. A cluster takes the properties of its first point.
+fn add_points(
+    tiles: &mut HashMap<(u32, u32), TileBuilder>,
+    points: &PointSet,
+    zoom: u8,
+    settings: &VectorTileSettings,
+) {
+    let clusters = if zoom <= settings.cluster_max_zoom && settings.cluster_distance > 0.0 {
+        cluster_points(&points.positions, zoom, settings.cluster_distance / 256.0)
+    } else {
+        points
+            .positions
+            .iter()
+            .enumerate()
+            .map(|(i, &p)| (p, vec![i]))
+            .collect()
+    };
+
+    let scale = f64::from(1u32 << zoom);
+    let max_index = (1u32 << zoom) - 1;
+
+    for (position, members) in clusters {
+        let feature = points.features[members[0]];
+        let (x, y) = (
+            ((position[0] * scale) as u32).min(max_index),
+            ((position[1] * scale) as u32).min(max_index),
+        );
+        let coords = to_tile_coords(&[position], zoom, x, y, settings.extent);
+
+        let properties = if members.len() > 1 {
+            let mut properties = feature.properties.clone();
+            properties.push(("clustered".to_string(), PropertyValue::Bool(true)));
+            properties.push((
+                "point_count".to_string(),
+                PropertyValue::Int(members.len() as i64),
+            ));
+            Cow::Owned(properties)
+        } else {
+            Cow::Borrowed(&feature.properties)
+        };
+
+        tiles
+            .entry((x, y))
+            .or_default()
+            .layer(&feature.layer, settings.extent)
+            .add_point_feature(feature.id, coords[0], &properties);
+    }
+}
+
+/// Fraction of line features kept at `zoom`
+fn keep_fraction(settings: &VectorTileSettings, zoom: u8) -> f64 {
+    settings
+        .drop_rate
+        .filter(|rate| *rate > 1.0)
+        .map_or(1.0, |rate| rate.powi(-i32::from(settings.max_zoom - zoom)))
+}
+
+/// Deterministically keep roughly `fraction` of features by Fibonacci
+/// hashing their IDs, so the choice for an activity does not depend on the
+/// other activities. Features kept at a zoom are also kept at every higher
+/// zoom.
+fn keep_feature(id: u64, fraction: f64) -> bool {
+    const GOLDEN_RATIO_64: u64 = 0x9E37_79B9_7F4A_7C15;
+    let position = (id.wrapping_mul(GOLDEN_RATIO_64) >> 11) as f64 / (1u64 << 53) as f64;
+    fraction >= 1.0 || position < fraction
+}
+
+/// Feature ID derived from the activity ID and marker, so a feature keeps
+/// its ID across builds and in tiles regenerated by a patch. IDs fit in 53
+/// bits for JavaScript clients. Features without an activity ID fall back to
+/// their position in the input.
+fn stable_feature_id(feature: &Feature, position: usize) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+    let Some(activity_id) = feature.property("id").and_then(|id| id.as_str()) else {
+        return position as u64;
+    };
+    let marker = feature
+        .property("marker")
+        .and_then(|marker| marker.as_str())
+        .unwrap_or_default();
+
+    let mut hash = FNV_OFFSET;
+    for byte in activity_id.bytes().chain([0]).chain(marker.bytes()) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash & ((1 << 53) - 1)
+}
+
+/// World-space tolerance equivalent to `settings.simplification` tile units
+fn simplification_epsilon(settings: &VectorTileSettings, zoom: u8) -> f64 {
+    settings.simplification / (f64::from(settings.extent) * f64::from(1u32 << zoom))
+}
+
+fn read_features(
+    geojson_path: &Path,
+    settings: &VectorTileSettings,
+) -> Result<(Vec<SourceFeature>, [f64; 4])> {
+    let file = File::open(geojson_path)
+        .with_context(|| format!("Failed to open {}", geojson_path.display()))?;
+
+    let max_zoom_epsilon = simplification_epsilon(settings, settings.max_zoom);
+    let mut features = Vec::new();
+    let mut bounds = [f64::MAX, f64::MAX, f64::MIN, f64::MIN];
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let geojson_features = match line.parse::<GeoJson>() {
+            Ok(GeoJson::FeatureCollection(collection)) => collection.features,
+            Ok(GeoJson::Feature(feature)) => vec![feature],
+            Ok(GeoJson::Geometry(_)) => continue,
+            Err(e) => {
+                warn!("Skipping unparseable GeoJSON line: {}", e);
+                continue;
+            }
+        };
+
+        for feature in geojson_features {
+            let layer = settings.layer_for(&feature).to_string();
+            let mut extend_bounds = |lon: f64, lat: f64| {
+                bounds = [
+                    bounds[0].min(lon),
+                    bounds[1].min(lat),
+                    bounds[2].max(lon),
+                    bounds[3].max(lat),
+                ];
+            };
+
+            let raw_lines = match feature.geometry.as_ref().map(|g| &g.value) {
+                Some(Value::LineString(line)) => vec![line.clone()],
+                Some(Value::MultiLineString(lines)) => lines.clone(),
+                Some(Value::Point(position)) if position.len() >= 2 => {
+                    extend_bounds(position[0], position[1]);
+                    let [x, y] = lon_lat_to_world(position[0], position[1]);
+                    features.push(SourceFeature {
+                        id: stable_feature_id(&feature, features.len()),
+                        layer,
+                        geometry: SourceGeometry::Point([x, y]),
+                        world_bounds: [x, y, x, y],
+                        properties: feature_properties(&feature, settings),
+                    });
+                    continue;
+                }
+                _ => continue,
+            };
+
+            let mut lines = Vec::with_capacity(raw_lines.len());
+            for raw_line in raw_lines {
+                let mut world_line = Vec::with_capacity(raw_line.len());
+                for position in raw_line.iter().filter(|p| p.len() >= 2) {
+                    let (lon, lat) = (position[0], position[1]);
+                    extend_bounds(lon, lat);
+                    world_line.push(lon_lat_to_world(lon, lat));
+                }
+                if world_line.len() >= 2 {
+                    lines.push(simplify(&world_line, max_zoom_epsilon));
+                }
+            }
+
+            if lines.is_empty() {
+                continue;
+            }
+
+            let world_bounds = lines.iter().flatten().fold(
+                [f64::MAX, f64::MAX, f64::MIN, f64::MIN],
+                |[min_x, min_y, max_x, max_y], &[x, y]| {
+                    [min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y)]
+                },
+            );
+            features.push(SourceFeature {
+                id: stable_feature_id(&feature, features.len()),
+                layer,
+                geometry: SourceGeometry::Lines(lines),
+                world_bounds,
+                properties: feature_properties(&feature, settings),
+            });
+        }
+    }
+
+    if features.is_empty() {
+        bounds = [-180.0, -85.0, 180.0, 85.0];
+    }
+
+    Ok((features, bounds))
+}
+
+/// The track of one activity's FeatureCollection in world coordinates,
+/// skipping its start and finish markers
+pub fn activity_lines(collection: &geojson::FeatureCollection) -> Vec<Vec<WorldPoint>> {
+    let mut lines = Vec::new();
+    for feature in &collection.features {
+        let raw_lines = match feature.geometry.as_ref().map(|g| &g.value) {
+            Some(Value::LineString(line)) => std::slice::from_ref(line),
+            Some(Value::MultiLineString(lines)) => lines.as_slice(),
+            _ => continue,
+        };
+        for raw_line in raw_lines {
+            lines.push(
+                raw_line
+                    .iter()
+                    .filter(|p| p.len() >= 2)
+                    .map(|p| lon_lat_to_world(p[0], p[1]))
+                    .collect(),
+            );
+        }
+    }
+    lines
+}
+
+fn feature_properties(
+    feature: &Feature,
+    settings: &VectorTileSettings,
+) -> Vec<(String, PropertyValue)> {
+    feature
+        .properties
+        .iter()
+        .flatten()
+        .filter(|(key, _)| keep_attribute(settings, key))
+        .filter_map(|(key, value)| {
+            PropertyValue::from_json(value).map(|value| (key.clone(), value))
+        })
+        .collect()
+}
+
+fn keep_attribute(settings: &VectorTileSettings, key: &str) -> bool {
+    (settings.include_attributes.is_empty() || settings.include_attributes.iter().any(|a| a == key))
+        && !settings.exclude_attributes.iter().any(|a| a == key)
+}
+
+/// Feature count, geometry and property types of a layer
+#[derive(Default)]
+struct LayerStats {
+    count: usize,
+    geometry: &'static str,
+    fields: BTreeMap<String, &'static str>,
+}
+
+/// Statistics for each layer that has features
+fn collect_layer_stats(
+    features: &[SourceFeature],
+    settings: &VectorTileSettings,
+) -> BTreeMap<String, LayerStats> {
+    let mut layers: BTreeMap<String, LayerStats> = BTreeMap::new();
+    for feature in features {
+        let stats = layers.entry(feature.layer.clone()).or_default();
+        stats.count += 1;
+        for (key, value) in &feature.properties {
+            stats.fields.entry(key.clone()).or_insert(value.type_name());
+        }
+        match feature.geometry {
+            SourceGeometry::Lines(_) => stats.geometry = "LineString",
+            SourceGeometry::Point(_) => {
+                stats.geometry = "Point";
+                if settings.cluster_distance > 0.0 {
+                    stats.fields.insert("clustered".to_string(), "Boolean");
+                    stats.fields.insert("point_count".to_string(), "Number");
+                }
+            }
+        }
+    }
+    layers
+}
+
+fn tileset_metadata(
+    settings: &VectorTileSettings,
+    layer_stats: &BTreeMap<String, LayerStats>,
+) -> serde_json::Value {
+    let vector_layers: Vec<serde_json::Value> = layer_stats
+        .iter()
+        .map(|(layer, stats)| {
+            serde_json::json!({
+                "id": layer,
+                "fields": stats.fields,
+                "minzoom": settings.min_zoom,
+                "maxzoom": settings.max_zoom,
+            })
+        })
+        .collect();
+
+    // Per-layer feature counts, in the same shape as tippecanoe's tilestats
+    let tilestats_layers: Vec<serde_json::Value> = layer_stats
+        .iter()
+        .map(|(layer, stats)| {
+            serde_json::json!({
+                "layer": layer,
+                "count": stats.count,
+                "geometry": stats.geometry,
+                "attributeCount": stats.fields.len(),
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "name": settings.layer_name,
+        "description": settings.description,
+        "format": "pbf",
+        "type": "overlay",
+        "generator": GENERATOR,
+        "vector_layers": vector_layers,
+        "tilestats": {
+            "layerCount": tilestats_layers.len(),
+            "layers": tilestats_layers,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One activity line: the track followed by its start and finish markers
+    fn activity(id: &str, activity_type: &str, track: &[[f64; 2]]) -> String {
+        let marker = |position: [f64; 2], marker: &str| {
+            serde_json::json!({
+                "type": "Feature",
+                "geometry": {"type": "Point", "coordinates": position},
+                "properties": {"id": id, "type": activity_type, "marker": marker},
+            })
+        };
+        serde_json::json!({
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "geometry": {"type": "LineString", "coordinates": track},
+                    "properties": {"id": id, "type": activity_type},
+                },
+                marker(track[0], "start"),
+                marker(track[track.len() - 1], "finish"),
+            ],
+        })
+        .to_string()
+    }
+
+    fn read_tiles(path: &Path) -> Vec<(u64, Vec<u8>)> {
+        let mut reader = PmtilesReader::open(path).unwrap();
+        reader
+            .entries()
+            .unwrap()
+            .iter()
+            .map(|entry| (entry.tile_id, reader.read_tile(entry).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_patch_matches_full_build() {
+        let dir = tempdir::TempDir::new("patch_pmtiles").unwrap();
+        let write = |name: &str, lines: &[&String]| {
+            let path = dir.path().join(name);
+            let content: String = lines.iter().map(|line| format!("{line}\n")).collect();
+            std::fs::write(&path, content).unwrap();
+            path
+        };
+
+        let london = activity("i1", "Ride", &[[-0.1, 51.5], [-0.05, 51.52], [0.0, 51.5]]);
+        let paris = activity("i2", "Run", &[[2.3, 48.8], [2.31, 48.81]]);
+        let berlin = activity("i3", "Ride", &[[13.4, 52.5], [13.45, 52.52]]);
+        let london_changed = activity("i1", "Ride", &[[-0.1, 51.5], [-0.12, 51.45]]);
+        let rome = activity("i4", "Walk", &[[12.48, 41.89], [12.5, 41.9]]);
+
+        let previous_geojson = write("previous.geojson", &[&london, &paris, &berlin]);
+        let geojson = write("current.geojson", &[&london_changed, &berlin, &rome]);
+        let changes = write(
+            "changes.geojson",
+            &[&london, &paris, &london_changed, &rome],
+        );
+
+        let settings = VectorTileSettings {
+            max_zoom: 10,
+            cluster_distance: 10.0,
+            cluster_max_zoom: 6,
+            description: "test".to_string(),
+            ..VectorTileSettings::default()
+        };
+        let previous = dir.path().join("previous.pmtiles");
+        let full = dir.path().join("full.pmtiles");
+        let patched = dir.path().join("patched.pmtiles");
+        build_vector_pmtiles(&previous_geojson, &previous, &settings).unwrap();
+        build_vector_pmtiles(&geojson, &full, &settings).unwrap();
+
+        let patch = patch_vector_pmtiles(&previous, &geojson, &changes, &patched, &settings, 1.0)
+            .unwrap()
+            .expect("archive should be patched");
+        assert!(patch.copied_tiles > 0);
+        assert_eq!(read_tiles(&patched), read_tiles(&full));
+
+        // A full build is cheaper when most tiles change
+        assert!(
+            patch_vector_pmtiles(&previous, &geojson, &changes, &patched, &settings, 0.01)
+                .unwrap()
+                .is_none()
+        );
+
+        // Archives built with other settings are not patched
+        let other = VectorTileSettings {
+            description: "other".to_string(),
+            ..settings
+        };
+        assert!(
+            patch_vector_pmtiles(&previous, &geojson, &changes, &patched, &other, 1.0)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,273 @@
+// Mapbox Vector Tile v2.1 protobuf encoding
+use std::collections::HashMap;
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_64BIT: u32 = 1;
+const WIRE_LEN: u32 = 2;
+
+const GEOM_POINT: u32 = 1;
+const GEOM_LINESTRING: u32 = 2;
+
+const CMD_MOVE_TO: u32 = 1;
+const CMD_LINE_TO: u32 = 2;
+
+/// A feature property value as stored in the layer's value table
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyValue {
+    String(String),
+    Double(f64),
+    Int(i64),
+    Bool(bool),
+}
+
+impl PropertyValue {
+    pub fn from_json(value: &serde_json::Value) -> Option<Self> {
+        match value {
+            serde_json::Value::String(s) => Some(PropertyValue::String(s.clone())),
+            serde_json::Value::Bool(b) => Some(PropertyValue::Bool(*b)),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => Some(PropertyValue::Int(i)),
+                None => n.as_f64().map(PropertyValue::Double),
+            },
+            _ => None,
+        }
+    }
+
+    /// Field type name used in the TileJSON `vector_layers` metadata
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            PropertyValue::String(_) => "String",
+            PropertyValue::Double(_) | PropertyValue::Int(_) => "Number",
+            PropertyValue::Bool(_) => "Boolean",
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            PropertyValue::String(s) => write_bytes_field(&mut buf, 1, s.as_bytes()),
+            PropertyValue::Double(d) => {
+                write_key(&mut buf, 3, WIRE_64BIT);
+                buf.extend_from_slice(&d.to_le_bytes());
+            }
+            PropertyValue::Int(i) => {
+                write_key(&mut buf, 6, WIRE_VARINT);
+                write_varint(&mut buf, zigzag64(*i));
+            }
+            PropertyValue::Bool(b) => {
+                write_key(&mut buf, 7, WIRE_VARINT);
+                write_varint(&mut buf, u64::from(*b));
+            }
+        }
+        buf
+    }
+}
+
+/// Accumulates features for one layer of one tile
+pub struct LayerBuilder {
+    name: String,
+    extent: u32,
+    keys: Vec<String>,
+    key_index: HashMap<String, u32>,
+    values: Vec<Vec<u8>>,
+    value_index: HashMap<Vec<u8>, u32>,
+    features: Vec<u8>,
+    feature_count: usize,
+}
+
+impl LayerBuilder {
+    pub fn new(name: &str, extent: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            extent,
+            keys: Vec::new(),
+            key_index: HashMap::new(),
+            values: Vec::new(),
+            value_index: HashMap::new(),
+            features: Vec::new(),
+            feature_count: 0,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn feature_count(&self) -> usize {
+        self.feature_count
+    }
+
+    /// Add a (multi)linestring feature; `parts` are in tile coordinates and
+    /// each must have at least two points
+    pub fn add_line_feature(
+        &mut self,
+        id: u64,
+        parts: &[Vec<[i32; 2]>],
+        properties: &[(String, PropertyValue)],
+    ) {
+        let mut geometry = Vec::new();
+        let mut cursor = [0i32; 2];
+        for part in parts.iter().filter(|part| part.len() >= 2) {
+            geometry.push(command(CMD_MOVE_TO, 1));
+            push_delta(&mut geometry, &mut cursor, part[0]);
+            geometry.push(command(CMD_LINE_TO, part.len() as u32 - 1));
+            for &point in &part[1..] {
+                push_delta(&mut geometry, &mut cursor, point);
+            }
+        }
+
+        if !geometry.is_empty() {
+            self.add_feature(id, GEOM_LINESTRING, &geometry, properties);
+        }
+    }
+
+    /// Add a point feature at tile coordinates `point`
+    pub fn add_point_feature(
+        &mut self,
+        id: u64,
+        point: [i32; 2],
+        properties: &[(String, PropertyValue)],
+    ) {
+        let mut geometry = vec![command(CMD_MOVE_TO, 1)];
+        push_delta(&mut geometry, &mut [0, 0], point);
+        self.add_feature(id, GEOM_POINT, &geometry, properties);
+    }
+
+    fn add_feature(
+        &mut self,
+        id: u64,
+        geom_type: u32,
+        geometry: &[u32],
+        properties: &[(String, PropertyValue)],
+    ) {
+        let mut tags = Vec::with_capacity(properties.len() * 2);
+        for (key, value) in properties {
+            tags.push(self.key_id(key));
+            tags.push(self.value_id(value));
+        }
+
+        let mut feature = Vec::new();
+        write_key(&mut feature, 1, WIRE_VARINT);
+        write_varint(&mut feature, id);
+        write_packed_field(&mut feature, 2, &tags);
+        write_key(&mut feature, 3, WIRE_VARINT);
+        write_varint(&mut feature, u64::from(geom_type));
+        write_packed_field(&mut feature, 4, geometry);
+
+        write_bytes_field(&mut self.features, 2, &feature);
+        self.feature_count += 1;
+    }
+
+    fn key_id(&mut self, key: &str) -> u32 {
+        if let Some(&id) = self.key_index.get(key) {
+            return id;
+        }
+        let id = self.keys.len() as u32;
+        self.keys.push(key.to_string());
+        self.key_index.insert(key.to_string(), id);
+        id
+    }
+
+    fn value_id(&mut self, value: &PropertyValue) -> u32 {
+        let encoded = value.encode();
+        if let Some(&id) = self.value_index.get(&encoded) {
+            return id;
+        }
+        let id = self.values.len() as u32;
+        self.values.push(encoded.clone());
+        self.value_index.insert(encoded, id);
+        id
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut layer = Vec::with_capacity(self.features.len() + 64);
+        write_key(&mut layer, 15, WIRE_VARINT);
+        write_varint(&mut layer, 2);
+        write_bytes_field(&mut layer, 1, self.name.as_bytes());
+        layer.extend_from_slice(&self.features);
+        for key in &self.keys {
+            write_bytes_field(&mut layer, 3, key.as_bytes());
+        }
+        for value in &self.values {
+            write_bytes_field(&mut layer, 4, value);
+        }
+        write_key(&mut layer, 5, WIRE_VARINT);
+        write_varint(&mut layer, u64::from(self.extent));
+        layer
+    }
+}
+
+/// Accumulates the layers of a single tile
+#[derive(Default)]
+pub struct TileBuilder {
+    layers: Vec<LayerBuilder>,
+}
+
+impl TileBuilder {
+    /// Get the named layer, creating it if this tile does not have it yet
+    pub fn layer(&mut self, name: &str, extent: u32) -> &mut LayerBuilder {
+        let index = match self.layers.iter().position(|l| l.name() == name) {
+            Some(index) => index,
+            None => {
+                self.layers.push(LayerBuilder::new(name, extent));
+                self.layers.len() - 1
+            }
+        };
+        &mut self.layers[index]
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut tile = Vec::new();
+        for layer in self.layers.iter().filter(|l| l.feature_count() > 0) {
+            write_bytes_field(&mut tile, 3, &layer.encode());
+        }
+        tile
+    }
+}
+
+fn command(id: u32, count: u32) -> u32 {
+    (id & 0x7) | (count << 3)
+}
+
+fn push_delta(geometry: &mut Vec<u32>, cursor: &mut [i32; 2], point: [i32; 2]) {
+    geometry.push(zigzag32(point[0] - cursor[0]));
+    geometry.push(zigzag32(point[1] - cursor[1]));
+    *cursor = point;
+}
+
+fn zigzag32(n: i32) -> u32 {
+    ((n << 1) ^ (n >> 31)) as u32
+}
+
+fn zigzag64(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_key(buf: &mut Vec<u8>, field: u32, wire_type: u32) {
+    write_varint(buf, u64::from((field << 3) | wire_type));
+}
+
+fn write_bytes_field(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_key(buf, field, WIRE_LEN);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_packed_field(buf: &mut Vec<u8>, field: u32, values: &[u32]) {
+    if values.is_empty() {
+        return;
+    }
+    let mut packed = Vec::with_capacity(values.len() * 2);
+    for &value in values {
+        write_varint(&mut packed, u64::from(value));
+    }
+    write_bytes_field(buf, field, &packed);
+}
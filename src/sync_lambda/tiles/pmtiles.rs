@@ -0,0 +1,529 @@
+use super::mvt::write_varint;
+use anyhow::{Context, Result};
+use flate2::Compression as GzipLevel;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const HEADER_LEN: usize = 127;
+const MAX_ROOT_DIR_LEN: usize = 16384 - HEADER_LEN;
+
+pub const COMPRESSION_NONE: u8 = 1;
+pub const COMPRESSION_GZIP: u8 = 2;
+pub const TILE_TYPE_MVT: u8 = 1;
+pub const TILE_TYPE_PNG: u8 = 2;
+pub const TILE_TYPE_WEBP: u8 = 4;
+
+/// A single tile in the archive's directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub tile_id: u64,
+    pub offset: u64,
+    pub length: u32,
+    pub run_length: u32,
+}
+
+/// Bounds and zoom information written to the PMTiles header
+#[derive(Debug, Clone, Copy)]
+pub struct TilesetInfo {
+    pub tile_type: u8,
+    pub tile_compression: u8,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// min lon, min lat, max lon, max lat in degrees
+    pub bounds: [f64; 4],
+    pub center_zoom: u8,
+}
+
+/// The fixed-size header at the start of a PMTiles v3 archive
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub root_dir_offset: u64,
+    pub root_dir_len: u64,
+    pub metadata_offset: u64,
+    pub metadata_len: u64,
+    pub leaf_dirs_offset: u64,
+    pub leaf_dirs_len: u64,
+    pub tile_data_offset: u64,
+    pub tile_data_len: u64,
+    pub addressed_tiles: u64,
+    pub tile_entries: u64,
+    pub tile_contents: u64,
+    pub clustered: bool,
+    pub internal_compression: u8,
+    pub tile_compression: u8,
+    pub tile_type: u8,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// min lon, min lat, max lon, max lat in degrees
+    pub bounds: [f64; 4],
+    pub center_zoom: u8,
+}
+
+impl Header {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        anyhow::ensure!(bytes.len() >= HEADER_LEN, "PMTiles header is truncated");
+        anyhow::ensure!(&bytes[..7] == b"PMTiles", "Missing PMTiles magic number");
+        anyhow::ensure!(bytes[7] == 3, "Unsupported PMTiles version {}", bytes[7]);
+
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().expect("8 bytes"));
+        let degrees_at = |i: usize| {
+            f64::from(i32::from_le_bytes(
+                bytes[i..i + 4].try_into().expect("4 bytes"),
+            )) / 10_000_000.0
+        };
+
+        Ok(Self {
+            root_dir_offset: u64_at(8),
+            root_dir_len: u64_at(16),
+            metadata_offset: u64_at(24),
+            metadata_len: u64_at(32),
+            leaf_dirs_offset: u64_at(40),
+            leaf_dirs_len: u64_at(48),
+            tile_data_offset: u64_at(56),
+            tile_data_len: u64_at(64),
+            addressed_tiles: u64_at(72),
+            tile_entries: u64_at(80),
+            tile_contents: u64_at(88),
+            clustered: bytes[96] == 1,
+            internal_compression: bytes[97],
+            tile_compression: bytes[98],
+            tile_type: bytes[99],
+            min_zoom: bytes[100],
+            max_zoom: bytes[101],
+            bounds: [
+                degrees_at(102),
+                degrees_at(106),
+                degrees_at(110),
+                degrees_at(114),
+            ],
+            center_zoom: bytes[118],
+        })
+    }
+}
+
+/// Reads the header, metadata, directories and tiles of a PMTiles v3 archive
+pub struct PmtilesReader {
+    file: File,
+    file_len: u64,
+    header: Header,
+}
+
+impl PmtilesReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)
+            .with_context(|| format!("Failed to open PMTiles file {}", path.display()))?;
+        let file_len = file.metadata()?.len();
+
+        let mut header = [0u8; HEADER_LEN];
+        file.read_exact(&mut header)
+            .context("PMTiles file is shorter than its header")?;
+        let header = Header::parse(&header)?;
+
+        Ok(Self {
+            file,
+            file_len,
+            header,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn file_len(&self) -> u64 {
+        self.file_len
+    }
+
+    pub fn metadata(&mut self) -> Result<serde_json::Value> {
+        let (offset, len) = (self.header.metadata_offset, self.header.metadata_len);
+        let metadata = self.read_section(offset, len)?;
+        serde_json::from_slice(&metadata).context("PMTiles metadata is not valid JSON")
+    }
+
+    /// All tile entries in tile ID order, following leaf directories
+    pub fn entries(&mut self) -> Result<Vec<Entry>> {
+        let (offset, len) = (self.header.root_dir_offset, self.header.root_dir_len);
+        let root = deserialize_entries(&self.read_section(offset, len)?)?;
+
+        let mut entries = Vec::new();
+        for entry in root {
+            if entry.run_length > 0 {
+                entries.push(entry);
+                continue;
+            }
+
+            anyhow::ensure!(
+                entry.offset + u64::from(entry.length) <= self.header.leaf_dirs_len,
+                "Leaf directory at {} is outside the leaf directory section",
+                entry.offset
+            );
+            let leaf = self.read_section(
+                self.header.leaf_dirs_offset + entry.offset,
+                u64::from(entry.length),
+            )?;
+            let leaf_entries = deserialize_entries(&leaf)?;
+            anyhow::ensure!(
+                leaf_entries.iter().all(|e| e.run_length > 0),
+                "Nested leaf directories are not supported"
+            );
+            entries.extend(leaf_entries);
+        }
+        Ok(entries)
+    }
+
+    /// Raw, still compressed, data of a tile entry
+    pub fn read_tile(&mut self, entry: &Entry) -> Result<Vec<u8>> {
+        anyhow::ensure!(
+            entry.offset + u64::from(entry.length) <= self.header.tile_data_len,
+            "Tile {} is outside the tile data section",
+            entry.tile_id
+        );
+
+        let mut data = vec![0u8; entry.length as usize];
+        self.file
+            .seek(SeekFrom::Start(self.header.tile_data_offset + entry.offset))?;
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Read and decompress a directory or metadata section
+    fn read_section(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        anyhow::ensure!(
+            offset
+                .checked_add(len)
+                .is_some_and(|end| end <= self.file_len),
+            "Section at {offset}+{len} is outside the {} byte file",
+            self.file_len
+        );
+
+        let mut data = vec![0u8; len as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut data)?;
+
+        match self.header.internal_compression {
+            COMPRESSION_NONE => Ok(data),
+            COMPRESSION_GZIP => {
+                let mut decompressed = Vec::new();
+                GzDecoder::new(data.as_slice())
+                    .read_to_end(&mut decompressed)
+                    .context("Failed to decompress PMTiles section")?;
+                Ok(decompressed)
+            }
+            other => Err(anyhow::anyhow!(
+                "Unsupported PMTiles internal compression {other}"
+            )),
+        }
+    }
+}
+
+/// Streams tiles into a PMTiles v3 archive. Tiles must be added in ascending
+/// tile ID order, which keeps the archive clustered.
+pub struct PmtilesWriter {
+    output_path: PathBuf,
+    tile_data_path: PathBuf,
+    tile_data: BufWriter<File>,
+    tile_data_len: u64,
+    entries: Vec<Entry>,
+}
+
+impl PmtilesWriter {
+    pub fn create(output_path: &Path) -> Result<Self> {
+        let tile_data_path = output_path.with_extension("tiledata");
+        let tile_data = File::create(&tile_data_path).with_context(|| {
+            format!(
+                "Failed to create tile data file {}",
+                tile_data_path.display()
+            )
+        })?;
+
+        Ok(Self {
+            output_path: output_path.to_path_buf(),
+            tile_data_path,
+            tile_data: BufWriter::new(tile_data),
+            tile_data_len: 0,
+            entries: Vec::new(),
+        })
+    }
+
+    /// Append an already-compressed tile
+    pub fn add_tile(&mut self, tile_id: u64, data: &[u8]) -> Result<()> {
+        if let Some(last) = self.entries.last() {
+            anyhow::ensure!(
+                tile_id > last.tile_id,
+                "Tiles must be added in ascending tile ID order ({tile_id} after {})",
+                last.tile_id
+            );
+        }
+
+        self.tile_data.write_all(data)?;
+        self.entries.push(Entry {
+            tile_id,
+            offset: self.tile_data_len,
+            length: data.len() as u32,
+            run_length: 1,
+        });
+        self.tile_data_len += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn tile_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Write the header, directories and metadata, then the buffered tile data
+    pub fn finish(mut self, info: &TilesetInfo, metadata: &serde_json::Value) -> Result<()> {
+        self.tile_data.flush()?;
+        drop(self.tile_data);
+
+        let (root_dir, leaf_dirs) = build_directories(&self.entries)?;
+        let metadata = gzip(&serde_json::to_vec(metadata)?)?;
+
+        let root_dir_offset = HEADER_LEN as u64;
+        let metadata_offset = root_dir_offset + root_dir.len() as u64;
+        let leaf_dirs_offset = metadata_offset + metadata.len() as u64;
+        let tile_data_offset = leaf_dirs_offset + leaf_dirs.len() as u64;
+
+        let tile_count = self.entries.len() as u64;
+        let [min_lon, min_lat, max_lon, max_lat] = info.bounds;
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(b"PMTiles");
+        header.push(3);
+        for value in [
+            root_dir_offset,
+            root_dir.len() as u64,
+            metadata_offset,
+            metadata.len() as u64,
+            leaf_dirs_offset,
+            leaf_dirs.len() as u64,
+            tile_data_offset,
+            self.tile_data_len,
+            tile_count, // addressed tiles
+            tile_count, // tile entries
+            tile_count, // tile contents
+        ] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        header.push(1); // clustered
+        header.push(COMPRESSION_GZIP); // internal compression
+        header.push(info.tile_compression);
+        header.push(info.tile_type);
+        header.push(info.min_zoom);
+        header.push(info.max_zoom);
+        for degrees in [min_lon, min_lat, max_lon, max_lat] {
+            header.extend_from_slice(&to_e7(degrees).to_le_bytes());
+        }
+        header.push(info.center_zoom);
+        header.extend_from_slice(&to_e7((min_lon + max_lon) / 2.0).to_le_bytes());
+        header.extend_from_slice(&to_e7((min_lat + max_lat) / 2.0).to_le_bytes());
+        debug_assert_eq!(header.len(), HEADER_LEN);
+
+        let output = File::create(&self.output_path).with_context(|| {
+            format!(
+                "Failed to create PMTiles file {}",
+                self.output_path.display()
+            )
+        })?;
+        let mut writer = BufWriter::new(output);
+        writer.write_all(&header)?;
+        writer.write_all(&root_dir)?;
+        writer.write_all(&metadata)?;
+        writer.write_all(&leaf_dirs)?;
+
+        let mut tile_data = File::open(&self.tile_data_path)?;
+        std::io::copy(&mut tile_data, &mut writer)?;
+        writer.flush()?;
+
+        std::fs::remove_file(&self.tile_data_path).ok();
+        Ok(())
+    }
+}
+
+/// Convert z/x/y to a PMTiles tile ID: tiles of all lower zooms, then the
+/// position along the Hilbert curve at this zoom
+pub fn zxy_to_tile_id(z: u8, x: u32, y: u32) -> u64 {
+    let mut acc: u64 = ((1u64 << (2 * u32::from(z))) - 1) / 3;
+    let (mut tx, mut ty) = (u64::from(x), u64::from(y));
+    for a in (0..u32::from(z)).rev() {
+        let s = 1u64 << a;
+        let rx = s & tx;
+        let ry = s & ty;
+        acc += ((3 * rx) ^ ry) << a;
+        if ry == 0 {
+            if rx != 0 {
+                tx = s.wrapping_sub(1).wrapping_sub(tx);
+                ty = s.wrapping_sub(1).wrapping_sub(ty);
+            }
+            std::mem::swap(&mut tx, &mut ty);
+        }
+    }
+    acc
+}
+
+pub fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), GzipLevel::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+fn to_e7(degrees: f64) -> i32 {
+    (degrees * 10_000_000.0).round() as i32
+}
+
+fn serialize_entries(entries: &[Entry]) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    write_varint(&mut buf, entries.len() as u64);
+
+    let mut last_id = 0;
+    for entry in entries {
+        write_varint(&mut buf, entry.tile_id - last_id);
+        last_id = entry.tile_id;
+    }
+    for entry in entries {
+        write_varint(&mut buf, u64::from(entry.run_length));
+    }
+    for entry in entries {
+        write_varint(&mut buf, u64::from(entry.length));
+    }
+    for (i, entry) in entries.iter().enumerate() {
+        // 0 means "immediately after the previous entry"
+        if i > 0 && entry.offset == entries[i - 1].offset + u64::from(entries[i - 1].length) {
+            write_varint(&mut buf, 0);
+        } else {
+            write_varint(&mut buf, entry.offset + 1);
+        }
+    }
+
+    gzip(&buf)
+}
+
+fn deserialize_entries(buf: &[u8]) -> Result<Vec<Entry>> {
+    let mut pos = 0;
+    let mut next = || read_varint(buf, &mut pos);
+
+    let count = next()? as usize;
+    anyhow::ensure!(
+        count <= buf.len(),
+        "Directory entry count {count} is too large"
+    );
+
+    let mut entries = vec![
+        Entry {
+            tile_id: 0,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        };
+        count
+    ];
+
+    let mut last_id = 0u64;
+    for entry in entries.iter_mut() {
+        last_id = last_id
+            .checked_add(next()?)
+            .context("Directory tile ID overflow")?;
+        entry.tile_id = last_id;
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = u32::try_from(next()?).context("Run length out of range")?;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = u32::try_from(next()?).context("Tile length out of range")?;
+    }
+    for i in 0..count {
+        let value = next()?;
+        entries[i].offset = if value == 0 && i > 0 {
+            entries[i - 1].offset + u64::from(entries[i - 1].length)
+        } else {
+            value
+                .checked_sub(1)
+                .context("Invalid offset in first directory entry")?
+        };
+    }
+
+    anyhow::ensure!(pos == buf.len(), "Trailing bytes after directory entries");
+    Ok(entries)
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*pos).context("Truncated directory")?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow::anyhow!("Varint is too long"))
+}
+
+/// Serialize the root directory, splitting entries into leaf directories
+/// until the root fits in the first 16 KiB of the archive
+fn build_directories(entries: &[Entry]) -> Result<(Vec<u8>, Vec<u8>)> {
+    if entries.len() < 16384 {
+        let root = serialize_entries(entries)?;
+        if root.len() <= MAX_ROOT_DIR_LEN {
+            return Ok((root, Vec::new()));
+        }
+    }
+
+    let mut leaf_size = (entries.len() / 3500).max(4096);
+    loop {
+        let mut root_entries = Vec::new();
+        let mut leaf_dirs = Vec::new();
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = serialize_entries(chunk)?;
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaf_dirs.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0,
+            });
+            leaf_dirs.extend_from_slice(&leaf);
+        }
+
+        let root = serialize_entries(&root_entries)?;
+        if root.len() <= MAX_ROOT_DIR_LEN {
+            return Ok((root, leaf_dirs));
+        }
+        leaf_size += leaf_size / 5;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zxy_to_tile_id() {
+        assert_eq!(zxy_to_tile_id(0, 0, 0), 0);
+        assert_eq!(zxy_to_tile_id(1, 0, 0), 1);
+        assert_eq!(zxy_to_tile_id(1, 0, 1), 2);
+        assert_eq!(zxy_to_tile_id(1, 1, 1), 3);
+        assert_eq!(zxy_to_tile_id(1, 1, 0), 4);
+        assert_eq!(zxy_to_tile_id(2, 0, 0), 5);
+        assert_eq!(zxy_to_tile_id(3, 7, 0), 84);
+    }
+
+    #[test]
+    fn test_directory_round_trip() {
+        let entries: Vec<Entry> = (0..5)
+            .map(|i| Entry {
+                tile_id: i * 3,
+                offset: if i == 3 { 1000 } else { i * 10 },
+                length: 10,
+                run_length: 1,
+            })
+            .collect();
+
+        let mut serialized = Vec::new();
+        GzDecoder::new(serialize_entries(&entries).unwrap().as_slice())
+            .read_to_end(&mut serialized)
+            .unwrap();
+        assert_eq!(deserialize_entries(&serialized).unwrap(), entries);
+    }
+}
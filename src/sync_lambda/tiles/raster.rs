@@ -0,0 +1,391 @@
+use super::GENERATOR;
+use super::geometry::{WorldPoint, lon_lat_to_world, simplify};
+use super::pmtiles::{self, PmtilesWriter, TilesetInfo};
+use anyhow::{Context, Result};
+use geojson::{GeoJson, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use tracing::{debug, warn};
+
+/// Colour stops from the least to the most visited pixels, as (position,
+/// RGBA). Unvisited pixels stay transparent.
+const HEAT_RAMP: [(f64, [u8; 4]); 4] = [
+    (0.0, [110, 40, 200, 150]),
+    (0.35, [230, 30, 90, 200]),
+    (0.7, [255, 150, 0, 235]),
+    (1.0, [255, 255, 210, 255]),
+];
+
+/// Image format of raster tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RasterFormat {
+    Png,
+    /// Lossless WebP, smaller than PNG but not supported by every client
+    Webp,
+}
+
+impl RasterFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RasterFormat::Png => "png",
+            RasterFormat::Webp => "webp",
+        }
+    }
+
+    fn tile_type(&self) -> u8 {
+        match self {
+            RasterFormat::Png => pmtiles::TILE_TYPE_PNG,
+            RasterFormat::Webp => pmtiles::TILE_TYPE_WEBP,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HeatmapSettings {
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// Width and height of each tile image in pixels
+    pub tile_size: u32,
+    pub format: RasterFormat,
+    /// Stored as the tileset description in the PMTiles metadata
+    pub description: String,
+}
+
+impl Default for HeatmapSettings {
+    fn default() -> Self {
+        Self {
+            min_zoom: 0,
+            max_zoom: 12,
+            tile_size: 256,
+            format: RasterFormat::Png,
+            description: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HeatmapSummary {
+    pub tile_count: usize,
+    /// Number of activities with a track
+    pub track_count: usize,
+    /// Most activities through a single pixel, by zoom
+    pub max_density: BTreeMap<u8, u32>,
+}
+
+/// The lines of one activity in world coordinates
+type Track = Vec<Vec<WorldPoint>>;
+
+/// Visit counts of the pixels of one tile, keyed by row-major pixel index
+type DensityGrid = HashMap<u32, u32>;
+
+/// Render every activity track into a raster PMTiles archive where each
+/// pixel is coloured by how many activities pass through it. Start and
+/// finish markers are ignored.
+pub fn build_heatmap_pmtiles(
+    geojson_path: &Path,
+    output_path: &Path,
+    settings: &HeatmapSettings,
+) -> Result<HeatmapSummary> {
+    anyhow::ensure!(
+        settings.min_zoom <= settings.max_zoom && settings.max_zoom <= 18,
+        "Invalid heatmap zoom range {}-{}",
+        settings.min_zoom,
+        settings.max_zoom
+    );
+    anyhow::ensure!(
+        (64..=1024).contains(&settings.tile_size),
+        "Invalid heatmap tile size {}",
+        settings.tile_size
+    );
+
+    let (tracks, bounds) = read_tracks(geojson_path, settings)?;
+
+    let mut writer = PmtilesWriter::create(output_path)?;
+    let mut max_density = BTreeMap::new();
+    for zoom in settings.min_zoom..=settings.max_zoom {
+        let grids = density_grids(&tracks, zoom, settings.tile_size);
+        let Some(max) = grids.values().flat_map(|grid| grid.values()).max().copied() else {
+            continue;
+        };
+        debug!(
+            "Heatmap zoom {}: {} tiles, max density {}",
+            zoom,
+            grids.len(),
+            max
+        );
+        max_density.insert(zoom, max);
+
+        let mut tiles: Vec<(u64, DensityGrid)> = grids
+            .into_iter()
+            .map(|((x, y), grid)| (pmtiles::zxy_to_tile_id(zoom, x, y), grid))
+            .collect();
+        tiles.sort_unstable_by_key(|(tile_id, _)| *tile_id);
+
+        for (tile_id, grid) in tiles {
+            let image = colourize(&grid, max, settings.tile_size);
+            writer.add_tile(tile_id, &encode(&image, settings)?)?;
+        }
+    }
+
+    let tile_count = writer.tile_count();
+    writer.finish(
+        &TilesetInfo {
+            tile_type: settings.format.tile_type(),
+            // Images are already compressed
+            tile_compression: pmtiles::COMPRESSION_NONE,
+            min_zoom: settings.min_zoom,
+            max_zoom: settings.max_zoom,
+            bounds,
+            center_zoom: settings.min_zoom,
+        },
+        &serde_json::json!({
+            "name": "heatmap",
+            "description": settings.description,
+            "format": settings.format.as_str(),
+            "type": "overlay",
+            "generator": GENERATOR,
+            "tileSize": settings.tile_size,
+            "maxDensity": max_density,
+        }),
+    )?;
+
+    Ok(HeatmapSummary {
+        tile_count,
+        track_count: tracks.len(),
+        max_density,
+    })
+}
+
+/// Each activity's lines in world coordinates, simplified to well under a
+/// pixel at the maximum zoom, and the bounds of all of them
+fn read_tracks(geojson_path: &Path, settings: &HeatmapSettings) -> Result<(Vec<Track>, [f64; 4])> {
+    let file = File::open(geojson_path)
+        .with_context(|| format!("Failed to open {}", geojson_path.display()))?;
+
+    let world_pixels = f64::from(settings.tile_size) * f64::from(1u32 << settings.max_zoom);
+    let epsilon = 0.25 / world_pixels;
+    let mut tracks = Vec::new();
+    let mut bounds = [f64::MAX, f64::MAX, f64::MIN, f64::MIN];
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let features = match line.parse::<GeoJson>() {
+            Ok(GeoJson::FeatureCollection(collection)) => collection.features,
+            Ok(GeoJson::Feature(feature)) => vec![feature],
+            Ok(GeoJson::Geometry(_)) => continue,
+            Err(e) => {
+                warn!("Skipping unparseable GeoJSON line: {}", e);
+                continue;
+            }
+        };
+
+        let mut track = Vec::new();
+        for feature in features {
+            let raw_lines = match feature.geometry.map(|g| g.value) {
+                Some(Value::LineString(line)) => vec![line],
+                Some(Value::MultiLineString(lines)) => lines,
+                _ => continue,
+            };
+
+            for raw_line in raw_lines {
+                let mut world_line = Vec::with_capacity(raw_line.len());
+                for position in raw_line.iter().filter(|p| p.len() >= 2) {
+                    let (lon, lat) = (position[0], position[1]);
+                    bounds = [
+                        bounds[0].min(lon),
+                        bounds[1].min(lat),
+                        bounds[2].max(lon),
+                        bounds[3].max(lat),
+                    ];
+                    world_line.push(lon_lat_to_world(lon, lat));
+                }
+                if world_line.len() >= 2 {
+                    track.push(simplify(&world_line, epsilon));
+                }
+            }
+        }
+
+        if !track.is_empty() {
+            tracks.push(track);
+        }
+    }
+
+    if tracks.is_empty() {
+        bounds = [-180.0, -85.0, 180.0, 85.0];
+    }
+
+    Ok((tracks, bounds))
+}
+
+/// Count, for every pixel at this zoom, how many activities pass through
+/// it. An activity covering the same pixel several times, such as a lap
+/// ride, counts once.
+fn density_grids(tracks: &[Track], zoom: u8, tile_size: u32) -> HashMap<(u32, u32), DensityGrid> {
+    let world_pixels = u64::from(tile_size) << zoom;
+    let mut grids: HashMap<(u32, u32), DensityGrid> = HashMap::new();
+
+    for track in tracks {
+        let mut pixels = HashSet::new();
+        for line in track {
+            for segment in line.windows(2) {
+                rasterize_segment(segment[0], segment[1], world_pixels, &mut pixels);
+            }
+        }
+
+        let size = u64::from(tile_size);
+        for (px, py) in pixels {
+            let tile = ((px / size) as u32, (py / size) as u32);
+            let index = ((py % size) * size + px % size) as u32;
+            *grids.entry(tile).or_default().entry(index).or_default() += 1;
+        }
+    }
+
+    grids
+}
+
+/// Add the pixels a segment passes through, stepping at most one pixel at a
+/// time along its longer axis
+fn rasterize_segment(
+    a: WorldPoint,
+    b: WorldPoint,
+    world_pixels: u64,
+    pixels: &mut HashSet<(u64, u64)>,
+) {
+    let scale = world_pixels as f64;
+    let (ax, ay) = (a[0] * scale, a[1] * scale);
+    let (dx, dy) = (b[0] * scale - ax, b[1] * scale - ay);
+    let steps = dx.abs().max(dy.abs()).ceil().max(1.0) as u64;
+
+    let to_pixel = |v: f64| (v.floor().max(0.0) as u64).min(world_pixels - 1);
+    for step in 0..=steps {
+        let t = step as f64 / steps as f64;
+        pixels.insert((to_pixel(ax + dx * t), to_pixel(ay + dy * t)));
+    }
+}
+
+/// RGBA image of a tile, with visit counts scaled logarithmically against
+/// the busiest pixel at this zoom so rarely used roads stay visible
+fn colourize(grid: &DensityGrid, max_density: u32, tile_size: u32) -> Vec<u8> {
+    let mut image = vec![0u8; (tile_size * tile_size * 4) as usize];
+    let scale = f64::from(max_density).ln_1p();
+
+    for (&index, &count) in grid {
+        let intensity = if scale > 0.0 {
+            f64::from(count).ln_1p() / scale
+        } else {
+            1.0
+        };
+        let offset = index as usize * 4;
+        image[offset..offset + 4].copy_from_slice(&ramp_colour(intensity));
+    }
+    image
+}
+
+fn ramp_colour(intensity: f64) -> [u8; 4] {
+    let intensity = intensity.clamp(0.0, 1.0);
+    for stops in HEAT_RAMP.windows(2) {
+        let (start, from) = stops[0];
+        let (end, to) = stops[1];
+        if intensity <= end {
+            let t = (intensity - start) / (end - start);
+            return std::array::from_fn(|i| {
+                (f64::from(from[i]) + (f64::from(to[i]) - f64::from(from[i])) * t).round() as u8
+            });
+        }
+    }
+    HEAT_RAMP[HEAT_RAMP.len() - 1].1
+}
+
+fn encode(image: &[u8], settings: &HeatmapSettings) -> Result<Vec<u8>> {
+    let size = settings.tile_size;
+    match settings.format {
+        RasterFormat::Png => encode_png(image, size, size),
+        RasterFormat::Webp => {
+            let mut data = Vec::new();
+            image_webp::WebPEncoder::new(&mut data).encode(
+                image,
+                size,
+                size,
+                image_webp::ColorType::Rgba8,
+            )?;
+            Ok(data)
+        }
+    }
+}
+
+/// Encode an 8-bit RGBA image
+pub(super) fn encode_png(image: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(image)?;
+    writer.finish()?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::pmtiles::PmtilesReader;
+
+    #[test]
+    fn test_build_heatmap_pmtiles() {
+        let dir = tempdir::TempDir::new("heatmap").unwrap();
+        let geojson = dir.path().join("activities.geojson");
+        let track = |id: &str| {
+            serde_json::json!({
+                "type": "FeatureCollection",
+                "features": [{
+                    "type": "Feature",
+                    "geometry": {"type": "LineString", "coordinates": [[0.1, 51.5], [0.2, 51.52]]},
+                    "properties": {"id": id},
+                }],
+            })
+            .to_string()
+        };
+        std::fs::write(&geojson, format!("{}\n{}\n", track("a"), track("b"))).unwrap();
+
+        for format in [RasterFormat::Png, RasterFormat::Webp] {
+            let output = dir
+                .path()
+                .join(format!("heatmap.{}.pmtiles", format.as_str()));
+            let settings = HeatmapSettings {
+                max_zoom: 10,
+                format,
+                ..Default::default()
+            };
+            let summary = build_heatmap_pmtiles(&geojson, &output, &settings).unwrap();
+
+            assert_eq!(summary.track_count, 2);
+            // Both activities share every pixel
+            assert!(summary.max_density.values().all(|&max| max == 2));
+            assert_eq!(summary.max_density.len(), 11);
+
+            let mut reader = PmtilesReader::open(&output).unwrap();
+            assert_eq!(reader.header().tile_type, format.tile_type());
+            let entries = reader.entries().unwrap();
+            assert_eq!(entries.len(), summary.tile_count);
+            let tile = reader.read_tile(&entries[0]).unwrap();
+            match format {
+                RasterFormat::Png => assert!(tile.starts_with(b"\x89PNG")),
+                RasterFormat::Webp => assert_eq!(&tile[8..12], b"WEBP"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_ramp_colour() {
+        assert_eq!(ramp_colour(0.0), HEAT_RAMP[0].1);
+        assert_eq!(ramp_colour(1.0), HEAT_RAMP[3].1);
+        // Alpha rises with intensity
+        assert!(ramp_colour(0.5)[3] > ramp_colour(0.1)[3]);
+    }
+}
@@ -0,0 +1,450 @@
+use super::activity_lines;
+use super::geometry::{WorldPoint, lon_lat_to_world, simplify};
+use super::raster::encode_png;
+use anyhow::Result;
+use geojson::{FeatureCollection, Value};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Basemap tiles are the standard 256px slippy map tiles
+const BASEMAP_TILE_SIZE: u32 = 256;
+
+const START_COLOUR: [u8; 4] = [46, 160, 67, 255];
+const FINISH_COLOUR: [u8; 4] = [215, 38, 61, 255];
+
+#[derive(Debug, Clone)]
+pub struct ThumbnailSettings {
+    pub width: u32,
+    pub height: u32,
+    /// Space kept clear around the tracks, in pixels
+    pub padding: u32,
+    pub line_width: f64,
+    pub line_colour: [u8; 4],
+    /// Plain background, also used where a basemap tile is missing
+    pub background: [u8; 4],
+    /// Directory of `{z}/{x}/{y}.png` basemap tiles drawn under the tracks
+    pub basemap_dir: Option<PathBuf>,
+    /// Highest zoom the image is drawn at, so short activities are not
+    /// zoomed in past the basemap's detail
+    pub max_zoom: u8,
+}
+
+impl Default for ThumbnailSettings {
+    fn default() -> Self {
+        // Open Graph preview size, at half resolution
+        Self {
+            width: 600,
+            height: 315,
+            padding: 24,
+            line_width: 3.0,
+            line_colour: [252, 76, 2, 255],
+            background: [242, 239, 233, 255],
+            basemap_dir: None,
+            max_zoom: 16,
+        }
+    }
+}
+
+/// Tracks to draw, and where to mark the start and finish of a single activity
+#[derive(Debug, Default, Clone)]
+pub struct ThumbnailContent {
+    pub lines: Vec<Vec<WorldPoint>>,
+    pub start: Option<WorldPoint>,
+    pub finish: Option<WorldPoint>,
+}
+
+impl ThumbnailContent {
+    /// One activity's track, with its start and finish markers
+    pub fn from_activity(collection: &FeatureCollection) -> Self {
+        let mut content = Self {
+            lines: activity_lines(collection),
+            ..Default::default()
+        };
+        for feature in &collection.features {
+            let Some(Value::Point(position)) = feature.geometry.as_ref().map(|g| &g.value) else {
+                continue;
+            };
+            if position.len() < 2 {
+                continue;
+            }
+            let point = Some(lon_lat_to_world(position[0], position[1]));
+            match feature.property("marker").and_then(|m| m.as_str()) {
+                Some("start") => content.start = point,
+                Some("finish") => content.finish = point,
+                _ => {}
+            }
+        }
+        content
+    }
+
+    /// min x, min y, max x, max y in world coordinates, None when empty
+    pub fn world_bounds(&self) -> Option<[f64; 4]> {
+        self.lines
+            .iter()
+            .flatten()
+            .chain(self.start.iter())
+            .chain(self.finish.iter())
+            .fold(None, |bounds, &[x, y]| {
+                let [min_x, min_y, max_x, max_y] = bounds.unwrap_or([x, y, x, y]);
+                Some([min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y)])
+            })
+    }
+}
+
+/// Draw one activity onto a PNG framed to fit it. Returns None when there
+/// is nothing to draw.
+pub fn render_thumbnail(
+    content: &ThumbnailContent,
+    settings: &ThumbnailSettings,
+) -> Result<Option<Vec<u8>>> {
+    let Some(bounds) = content.world_bounds() else {
+        return Ok(None);
+    };
+    let mut thumbnail = Thumbnail::new(bounds, settings)?;
+    thumbnail.draw(content);
+    thumbnail.finish().map(Some)
+}
+
+/// An image framed around fixed bounds, over the basemap or a plain
+/// background, that tracks are drawn onto one activity at a time
+pub struct Thumbnail {
+    image: Image,
+    /// Line coverage of each pixel, the highest over all segments so joins
+    /// and overlapping activities are not drawn darker
+    coverage: Vec<f32>,
+    markers: Vec<([f64; 2], [u8; 4])>,
+    world_pixels: f64,
+    /// Global pixel coordinates of the image's top-left corner
+    origin: [f64; 2],
+    settings: ThumbnailSettings,
+}
+
+impl Thumbnail {
+    /// Frame the image around world bounds and draw its background
+    pub fn new(bounds: [f64; 4], settings: &ThumbnailSettings) -> Result<Self> {
+        let (width, height) = (settings.width, settings.height);
+        anyhow::ensure!(
+            width > 2 * settings.padding && height > 2 * settings.padding,
+            "Thumbnail of {width}x{height} has no room inside its padding"
+        );
+
+        let zoom = fit_zoom(bounds, settings);
+        let world_pixels = f64::from(BASEMAP_TILE_SIZE) * f64::from(1u32 << zoom);
+        let origin = [
+            (bounds[0] + bounds[2]) / 2.0 * world_pixels - f64::from(width) / 2.0,
+            (bounds[1] + bounds[3]) / 2.0 * world_pixels - f64::from(height) / 2.0,
+        ];
+
+        let mut image = Image::new(width, height, settings.background);
+        if let Some(dir) = &settings.basemap_dir {
+            draw_basemap(&mut image, dir, zoom, origin);
+        }
+
+        Ok(Self {
+            image,
+            coverage: vec![0.0; (width * height) as usize],
+            markers: Vec::new(),
+            world_pixels,
+            origin,
+            settings: settings.clone(),
+        })
+    }
+
+    pub fn draw(&mut self, content: &ThumbnailContent) {
+        let (width, height) = (self.settings.width, self.settings.height);
+        let epsilon = 0.5 / self.world_pixels;
+        let radius = self.settings.line_width / 2.0;
+
+        for line in &content.lines {
+            let line: Vec<[f64; 2]> = simplify(line, epsilon)
+                .into_iter()
+                .map(|point| self.to_image(point))
+                .collect();
+            for segment in line.windows(2) {
+                stroke_segment(
+                    &mut self.coverage,
+                    width,
+                    height,
+                    segment[0],
+                    segment[1],
+                    radius,
+                );
+            }
+        }
+
+        for (point, colour) in [
+            (content.start, START_COLOUR),
+            (content.finish, FINISH_COLOUR),
+        ] {
+            if let Some(point) = point {
+                self.markers.push((self.to_image(point), colour));
+            }
+        }
+    }
+
+    /// Paint the tracks, then the markers over them, and encode the image
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let (width, height) = (self.settings.width, self.settings.height);
+        self.image.fill(&self.coverage, self.settings.line_colour);
+
+        for (centre, colour) in self.markers {
+            let mut dot = vec![0f32; (width * height) as usize];
+            let radius = self.settings.line_width * 1.5;
+            stroke_segment(&mut dot, width, height, centre, centre, radius);
+            self.image.fill(&dot, colour);
+        }
+
+        encode_png(&self.image.pixels, width, height)
+    }
+
+    fn to_image(&self, [x, y]: WorldPoint) -> [f64; 2] {
+        [
+            x * self.world_pixels - self.origin[0],
+            y * self.world_pixels - self.origin[1],
+        ]
+    }
+}
+
+/// Highest zoom at which the bounds fit inside the padding
+fn fit_zoom(bounds: [f64; 4], settings: &ThumbnailSettings) -> u8 {
+    let usable = [
+        f64::from(settings.width - 2 * settings.padding),
+        f64::from(settings.height - 2 * settings.padding),
+    ];
+    let span = [bounds[2] - bounds[0], bounds[3] - bounds[1]];
+
+    (0..=settings.max_zoom)
+        .rev()
+        .find(|&zoom| {
+            let world_pixels = f64::from(BASEMAP_TILE_SIZE) * f64::from(1u32 << zoom);
+            span[0] * world_pixels <= usable[0] && span[1] * world_pixels <= usable[1]
+        })
+        .unwrap_or(0)
+}
+
+/// Raise the coverage of every pixel within `radius` of the segment, with
+/// a one pixel soft edge
+fn stroke_segment(
+    coverage: &mut [f32],
+    width: u32,
+    height: u32,
+    a: [f64; 2],
+    b: [f64; 2],
+    radius: f64,
+) {
+    let reach = radius + 1.0;
+    let min_x = (a[0].min(b[0]) - reach).floor().max(0.0);
+    let min_y = (a[1].min(b[1]) - reach).floor().max(0.0);
+    let max_x = (a[0].max(b[0]) + reach).ceil().min(f64::from(width) - 1.0);
+    let max_y = (a[1].max(b[1]) + reach).ceil().min(f64::from(height) - 1.0);
+    if min_x > max_x || min_y > max_y {
+        return;
+    }
+
+    let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+    let length_squared = dx * dx + dy * dy;
+    for y in min_y as u32..=max_y as u32 {
+        for x in min_x as u32..=max_x as u32 {
+            let (px, py) = (f64::from(x) + 0.5, f64::from(y) + 0.5);
+            let t = if length_squared > 0.0 {
+                (((px - a[0]) * dx + (py - a[1]) * dy) / length_squared).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let distance = (px - (a[0] + t * dx)).hypot(py - (a[1] + t * dy));
+            let value = (radius + 0.5 - distance).clamp(0.0, 1.0) as f32;
+
+            let pixel = &mut coverage[(y * width + x) as usize];
+            *pixel = pixel.max(value);
+        }
+    }
+}
+
+/// Draw the basemap tiles under the image, wrapping around the antimeridian
+fn draw_basemap(image: &mut Image, dir: &Path, zoom: u8, origin: [f64; 2]) {
+    let size = i64::from(BASEMAP_TILE_SIZE);
+    let tiles_across = 1i64 << zoom;
+    let (origin_x, origin_y) = (origin[0].floor() as i64, origin[1].floor() as i64);
+
+    let first = [origin_x.div_euclid(size), origin_y.div_euclid(size)];
+    let last = [
+        (origin_x + i64::from(image.width) - 1).div_euclid(size),
+        (origin_y + i64::from(image.height) - 1).div_euclid(size),
+    ];
+    for ty in first[1].max(0)..=last[1].min(tiles_across - 1) {
+        for tx in first[0]..=last[0] {
+            let x = tx.rem_euclid(tiles_across);
+            let path = dir.join(format!("{zoom}/{x}/{ty}.png"));
+            let Some(tile) = load_basemap_tile(&path) else {
+                continue;
+            };
+            image.blit(
+                &tile,
+                BASEMAP_TILE_SIZE,
+                tx * size - origin_x,
+                ty * size - origin_y,
+            );
+        }
+    }
+}
+
+/// An RGBA basemap tile, None when it is missing or unreadable
+fn load_basemap_tile(path: &Path) -> Option<Vec<u8>> {
+    let file = File::open(path).ok()?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    let decoded = (|| {
+        let mut reader = decoder.read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size().unwrap_or_default()];
+        let info = reader.next_frame(&mut buffer)?;
+        buffer.truncate(info.buffer_size());
+        Ok::<_, png::DecodingError>((info, buffer))
+    })();
+    let (info, buffer) = match decoded {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            warn!("Skipping unreadable basemap tile {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    if (info.width, info.height) != (BASEMAP_TILE_SIZE, BASEMAP_TILE_SIZE) {
+        warn!(
+            "Skipping basemap tile {} of {}x{}",
+            path.display(),
+            info.width,
+            info.height
+        );
+        return None;
+    }
+
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => buffer,
+        png::ColorType::Rgb => buffer
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buffer
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => buffer.iter().flat_map(|&v| [v, v, v, 255]).collect(),
+        png::ColorType::Indexed => return None,
+    };
+    Some(rgba)
+}
+
+/// An 8-bit RGBA image
+struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    fn new(width: u32, height: u32, background: [u8; 4]) -> Self {
+        Self {
+            width,
+            height,
+            pixels: background.repeat((width * height) as usize),
+        }
+    }
+
+    /// Draw a square RGBA tile with its top-left corner at (x, y), blending
+    /// it over what is already there
+    fn blit(&mut self, tile: &[u8], size: u32, x: i64, y: i64) {
+        for row in 0..i64::from(size) {
+            let image_y = y + row;
+            if !(0..i64::from(self.height)).contains(&image_y) {
+                continue;
+            }
+            for column in 0..i64::from(size) {
+                let image_x = x + column;
+                if !(0..i64::from(self.width)).contains(&image_x) {
+                    continue;
+                }
+                let source = ((row * i64::from(size) + column) * 4) as usize;
+                let colour: [u8; 4] = tile[source..source + 4].try_into().unwrap_or_default();
+                let alpha = f32::from(colour[3]) / 255.0;
+                self.blend(image_x as u32, image_y as u32, colour, alpha);
+            }
+        }
+    }
+
+    /// Paint `colour` over the image, weighted by each pixel's coverage
+    fn fill(&mut self, coverage: &[f32], colour: [u8; 4]) {
+        let opacity = f32::from(colour[3]) / 255.0;
+        for (index, &value) in coverage.iter().enumerate() {
+            if value > 0.0 {
+                let index = index as u32;
+                self.blend(
+                    index % self.width,
+                    index / self.width,
+                    colour,
+                    value * opacity,
+                );
+            }
+        }
+    }
+
+    fn blend(&mut self, x: u32, y: u32, colour: [u8; 4], alpha: f32) {
+        let offset = ((y * self.width + x) * 4) as usize;
+        let pixel = &mut self.pixels[offset..offset + 4];
+        for channel in 0..3 {
+            let blended =
+                f32::from(colour[channel]) * alpha + f32::from(pixel[channel]) * (1.0 - alpha);
+            pixel[channel] = blended.round() as u8;
+        }
+        let blended_alpha = alpha * 255.0 + f32::from(pixel[3]) * (1.0 - alpha);
+        pixel[3] = blended_alpha.round() as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_thumbnail() {
+        let content = ThumbnailContent {
+            lines: vec![vec![
+                lon_lat_to_world(-0.2, 51.5),
+                lon_lat_to_world(-0.1, 51.45),
+            ]],
+            start: Some(lon_lat_to_world(-0.2, 51.5)),
+            finish: Some(lon_lat_to_world(-0.1, 51.45)),
+        };
+        let settings = ThumbnailSettings::default();
+
+        // A 7km ride is framed at about city level
+        let bounds = content.world_bounds().unwrap();
+        let zoom = fit_zoom(bounds, &settings);
+        assert!((10..=13).contains(&zoom), "{zoom}");
+
+        let png = render_thumbnail(&content, &settings).unwrap().unwrap();
+        let mut reader = png::Decoder::new(std::io::Cursor::new(png))
+            .read_info()
+            .unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (600, 315));
+
+        // The centre of the image lies on the track, the corners do not
+        let pixel = |x: u32, y: u32| {
+            let offset = ((y * 600 + x) * 4) as usize;
+            [pixels[offset], pixels[offset + 1], pixels[offset + 2]]
+        };
+        let line = settings.line_colour;
+        assert_eq!(pixel(300, 157), [line[0], line[1], line[2]]);
+        let background = settings.background;
+        assert_eq!(pixel(0, 0), [background[0], background[1], background[2]]);
+
+        assert!(
+            render_thumbnail(&ThumbnailContent::default(), &settings)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
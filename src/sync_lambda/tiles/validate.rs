@@ -0,0 +1,262 @@
+use super::VectorTileSettings;
+use super::pmtiles::{self, Entry, PmtilesReader};
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use tracing::warn;
+
+/// What a validated archive contains
+#[derive(Debug, Clone)]
+pub struct ArchiveSummary {
+    pub tile_count: u64,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// min lon, min lat, max lon, max lat in degrees
+    pub bounds: [f64; 4],
+    pub layers: Vec<String>,
+    /// Line features recorded in the tileset statistics, if present
+    pub feature_count: Option<usize>,
+}
+
+/// Check that a PMTiles archive is complete and matches the GeoJSON it was
+/// built from, so a broken build is never published
+pub fn validate_pmtiles(
+    pmtiles_path: &Path,
+    geojson_path: &Path,
+    settings: &VectorTileSettings,
+) -> Result<ArchiveSummary> {
+    let mut reader = PmtilesReader::open(pmtiles_path)?;
+    let header = reader.header().clone();
+
+    let expected_len = header
+        .tile_data_offset
+        .checked_add(header.tile_data_len)
+        .context("Tile data section overflows")?;
+    anyhow::ensure!(
+        expected_len == reader.file_len(),
+        "Archive is {} bytes but its header describes {expected_len}",
+        reader.file_len()
+    );
+    anyhow::ensure!(
+        header.tile_type == pmtiles::TILE_TYPE_MVT,
+        "Unexpected tile type {}",
+        header.tile_type
+    );
+
+    anyhow::ensure!(
+        matches!(
+            header.tile_compression,
+            pmtiles::COMPRESSION_NONE | pmtiles::COMPRESSION_GZIP
+        ),
+        "Tile compression {} is not supported by the map client",
+        header.tile_compression
+    );
+    if !header.clustered {
+        warn!("Archive is not clustered, tile reads will be slower");
+    }
+
+    let entries = reader.entries()?;
+    check_entries(&entries, header.tile_data_len)?;
+
+    let tile_count: u64 = entries.iter().map(|e| u64::from(e.run_length)).sum();
+    // A count of 0 in the header means unknown
+    anyhow::ensure!(
+        header.addressed_tiles == 0 || header.addressed_tiles == tile_count,
+        "Header lists {} tiles but the directories address {tile_count}",
+        header.addressed_tiles
+    );
+    anyhow::ensure!(
+        header.tile_entries == 0 || header.tile_entries == entries.len() as u64,
+        "Header lists {} tile entries but the directories have {}",
+        header.tile_entries,
+        entries.len()
+    );
+    anyhow::ensure!(
+        header.tile_entries == 0 || header.tile_contents <= header.tile_entries,
+        "Header lists more tile contents ({}) than entries ({})",
+        header.tile_contents,
+        header.tile_entries
+    );
+
+    let activity_count = count_activities(geojson_path)?;
+    anyhow::ensure!(
+        activity_count == 0 || tile_count > 0,
+        "Archive has no tiles for {activity_count} activities"
+    );
+
+    anyhow::ensure!(
+        header.min_zoom <= header.max_zoom && header.max_zoom <= 22,
+        "Invalid zoom range {}-{}",
+        header.min_zoom,
+        header.max_zoom
+    );
+    anyhow::ensure!(
+        (header.min_zoom..=header.max_zoom).contains(&header.center_zoom),
+        "Center zoom {} is outside the zoom range",
+        header.center_zoom
+    );
+    if let (Some(first), Some(last)) = (entries.first(), entries.last()) {
+        let lowest = tile_id_zoom(first.tile_id);
+        let highest = tile_id_zoom(last.tile_id + u64::from(last.run_length) - 1);
+        anyhow::ensure!(
+            header.min_zoom <= lowest && highest <= header.max_zoom,
+            "Tiles span zooms {lowest}-{highest} outside the header range {}-{}",
+            header.min_zoom,
+            header.max_zoom
+        );
+    }
+
+    let [min_lon, min_lat, max_lon, max_lat] = header.bounds;
+    anyhow::ensure!(
+        (-180.0..=180.0).contains(&min_lon)
+            && (-180.0..=180.0).contains(&max_lon)
+            && (-90.0..=90.0).contains(&min_lat)
+            && (-90.0..=90.0).contains(&max_lat)
+            && min_lon <= max_lon
+            && min_lat <= max_lat,
+        "Invalid bounds {:?}",
+        header.bounds
+    );
+
+    let metadata = reader.metadata()?;
+    let layers: Vec<String> = metadata["vector_layers"]
+        .as_array()
+        .context("Metadata has no vector_layers")?
+        .iter()
+        .filter_map(|layer| layer["id"].as_str().map(String::from))
+        .collect();
+
+    let expected_layers: BTreeSet<&str> = settings
+        .layers_by_type
+        .values()
+        .chain([&settings.layer_name, &settings.point_layer_name])
+        .map(String::as_str)
+        .collect();
+    if let Some(unknown) = layers
+        .iter()
+        .find(|l| !expected_layers.contains(l.as_str()))
+    {
+        anyhow::bail!("Unexpected layer {unknown:?} in archive");
+    }
+    anyhow::ensure!(
+        activity_count == 0 || layers.iter().any(|l| *l != settings.point_layer_name),
+        "Archive has no activity layers for {activity_count} activities"
+    );
+
+    // Tippecanoe can be told not to write tileset statistics
+    let feature_count = line_feature_count(&metadata, &settings.point_layer_name);
+    match feature_count {
+        Some(count) => anyhow::ensure!(
+            count == activity_count,
+            "Archive has {count} activities but the GeoJSON has {activity_count}"
+        ),
+        None => warn!("Archive has no tilestats, not checking its feature count"),
+    }
+
+    Ok(ArchiveSummary {
+        tile_count,
+        min_zoom: header.min_zoom,
+        max_zoom: header.max_zoom,
+        bounds: header.bounds,
+        layers,
+        feature_count,
+    })
+}
+
+fn check_entries(entries: &[Entry], tile_data_len: u64) -> Result<()> {
+    for pair in entries.windows(2) {
+        anyhow::ensure!(
+            pair[0].tile_id < pair[1].tile_id,
+            "Directory entries are not in tile ID order"
+        );
+    }
+    for entry in entries {
+        anyhow::ensure!(
+            entry.length > 0 && entry.offset + u64::from(entry.length) <= tile_data_len,
+            "Tile {} is outside the tile data section",
+            entry.tile_id
+        );
+    }
+    Ok(())
+}
+
+/// Zoom level of a PMTiles tile ID
+fn tile_id_zoom(tile_id: u64) -> u8 {
+    let mut zoom = 0;
+    let mut tiles_below = 0u64;
+    while zoom < 31 {
+        let tiles_at_zoom = 1u64 << (2 * zoom);
+        if tile_id < tiles_below + tiles_at_zoom {
+            break;
+        }
+        tiles_below += tiles_at_zoom;
+        zoom += 1;
+    }
+    zoom as u8
+}
+
+/// Number of activities in the GeoJSON, which has one FeatureCollection per line
+fn count_activities(geojson_path: &Path) -> Result<usize> {
+    let file = File::open(geojson_path)
+        .with_context(|| format!("Failed to open {}", geojson_path.display()))?;
+
+    let mut count = 0;
+    for line in BufReader::new(file).lines() {
+        if !line?.trim().is_empty() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Total features in the tilestats of every layer except the point layer
+fn line_feature_count(metadata: &serde_json::Value, point_layer_name: &str) -> Option<usize> {
+    let layers = metadata["tilestats"]["layers"].as_array()?;
+    layers
+        .iter()
+        .filter(|layer| layer["layer"].as_str() != Some(point_layer_name))
+        .map(|layer| layer["count"].as_u64().map(|count| count as usize))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::build_vector_pmtiles;
+
+    #[test]
+    fn test_validate_pmtiles() {
+        let dir = tempdir::TempDir::new("validate_pmtiles").unwrap();
+        let geojson_path = dir.path().join("activities.geojson");
+        let pmtiles_path = dir.path().join("activities.pmtiles");
+
+        std::fs::write(
+            &geojson_path,
+            concat!(
+                r#"{"type":"FeatureCollection","features":[{"type":"Feature","geometry":{"type":"LineString","coordinates":[[-0.1,51.5],[-0.09,51.51]]},"properties":{"id":"i1","type":"Ride"}}]}"#,
+                "\n",
+                r#"{"type":"FeatureCollection","features":[{"type":"Feature","geometry":{"type":"LineString","coordinates":[[2.3,48.8],[2.31,48.81]]},"properties":{"id":"i2","type":"Run"}}]}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+
+        let settings = VectorTileSettings {
+            max_zoom: 8,
+            ..VectorTileSettings::default()
+        };
+        build_vector_pmtiles(&geojson_path, &pmtiles_path, &settings).unwrap();
+
+        let summary = validate_pmtiles(&pmtiles_path, &geojson_path, &settings).unwrap();
+        assert_eq!(summary.feature_count, Some(2));
+        assert_eq!(summary.layers, vec!["activities"]);
+        assert_eq!((summary.min_zoom, summary.max_zoom), (0, 8));
+
+        // A truncated upload must be rejected
+        let data = std::fs::read(&pmtiles_path).unwrap();
+        std::fs::write(&pmtiles_path, &data[..data.len() - 1]).unwrap();
+        assert!(validate_pmtiles(&pmtiles_path, &geojson_path, &settings).is_err());
+    }
+}
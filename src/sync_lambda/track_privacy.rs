@@ -0,0 +1,175 @@
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::AttributeValue;
+use ridelines_drivetrain::common::types::MapTarget;
+use std::collections::HashMap;
+use std::env;
+
+const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+
+/// Circle around a place a user does not want shown, such as their home
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrivacyZone {
+    pub lat: f64,
+    pub lon: f64,
+    pub radius_meters: f64,
+}
+
+/// Parts of a track that may not be shown: its ends and any privacy zones
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackPrivacy {
+    /// Track removed from each end of every activity
+    pub endpoint_trim_meters: f64,
+    pub zones: Vec<PrivacyZone>,
+}
+
+impl TrackPrivacy {
+    /// The settings of the user's own map, from `mapPrivacy` in their user
+    /// record. Maps of coached athletes have none, as the athlete's settings
+    /// are not known.
+    pub async fn fetch(dynamodb_client: &DynamoDbClient, target: &MapTarget) -> Result<Self> {
+        if target.athlete_id.is_some() {
+            return Ok(Self::default());
+        }
+        let users_table_name = env::var("USERS_TABLE_NAME")
+            .context("USERS_TABLE_NAME environment variable not set")?;
+
+        let item = dynamodb_client
+            .get_item()
+            .table_name(&users_table_name)
+            .key("id", AttributeValue::S(target.user_id.clone()))
+            .projection_expression("mapPrivacy")
+            .send()
+            .await
+            .context("Failed to read user record from DynamoDB")?
+            .item
+            .unwrap_or_default();
+
+        match item.get("mapPrivacy") {
+            Some(AttributeValue::M(privacy)) => Self::from_map(privacy),
+            Some(_) => anyhow::bail!("mapPrivacy is not a map"),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Changes whenever the settings do, so markers placed with older
+    /// settings can be placed again
+    pub fn fingerprint(&self) -> String {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = DefaultHasher::new();
+        self.endpoint_trim_meters.to_bits().hash(&mut hasher);
+        for zone in &self.zones {
+            [zone.lat, zone.lon, zone.radius_meters]
+                .map(f64::to_bits)
+                .hash(&mut hasher);
+        }
+        format!("{:x}", hasher.finish())
+    }
+
+    /// Read `endpointTrimMeters` and `privacyZones` from a privacy settings
+    /// map. Settings that cannot be read are an error rather than ignored,
+    /// so nothing is shown with less privacy than was asked for.
+    pub fn from_map(privacy: &HashMap<String, AttributeValue>) -> Result<Self> {
+        let mut settings = Self::default();
+        if let Some(trim) = privacy.get("endpointTrimMeters") {
+            settings.endpoint_trim_meters = number(trim).context("Invalid endpointTrimMeters")?;
+        }
+        if let Some(zones) = privacy.get("privacyZones") {
+            let Ok(zones) = zones.as_l() else {
+                anyhow::bail!("privacyZones is not a list");
+            };
+            for zone in zones {
+                let zone = zone
+                    .as_m()
+                    .map_err(|_| anyhow::anyhow!("Privacy zone is not a map"))?;
+                let field = |name: &str| {
+                    zone.get(name)
+                        .context("Privacy zone is incomplete")
+                        .and_then(number)
+                        .with_context(|| format!("Invalid privacy zone {name}"))
+                };
+                settings.zones.push(PrivacyZone {
+                    lat: field("lat")?,
+                    lon: field("lon")?,
+                    radius_meters: field("radiusMeters")?,
+                });
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Drop the points within the trim distance of either end of the track
+    /// and those inside a privacy zone, splitting the track where points are
+    /// dropped. Whole points are dropped rather than cutting segments at the
+    /// boundary, so no recorded position inside a zone is kept.
+    pub fn clip(&self, parts: &[Vec<Vec<f64>>]) -> Vec<Vec<Vec<f64>>> {
+        // Distance along the whole track, carried across gaps between parts
+        let mut along = Vec::with_capacity(parts.len());
+        let mut distance = 0.0;
+        let mut previous: Option<&[f64]> = None;
+        for part in parts {
+            let mut part_along = Vec::with_capacity(part.len());
+            for point in part.iter().filter(|p| p.len() >= 2) {
+                if let Some(previous) = previous {
+                    distance += haversine_meters(previous, point);
+                }
+                part_along.push(distance);
+                previous = Some(point);
+            }
+            along.push(part_along);
+        }
+        let total = distance;
+
+        let hidden = |point: &[f64], along: f64| {
+            along < self.endpoint_trim_meters
+                || total - along < self.endpoint_trim_meters
+                || self.in_zone(point)
+        };
+
+        let mut clipped = Vec::new();
+        for (part, part_along) in parts.iter().zip(along) {
+            let mut run: Vec<Vec<f64>> = Vec::new();
+            for (point, along) in part.iter().filter(|p| p.len() >= 2).zip(part_along) {
+                if hidden(point, along) {
+                    if run.len() >= 2 {
+                        clipped.push(std::mem::take(&mut run));
+                    }
+                    run.clear();
+                } else {
+                    run.push(point.clone());
+                }
+            }
+            if run.len() >= 2 {
+                clipped.push(run);
+            }
+        }
+        clipped
+    }
+
+    /// Whether a [lon, lat] point is inside any privacy zone
+    pub fn in_zone(&self, point: &[f64]) -> bool {
+        self.zones
+            .iter()
+            .any(|zone| haversine_meters(point, &[zone.lon, zone.lat]) <= zone.radius_meters)
+    }
+}
+
+fn number(value: &AttributeValue) -> Result<f64> {
+    let AttributeValue::N(number) = value else {
+        anyhow::bail!("Expected a number");
+    };
+    let number: f64 = number.parse()?;
+    anyhow::ensure!(number.is_finite(), "Expected a finite number");
+    Ok(number)
+}
+
+/// Great-circle distance between two [lon, lat] points
+fn haversine_meters(a: &[f64], b: &[f64]) -> f64 {
+    let (lat_a, lat_b) = (a[1].to_radians(), b[1].to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b[0] - a[0]).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * h.sqrt().min(1.0).asin()
+}